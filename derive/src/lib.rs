@@ -0,0 +1,434 @@
+//! Derive macro for the boilerplate of `linstr::Instrument`.
+//!
+//! The streams are declared with attributes on the struct, in index order, and parameters with attributes on
+//! the fields. The processing itself is written against `linstr::dynamic::DynInput` and `DynOutput`, so it
+//! does not repeat the const generic `process_block` signature:
+//!
+//! ```ignore
+//! use linstr::dynamic::{DynInput, DynOutput};
+//! use linstr::Instrument;
+//!
+//! #[derive(Instrument)]
+//! #[instrument(process = Self::process, new)]
+//! #[value_input("input", Audio)]
+//! #[value_input("gain", Gain, unit = Gain, min = 0.0, max = 1.0)]
+//! #[value_output("output", Audio)]
+//! pub struct Gain {
+//!     #[parameter("volume", unit = Gain, min = 0.0, max = 2.0, default = 1.0)]
+//!     pub volume: f32,
+//! }
+//!
+//! impl Gain {
+//!     fn process<Note>(&mut self, input: &DynInput<Note>, output: &mut DynOutput) {
+//!         for segment in input.parameter_segments() {
+//!             for event in segment.events {
+//!                 self.apply_parameter(event.id, event.value);
+//!             }
+//!             ...
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Struct attributes:
+//! - `#[instrument(...)]`: `process = path` to the processing function (required), `note = Type` to implement
+//!   the trait for a single note type, `new` to generate a `fn new` or `new = const` for a `const fn new`, and
+//!   paths to functions implementing `prepare`, `latency`, `reset`, `tail_length`, `is_silent`, `save_state`,
+//!   `load_state` and `check_state`
+//! - `#[value_input("name", Role, ...)]`, `#[control_input("name")]` and `#[value_output("name", Role, ...)]`:
+//!   one stream each, with an optional `StreamRole` (`Generic` by default, `Notes` for control inputs) and
+//!   optional `unit`, `min`, `max` and `default`. The range defaults to -1 to 1 for audio streams and is
+//!   unbounded for other roles
+//!
+//! Field attributes:
+//! - `#[parameter("name", ...)]`: a parameter with optional `unit`, `min`, `max`, `default` and `set = path` to
+//!   a function called with the new value instead of assigning the field
+//! - `#[init(expr)]`: the initial value of the field in the generated `new`
+//!
+//! If the struct has a type parameter named `Note`, it is used as the note type, otherwise the trait is
+//! implemented for every note type unless `note` is given, and `container` then needs the note type spelled out
+//! or inferred from its use. Streams are described by `stream_info`, parameters are applied by the generated
+//! `apply_parameter`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Expr, Fields, GenericParam, Ident, LitStr, Path, Result, Token, Type};
+
+/// A list of `key = value` options following the positional arguments of an attribute
+struct Options(Vec<(Ident, Expr)>);
+
+impl Options {
+    fn get(&self, key: &str) -> Option<&Expr> {
+        self.0.iter().find(|(name, _)| name == key).map(|(_, value)| value)
+    }
+
+    /// The option as a variant of the given enum, such as `unit = Gain`
+    fn variant(&self, key: &str, ty: proc_macro2::TokenStream, default: &str) -> proc_macro2::TokenStream {
+        match self.get(key) {
+            Some(value) => quote!(#ty::#value),
+            None => {
+                let default = Ident::new(default, Span::call_site());
+                quote!(#ty::#default)
+            },
+        }
+    }
+
+    /// The option as a value, or the given default
+    fn value(&self, key: &str, default: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match self.get(key) {
+            Some(value) => quote!(#value),
+            None => default,
+        }
+    }
+
+    fn check(&self, allowed: &[&str]) -> Result<()> {
+        for (name, _) in &self.0 {
+            if !allowed.iter().any(|key| name == key) {
+                return Err(Error::new(name.span(), format!("unknown option `{}`", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stream or parameter attribute: a name, an optional role and options
+struct Declaration {
+    name: LitStr,
+    role: Option<Ident>,
+    options: Options,
+}
+
+impl Parse for Declaration {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        let mut role = None;
+        let mut options = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            if input.peek(Ident) && input.peek2(Token![=]) {
+                let key = input.parse()?;
+                input.parse::<Token![=]>()?;
+                options.push((key, input.parse()?));
+            } else if role.is_none() && options.is_empty() {
+                role = Some(input.parse()?);
+            } else {
+                return Err(input.error("expected `key = value`"));
+            }
+        }
+        Ok(Self { name, role, options: Options(options) })
+    }
+}
+
+/// The arguments of `#[instrument(...)]`
+#[derive(Default)]
+struct InstrumentOptions {
+    process: Option<Path>,
+    note: Option<Type>,
+    new: bool,
+
+    /// Whether the generated `new` is a `const fn`, which needs every initial value to be a constant expression
+    const_new: bool,
+    hooks: Vec<(Ident, Path)>,
+}
+
+const HOOKS: [&str; 8] = ["prepare", "latency", "reset", "tail_length", "is_silent", "save_state", "load_state", "check_state"];
+
+impl InstrumentOptions {
+    fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut options = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("instrument")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("process") {
+                    options.process = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("note") {
+                    options.note = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("new") {
+                    options.new = true;
+                    if meta.input.peek(Token![=]) {
+                        meta.value()?.parse::<Token![const]>()?;
+                        options.const_new = true;
+                    }
+                } else if let Some(hook) = HOOKS.iter().find(|hook| meta.path.is_ident(hook)) {
+                    options.hooks.push((Ident::new(hook, Span::call_site()), meta.value()?.parse()?));
+                } else {
+                    return Err(meta.error("unknown instrument option"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(options)
+    }
+}
+
+/// Generates the `StreamInfo` of a stream declaration
+fn stream_info(declaration: &Declaration, default_role: &str) -> Result<proc_macro2::TokenStream> {
+    declaration.options.check(&["unit", "min", "max", "default"])?;
+    let name = &declaration.name;
+    let role = declaration.role.clone().unwrap_or_else(|| Ident::new(default_role, Span::call_site()));
+
+    // The ranges of `StreamInfo::audio` and `StreamInfo::notes`, unbounded for other roles
+    let (min, max) = match role.to_string().as_str() {
+        "Audio" => (quote!(-1.0), quote!(1.0)),
+        "Notes" => (quote!(0.0), quote!(0.0)),
+        _ => (quote!(::linstr::MusicalValue::MIN), quote!(::linstr::MusicalValue::MAX)),
+    };
+    let unit = declaration.options.variant("unit", quote!(::linstr::parameter::ParameterUnit), "Generic");
+    let min = declaration.options.value("min", min);
+    let max = declaration.options.value("max", max);
+    let role = quote!(::linstr::stream::StreamRole::#role);
+    let default = declaration.options.value("default", quote!(0.0));
+    Ok(quote!(::linstr::stream::StreamInfo::new(#name, #role, #unit, #min, #max, #default)))
+}
+
+#[proc_macro_derive(Instrument, attributes(instrument, value_input, control_input, value_output, parameter, init))]
+pub fn derive_instrument(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let options = InstrumentOptions::parse(&input.attrs)?;
+    let Some(process) = &options.process else {
+        return Err(Error::new(input.ident.span(), "missing `#[instrument(process = ...)]`"));
+    };
+
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(input.ident.span(), "`Instrument` can only be derived for structs"));
+    };
+
+    // Streams, in declaration order
+    let mut streams = Vec::new();
+    let mut counts = [0usize; 3];
+    for attr in &input.attrs {
+        let (kind, default_role, count) = if attr.path().is_ident("value_input") {
+            (quote!(ValueInput), "Generic", &mut counts[0])
+        } else if attr.path().is_ident("control_input") {
+            (quote!(ControlInput), "Notes", &mut counts[1])
+        } else if attr.path().is_ident("value_output") {
+            (quote!(ValueOutput), "Generic", &mut counts[2])
+        } else {
+            continue;
+        };
+        let info = stream_info(&attr.parse_args()?, default_role)?;
+        let index = *count;
+        *count += 1;
+        streams.push(quote!((::linstr::stream::StreamKind::#kind, #index) => Some(#info)));
+    }
+    let [in_value_streams, in_control_streams, out_value_streams] = counts;
+
+    // Parameters and initial values, in field order
+    let mut parameter_infos = Vec::new();
+    let mut parameter_getters = Vec::new();
+    let mut parameter_setters = Vec::new();
+    let mut initializers = Vec::new();
+    for (position, field) in data.fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(position);
+                quote!(#index)
+            },
+        };
+        let ty = &field.ty;
+        let mut init = None;
+
+        for attr in &field.attrs {
+            if attr.path().is_ident("parameter") {
+                let declaration: Declaration = attr.parse_args()?;
+                if let Some(role) = &declaration.role {
+                    return Err(Error::new(role.span(), "expected `key = value`"));
+                }
+                declaration.options.check(&["unit", "min", "max", "default", "set"])?;
+
+                let id = parameter_infos.len();
+                let name = &declaration.name;
+                let unit = declaration.options.variant("unit", quote!(::linstr::parameter::ParameterUnit), "Generic");
+                let min = declaration.options.value("min", quote!(::linstr::MusicalValue::MIN));
+                let max = declaration.options.value("max", quote!(::linstr::MusicalValue::MAX));
+                let default = declaration.options.value("default", quote!(0.0));
+                parameter_infos.push(quote!(#id => Some(::linstr::parameter::ParameterInfo::new(#name, #unit, #min, #max, #default))));
+                parameter_getters.push(quote!(#id => Some(self.#member as ::linstr::MusicalValue)));
+                parameter_setters.push(match declaration.options.get("set") {
+                    Some(set) => quote!(#id => #set(self, value)),
+                    None => quote!(#id => self.#member = value as #ty),
+                });
+                if declaration.options.get("default").is_some() {
+                    init.get_or_insert(quote!(#default as #ty));
+                }
+            } else if attr.path().is_ident("init") {
+                let expr: Expr = attr.parse_args()?;
+                init = Some(quote!(#expr));
+            }
+        }
+
+        if options.new {
+            let is_phantom = matches!(ty, Type::Path(path) if path.path.segments.last().is_some_and(|segment| segment.ident == "PhantomData"));
+            let init = match init {
+                Some(init) => init,
+                None if is_phantom => quote!(::core::marker::PhantomData),
+                None => return Err(Error::new_spanned(field, "the generated `new` needs `#[init(...)]` or a parameter `default` for this field")),
+            };
+            initializers.push((member, init));
+        }
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // The note type is the `Note` parameter of the struct, the given type, or a parameter of the trait impl
+    let has_note = input.generics.params.iter().any(|param| matches!(param, GenericParam::Type(param) if param.ident == "Note"));
+    let mut trait_generics = input.generics.clone();
+    let note = match &options.note {
+        Some(note) => quote!(#note),
+        None => {
+            if !has_note {
+                trait_generics.params.push(syn::parse_quote!(Note: Sized));
+            }
+            quote!(Note)
+        },
+    };
+    let (trait_impl_generics, _, _) = trait_generics.split_for_impl();
+
+    let parameter_count = parameter_infos.len();
+    let apply_body = if parameter_setters.is_empty() {
+        quote!(let _ = (id, value);)
+    } else {
+        quote! {
+            match id {
+                #(#parameter_setters,)*
+                _ => {},
+            }
+        }
+    };
+
+    let hooks = options.hooks.iter().map(|(hook, path)| match hook.to_string().as_str() {
+        "prepare" => quote! {
+            fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+                #path(self, sampling_rate, max_block)
+            }
+        },
+        "latency" => quote! {
+            fn latency(&self) -> usize {
+                #path(self)
+            }
+        },
+        "reset" => quote! {
+            fn reset(&mut self) {
+                #path(self)
+            }
+        },
+        "tail_length" => quote! {
+            fn tail_length(&self) -> Option<usize> {
+                #path(self)
+            }
+        },
+        "is_silent" => quote! {
+            fn is_silent(&self) -> bool {
+                #path(self)
+            }
+        },
+        "save_state" => quote! {
+            fn save_state(&self, writer: &mut ::linstr::preset::PresetWriter) -> Result<(), ::linstr::preset::PresetError> {
+                #path(self, writer)
+            }
+        },
+        "check_state" => quote! {
+            fn check_state(&self, reader: &mut ::linstr::preset::PresetReader) -> Result<(), ::linstr::preset::PresetError> {
+                #path(self, reader)
+            }
+        },
+        _ => quote! {
+            fn load_state(&mut self, reader: &mut ::linstr::preset::PresetReader) -> Result<(), ::linstr::preset::PresetError> {
+                #path(self, reader)
+            }
+        },
+    });
+    let new = options.new.then(|| {
+        let (members, values): (Vec<_>, Vec<_>) = initializers.into_iter().unzip();
+        let body = match &data.fields {
+            Fields::Named(_) => quote!(Self { #(#members: #values,)* }),
+            Fields::Unnamed(_) => quote!(Self(#(#values,)*)),
+            Fields::Unit => quote!(Self),
+        };
+        let constness = options.const_new.then(|| quote!(const));
+        quote! {
+            #vis #constness fn new() -> Self {
+                #body
+            }
+        }
+    });
+
+    let value_block = format_ident!("VALUE_BLOCK");
+    let control_elements = format_ident!("CONTROL_ELEMENTS");
+
+    Ok(quote! {
+        #[automatically_derived]
+        impl #trait_impl_generics ::linstr::Instrument<#in_value_streams, #in_control_streams, #out_value_streams, #note> for #name #ty_generics #where_clause {
+            fn process_block<const #value_block: usize, const #control_elements: usize>(
+                &mut self,
+                input: &::linstr::InstrumentInput<#in_value_streams, #in_control_streams, #note, #value_block, #control_elements>,
+                output: &mut ::linstr::InstrumentOutput<#out_value_streams, #value_block>,
+            ) {
+                let input = ::linstr::dynamic::DynInput {
+                    value_streams: input.value_streams.as_flattened(),
+                    control_streams: input.control_streams.as_flattened(),
+                    parameter_events: input.parameter_events(),
+                    block_size: #value_block,
+                    control_elements: #control_elements,
+                };
+                let mut output = ::linstr::dynamic::DynOutput {
+                    value_streams: output.value_streams.as_flattened_mut(),
+                    block_size: #value_block,
+                };
+                #process(self, &input, &mut output)
+            }
+
+            fn stream_info(&self, kind: ::linstr::stream::StreamKind, index: usize) -> Option<::linstr::stream::StreamInfo> {
+                match (kind, index) {
+                    #(#streams,)*
+                    _ => None,
+                }
+            }
+
+            fn parameter_count(&self) -> usize {
+                #parameter_count
+            }
+
+            fn parameter_info(&self, id: ::linstr::parameter::ParameterId) -> Option<::linstr::parameter::ParameterInfo> {
+                match id {
+                    #(#parameter_infos,)*
+                    _ => None,
+                }
+            }
+
+            fn parameter(&self, id: ::linstr::parameter::ParameterId) -> Option<::linstr::MusicalValue> {
+                match id {
+                    #(#parameter_getters,)*
+                    _ => None,
+                }
+            }
+
+            fn set_parameter(&mut self, id: ::linstr::parameter::ParameterId, value: ::linstr::MusicalValue) {
+                self.apply_parameter(id, value);
+            }
+
+            #(#hooks)*
+        }
+
+        #[automatically_derived]
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Sets the parameter with the given id, for applying the parameter events of a block
+            #vis fn apply_parameter(&mut self, id: ::linstr::parameter::ParameterId, value: ::linstr::MusicalValue) {
+                #apply_body
+            }
+
+            #new
+        }
+    })
+}
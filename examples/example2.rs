@@ -98,31 +98,13 @@ fn main() {
     graph.connect_value_stream(6, 0, 7, 0);
     graph.connect_value_stream(8, 0, 7, 1);
 
-    println!("Order: {:?}", graph.get_instrument_process_order());
+    println!("Order: {:?}", graph.process_order());
 
-    let mut remains: Vec<f32> = Vec::with_capacity(128);
+    let mut renderer = render::Renderer::new(graph, config.channels as usize);
     let stream = device.build_output_stream(
         &config,
         move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            let mut j = 0;
-            for i in 0..data.len() {
-                if j < remains.len() {
-                    data[i] = remains[j];
-                } else {
-                    if i % config.channels as usize == 0 {
-                        graph.process_next();
-                        remains.clear();
-                        remains.extend_from_slice(graph.get_output(0));
-                    }
-                    j = 0;
-                    data[i] = remains[j];
-                }
-                if i % config.channels as usize == config.channels as usize - 1 {
-                    j += 1;
-                }
-            }
-
-            remains.drain(0..j);
+            renderer.render_interleaved(data);
         },
         move |err| {
             eprintln!("an error occurred on stream: {}", err);
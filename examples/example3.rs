@@ -64,19 +64,31 @@ impl ControlStreamSource<MidiNote> for MyControl<'_> {
 }
 
 struct BellInstrumentUnit {
-    note: usize,
-    envelope: Box<dyn InstrumentContainer<MidiNote>>,
-    oscillator: Box<dyn InstrumentContainer<MidiNote>>,
-    amplifier: Box<dyn InstrumentContainer<MidiNote>>,
+    voice: graph::subgraph::Subgraph<graph::heap::HeapInstrumentGraph<MidiNote>, 0, 1, 1>,
 }
 
 impl BellInstrumentUnit {
     fn new(sampling_rate: usize, note: usize) -> Self {
+        let freq = 440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0);
+
+        let mut voice = graph::heap::HeapInstrumentGraph::new(1);
+        let envelope = voice.add_instrument(Box::new(container(instrument::envelope::LinearEnvelope::<1, MidiNote>::new([0], [0.25], sampling_rate / 2))));
+        let oscillator = voice.add_instrument(Box::new(container(instrument::oscillators::SineOscillator::<MidiNote>::new(sampling_rate))));
+        let amplifier = voice.add_instrument(Box::new(container(instrument::Amplifier::<MidiNote>::new())));
+        let frequency = voice.add_instrument(Box::new(container(instrument::Constant::<MidiNote>::new(freq))));
+        let phase = voice.add_instrument(Box::new(container(instrument::Constant::<MidiNote>::new(0.0))));
+
+        voice.connect_value_stream(frequency, 0, oscillator, 0);
+        voice.connect_value_stream(phase, 0, oscillator, 1);
+        voice.connect_value_stream(oscillator, 0, amplifier, 0);
+        voice.connect_value_stream(envelope, 0, amplifier, 1);
+        voice.connect_destination(0, amplifier, 0);
+
+        let mut voice = graph::subgraph::Subgraph::new(voice);
+        voice.connect_control_input(0, envelope, 0);
+
         Self {
-            note,
-            envelope: Box::new(container(instrument::envelope::LinearEnvelope::<1, MidiNote>::new([0], [0.25], sampling_rate / 2))),
-            oscillator: Box::new(container(instrument::oscillators::SineOscillator::<MidiNote>::new(sampling_rate))),
-            amplifier: Box::new(container(instrument::Amplifier::<MidiNote>::new())),
+            voice,
         }
     }
 }
@@ -87,22 +99,7 @@ impl Instrument<0, 1, 1, MidiNote> for BellInstrumentUnit {
         input: &InstrumentInput<0, 1, MidiNote, VALUE_BLOCK, CONTROL_ELEMENTS>,
         output: &mut InstrumentOutput<1, VALUE_BLOCK>,
     ) {
-        let freq = 440.0 * 2.0f32.powf((self.note as f32 - 69.0) / 12.0);
-        let mut control_stream = input.control_streams[0].clone();
-        for i in 0..CONTROL_ELEMENTS {
-            if control_stream[i].note != self.note as u8 {
-                control_stream[i].command_type = NoteCommandType::Noop;
-            } 
-        }
-        self.envelope.as_mut().feed_control_stream(0, &control_stream[0..1]);
-        self.envelope.as_mut().process_next();
-        self.oscillator.as_mut().feed_value_stream(0, &[freq; VALUE_BLOCK]);
-        self.oscillator.as_mut().feed_value_stream(1, &[0.0; VALUE_BLOCK]);
-        self.oscillator.as_mut().process_next();
-        self.amplifier.as_mut().feed_value_stream(0, self.oscillator.as_ref().get_output(0));
-        self.amplifier.as_mut().feed_value_stream(1, self.envelope.as_ref().get_output(0));
-        self.amplifier.as_mut().process_next();
-        output.value_streams[0].copy_from_slice(self.amplifier.as_ref().get_output(0));
+        self.voice.process_block(input, output);
     }
 }
 
@@ -126,8 +123,16 @@ impl Instrument<0, 1, 1, MidiNote> for BellInstrument {
         input: &InstrumentInput<0, 1, MidiNote, VALUE_BLOCK, CONTROL_ELEMENTS>,
         output: &mut InstrumentOutput<1, VALUE_BLOCK>,
     ) {
-        for unit in self.units.iter_mut() {
-            unit.as_mut().feed_control_stream(0, &input.control_streams[0]);
+        // Each unit only hears the first command of the block, and only if it is for its note
+        let command = input.control_streams[0][0];
+        let noop = NoteCommand {
+            command_type: NoteCommandType::Noop,
+            velocity: 0,
+            note: 0,
+        };
+        for (note, unit) in self.units.iter_mut().enumerate() {
+            let command = if command.note as usize == note { command } else { noop };
+            unit.as_mut().feed_control_stream(0, &[command]);
             unit.as_mut().process_next();
             let output_stream = unit.as_ref().get_output(0);
             for i in 0..VALUE_BLOCK {
@@ -154,42 +159,23 @@ fn main() {
 
     let mut rng = rand::rng();
 
-    let mut graph = graph::InstrumentGraph::<1>::new();
+    let mut graph = graph::heap::HeapInstrumentGraph::<MidiNote>::new(1);
 
-    graph.add_instrument(leak(container(BellInstrument::new(sampling_rate))));
+    graph.add_instrument(Box::new(container(BellInstrument::new(sampling_rate))));
 
     println!("initiated");
-    graph.add_control_source(leak(MyControl::new(signal_ref)));
+    graph.add_control_source(Box::new(MyControl::new(signal_ref)));
 
     graph.connect_control_source(0, 0);
     graph.connect_destination(0, 0, 0);
 
-    println!("Order: {:?}", graph.get_instrument_process_order());
+    println!("Order: {:?}", graph.process_order());
 
-    let mut remains: Vec<f32> = Vec::with_capacity(128);
+    let mut renderer = render::Renderer::new(graph, config.channels as usize);
     let stream = device.build_output_stream(
         &config,
         move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-
-            let mut j = 0;
-            for i in 0..data.len() {
-                if j < remains.len() {
-                    data[i] = remains[j];
-                } else {
-                    if i % config.channels as usize == 0 {
-                        graph.process_next();
-                        remains.clear();
-                        remains.extend_from_slice(graph.get_output(0));
-                        j = 0;
-                    }
-                    data[i] = remains[j];
-                }
-                if (i % config.channels as usize) == (config.channels as usize - 1) {
-                    j += 1;
-                }
-            }
-
-            remains.drain(0..j);
+            renderer.render_interleaved(data);
         },
         move |err| {
             eprintln!("an error occurred on stream: {}", err);
@@ -210,7 +196,3 @@ fn main() {
         }
     }
 }
-
-fn leak<'a, T: 'a>(value: T) -> &'a mut T {
-    Box::leak(Box::new(value))
-}
@@ -0,0 +1,765 @@
+//! Instruments with stream counts decided at runtime.
+//!
+//! `Instrument` fixes its stream counts and block size at compile time, so it cannot be used as a trait object.
+//! `DynInstrument` passes its streams as slices instead, and reports its stream counts when it is created,
+//! which suits instruments loaded at runtime, such as plugins, and instruments with a configurable number of
+//! streams.
+//!
+//! Adapters convert between the two: `DynAdapter` wraps an `Instrument` as a `DynInstrument`, and
+//! `StaticAdapter` wraps a `DynInstrument` as an `Instrument` with fixed capacities. `dyn_container` wraps a
+//! `DynInstrument` into an `InstrumentContainer` for use in graphs.
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+use crate::parameter::{ParameterEvent, ParameterId, ParameterInfo, ParameterSegments, MAX_PARAMETER_EVENTS};
+use crate::preset::{PresetError, PresetReader, PresetWriter};
+use crate::stream::{StreamInfo, StreamKind};
+use crate::{
+    Instrument, InstrumentContainer, InstrumentContainerImpl, InstrumentInput, InstrumentOutput, MidiNote, MusicalValue,
+    NoteCommand, NoteCommandType, STANDARD_BLOCK_SIZE, STANDARD_ELEMENT_COUNT,
+};
+
+/// The maximum number of input or output value streams of an instrument in a `dyn_container`
+pub const MAX_DYN_VALUE_STREAMS: usize = 16;
+
+/// The maximum number of control streams of an instrument in a `dyn_container`
+pub const MAX_DYN_CONTROL_STREAMS: usize = 4;
+
+/// The input block for a `DynInstrument`, with as many streams as the instrument reports
+pub struct DynInput<'b, Note: Sized = MidiNote> {
+    /// The value streams one after another, each `block_size` samples long
+    pub value_streams: &'b [MusicalValue],
+
+    /// The control streams one after another, each `control_elements` elements long
+    pub control_streams: &'b [NoteCommand<Note>],
+
+    /// Parameter changes during the block, ordered by offset
+    pub parameter_events: &'b [ParameterEvent],
+
+    pub block_size: usize,
+    pub control_elements: usize,
+}
+
+impl<'b, Note: Sized> DynInput<'b, Note> {
+    /// The value stream at the given index, out of bounds indexes panic
+    pub fn value_stream(&self, index: usize) -> &'b [MusicalValue] {
+        &self.value_streams[index * self.block_size..(index + 1) * self.block_size]
+    }
+
+    /// The control stream at the given index, out of bounds indexes panic
+    pub fn control_stream(&self, index: usize) -> &'b [NoteCommand<Note>] {
+        &self.control_streams[index * self.control_elements..(index + 1) * self.control_elements]
+    }
+
+    /// Splits the block at the offsets of the parameter events, for sample accurate automation
+    pub fn parameter_segments(&self) -> ParameterSegments<'b> {
+        ParameterSegments {
+            events: self.parameter_events,
+            position: 0,
+            block_size: self.block_size,
+        }
+    }
+}
+
+/// The output block for a `DynInstrument`, with as many streams as the instrument reports
+pub struct DynOutput<'b> {
+    /// The value streams one after another, each `block_size` samples long
+    pub value_streams: &'b mut [MusicalValue],
+
+    pub block_size: usize,
+}
+
+impl DynOutput<'_> {
+    /// The value stream at the given index, out of bounds indexes panic
+    pub fn value_stream(&self, index: usize) -> &[MusicalValue] {
+        &self.value_streams[index * self.block_size..(index + 1) * self.block_size]
+    }
+
+    /// The value stream at the given index, out of bounds indexes panic
+    pub fn value_stream_mut(&mut self, index: usize) -> &mut [MusicalValue] {
+        &mut self.value_streams[index * self.block_size..(index + 1) * self.block_size]
+    }
+}
+
+/// The object safe trait for an instrument whose stream counts are decided at runtime
+///
+/// The stream counts must not change once the instrument is wrapped by an adapter or a container.
+pub trait DynInstrument<Note: Sized = MidiNote> {
+    fn in_value_streams(&self) -> usize;
+
+    fn in_control_streams(&self) -> usize;
+
+    fn out_value_streams(&self) -> usize;
+
+    /// Processes a block of input data and produces a block of output data
+    fn process_block(&mut self, input: &DynInput<Note>, output: &mut DynOutput);
+
+    /// Prepares the instrument for processing at the given sampling rate, in blocks of at most `max_block` samples
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        let _ = (sampling_rate, max_block);
+    }
+
+    /// Describes the stream of the given kind at the given index, `None` if it is not described
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        let _ = (kind, index);
+        None
+    }
+
+    /// The number of automatable parameters, whose ids are `0..parameter_count()`
+    fn parameter_count(&self) -> usize {
+        0
+    }
+
+    /// Describes the parameter with the given id
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        let _ = id;
+        None
+    }
+
+    /// Gets the current value of the parameter with the given id
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        let _ = id;
+        None
+    }
+
+    /// Sets the parameter with the given id
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        let _ = (id, value);
+    }
+
+    /// Writes the runtime state of the instrument, see `Instrument::save_state`
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        let _ = writer;
+        Ok(())
+    }
+
+    /// Restores the runtime state written by `save_state`
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        let _ = reader;
+        Ok(())
+    }
+
+    /// Checks runtime state without restoring it, see `Instrument::check_state`
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        let _ = reader;
+        Ok(())
+    }
+
+    /// The number of samples the output lags behind the input
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Clears the runtime state, see `Instrument::reset`
+    fn reset(&mut self) {}
+
+    /// The number of samples the output can keep sounding once the input falls silent, `None` if unbounded
+    fn tail_length(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    /// Whether the output stays silent for as long as the input does
+    fn is_silent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<Note: Sized, D: DynInstrument<Note> + ?Sized> DynInstrument<Note> for Box<D> {
+    fn in_value_streams(&self) -> usize {
+        (**self).in_value_streams()
+    }
+
+    fn in_control_streams(&self) -> usize {
+        (**self).in_control_streams()
+    }
+
+    fn out_value_streams(&self) -> usize {
+        (**self).out_value_streams()
+    }
+
+    fn process_block(&mut self, input: &DynInput<Note>, output: &mut DynOutput) {
+        (**self).process_block(input, output);
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        (**self).prepare(sampling_rate, max_block);
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        (**self).stream_info(kind, index)
+    }
+
+    fn parameter_count(&self) -> usize {
+        (**self).parameter_count()
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        (**self).parameter_info(id)
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        (**self).parameter(id)
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        (**self).set_parameter(id, value);
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        (**self).save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        (**self).load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        (**self).check_state(reader)
+    }
+
+    fn latency(&self) -> usize {
+        (**self).latency()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        (**self).tail_length()
+    }
+
+    fn is_silent(&self) -> bool {
+        (**self).is_silent()
+    }
+}
+
+/// Wraps an `Instrument` as a `DynInstrument`, processing in blocks of `BLOCK_SIZE` samples
+///
+/// The streams are copied in and out of buffers of the fixed layout. Only blocks of exactly `BLOCK_SIZE`
+/// samples are processed, since the wrapped instrument cannot process part of a block. Other blocks are
+/// rejected with a debug assertion, and give silence in release builds without reaching the instrument.
+///
+/// At most `STANDARD_ELEMENT_COUNT` control elements per stream and `MAX_PARAMETER_EVENTS` parameter events
+/// are passed on, the rest of a longer control stream or event list is dropped.
+pub struct DynAdapter<
+    I: Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized = MidiNote,
+    const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE,
+> {
+    pub instrument: I,
+    input: InstrumentInput<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, Note, BLOCK_SIZE, STANDARD_ELEMENT_COUNT>,
+    output: InstrumentOutput<OUT_VALUE_STREAMS, BLOCK_SIZE>,
+}
+
+impl<
+    I: Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized + Default + Copy,
+    const BLOCK_SIZE: usize,
+> DynAdapter<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note, BLOCK_SIZE> {
+    pub fn new(instrument: I) -> Self {
+        Self {
+            instrument,
+            input: InstrumentInput {
+                control_streams: [[NoteCommand {
+                    command_type: NoteCommandType::Noop,
+                    velocity: 0,
+                    note: Note::default(),
+                }; STANDARD_ELEMENT_COUNT]; IN_CONTROL_STREAMS],
+                value_streams: [[0.0; BLOCK_SIZE]; IN_VALUE_STREAMS],
+                parameter_events: [ParameterEvent::default(); MAX_PARAMETER_EVENTS],
+                parameter_event_count: 0,
+            },
+            output: InstrumentOutput {
+                value_streams: [[0.0; BLOCK_SIZE]; OUT_VALUE_STREAMS],
+            },
+        }
+    }
+}
+
+impl<
+    I: Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized + Default + Copy,
+    const BLOCK_SIZE: usize,
+> DynInstrument<Note> for DynAdapter<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note, BLOCK_SIZE> {
+    fn in_value_streams(&self) -> usize {
+        IN_VALUE_STREAMS
+    }
+
+    fn in_control_streams(&self) -> usize {
+        IN_CONTROL_STREAMS
+    }
+
+    fn out_value_streams(&self) -> usize {
+        OUT_VALUE_STREAMS
+    }
+
+    /// Blocks of another size than `BLOCK_SIZE` are rejected, see `DynAdapter`
+    fn process_block(&mut self, input: &DynInput<Note>, output: &mut DynOutput) {
+        debug_assert!(input.block_size == BLOCK_SIZE && output.block_size == BLOCK_SIZE, "Block size must match the adapter");
+        if input.block_size != BLOCK_SIZE || output.block_size != BLOCK_SIZE {
+            output.value_streams.fill(0.0);
+            return;
+        }
+
+        for (index, stream) in self.input.value_streams.iter_mut().enumerate() {
+            stream.copy_from_slice(input.value_stream(index));
+        }
+
+        for (index, stream) in self.input.control_streams.iter_mut().enumerate() {
+            let source = input.control_stream(index);
+            let len = source.len().min(STANDARD_ELEMENT_COUNT);
+            stream[..len].copy_from_slice(&source[..len]);
+            stream[len..].fill(NoteCommand {
+                command_type: NoteCommandType::Noop,
+                velocity: 0,
+                note: Note::default(),
+            });
+        }
+
+        let count = input.parameter_events.len().min(MAX_PARAMETER_EVENTS);
+        self.input.parameter_events[..count].copy_from_slice(&input.parameter_events[..count]);
+        self.input.parameter_event_count = count;
+
+        self.instrument.process_block(&self.input, &mut self.output);
+
+        for (index, stream) in self.output.value_streams.iter().enumerate() {
+            output.value_stream_mut(index).copy_from_slice(stream);
+        }
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        self.instrument.prepare(sampling_rate, max_block);
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        self.instrument.stream_info(kind, index)
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.instrument.parameter_count()
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        self.instrument.parameter_info(id)
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        self.instrument.parameter(id)
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        self.instrument.set_parameter(id, value);
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.instrument.save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.instrument.load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.instrument.check_state(reader)
+    }
+
+    fn latency(&self) -> usize {
+        self.instrument.latency()
+    }
+
+    fn reset(&mut self) {
+        self.instrument.reset();
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        self.instrument.tail_length()
+    }
+
+    fn is_silent(&self) -> bool {
+        self.instrument.is_silent()
+    }
+}
+
+/// Wraps a `DynInstrument` as an `Instrument` with room for up to the given stream counts
+///
+/// Only as many streams as the wrapped instrument reports are passed to it, the remaining output streams are left
+/// untouched.
+pub struct StaticAdapter<
+    D: DynInstrument<Note>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized = MidiNote,
+> {
+    pub instrument: D,
+    in_value_streams: usize,
+    in_control_streams: usize,
+    out_value_streams: usize,
+    _phantom: core::marker::PhantomData<Note>,
+}
+
+impl<
+    D: DynInstrument<Note>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized,
+> StaticAdapter<D, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> {
+    /// Wraps the instrument, panics if it has more streams than there is room for
+    pub fn new(instrument: D) -> Self {
+        let in_value_streams = instrument.in_value_streams();
+        let in_control_streams = instrument.in_control_streams();
+        let out_value_streams = instrument.out_value_streams();
+        if in_value_streams > IN_VALUE_STREAMS || in_control_streams > IN_CONTROL_STREAMS || out_value_streams > OUT_VALUE_STREAMS {
+            panic!("Stream count out of bounds");
+        }
+
+        Self {
+            instrument,
+            in_value_streams,
+            in_control_streams,
+            out_value_streams,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// The number of streams of the given kind of the wrapped instrument
+    pub fn stream_count(&self, kind: StreamKind) -> usize {
+        match kind {
+            StreamKind::ValueInput => self.in_value_streams,
+            StreamKind::ControlInput => self.in_control_streams,
+            StreamKind::ValueOutput => self.out_value_streams,
+        }
+    }
+}
+
+impl<
+    D: DynInstrument<Note>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized,
+> Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> for StaticAdapter<D, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> {
+    fn process_block<const BLOCK_SIZE: usize, const CONTROL_ELEMENTS: usize>(
+        &mut self,
+        input: &InstrumentInput<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, Note, BLOCK_SIZE, CONTROL_ELEMENTS>,
+        output: &mut InstrumentOutput<OUT_VALUE_STREAMS, BLOCK_SIZE>,
+    ) {
+        let input = DynInput {
+            value_streams: input.value_streams[..self.in_value_streams].as_flattened(),
+            control_streams: input.control_streams[..self.in_control_streams].as_flattened(),
+            parameter_events: input.parameter_events(),
+            block_size: BLOCK_SIZE,
+            control_elements: CONTROL_ELEMENTS,
+        };
+        let mut output = DynOutput {
+            value_streams: output.value_streams[..self.out_value_streams].as_flattened_mut(),
+            block_size: BLOCK_SIZE,
+        };
+        self.instrument.process_block(&input, &mut output);
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        self.instrument.prepare(sampling_rate, max_block);
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        if index < self.stream_count(kind) { self.instrument.stream_info(kind, index) } else { None }
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.instrument.parameter_count()
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        self.instrument.parameter_info(id)
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        self.instrument.parameter(id)
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        self.instrument.set_parameter(id, value);
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.instrument.save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.instrument.load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.instrument.check_state(reader)
+    }
+
+    fn latency(&self) -> usize {
+        self.instrument.latency()
+    }
+
+    fn reset(&mut self) {
+        self.instrument.reset();
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        self.instrument.tail_length()
+    }
+
+    fn is_silent(&self) -> bool {
+        self.instrument.is_silent()
+    }
+}
+
+/// A container for a `DynInstrument`, reporting the stream counts of the instrument
+struct DynContainer<D: DynInstrument<Note>, Note: Sized, const BLOCK_SIZE: usize> {
+    inner: InstrumentContainerImpl<
+        StaticAdapter<D, MAX_DYN_VALUE_STREAMS, MAX_DYN_CONTROL_STREAMS, MAX_DYN_VALUE_STREAMS, Note>,
+        MAX_DYN_VALUE_STREAMS,
+        MAX_DYN_CONTROL_STREAMS,
+        MAX_DYN_VALUE_STREAMS,
+        Note,
+        BLOCK_SIZE,
+    >,
+}
+
+impl<D: DynInstrument<Note>, Note: Sized, const BLOCK_SIZE: usize> DynContainer<D, Note, BLOCK_SIZE> {
+    fn check_stream(&self, kind: StreamKind, index: usize) {
+        if index >= self.inner.instrument.stream_count(kind) {
+            panic!("Stream index out of bounds");
+        }
+    }
+}
+
+impl<D: DynInstrument<Note> + Send, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize> InstrumentContainer<Note, BLOCK_SIZE> for DynContainer<D, Note, BLOCK_SIZE> {
+    fn in_value_streams(&self) -> usize {
+        self.inner.instrument.stream_count(StreamKind::ValueInput)
+    }
+
+    fn in_control_streams(&self) -> usize {
+        self.inner.instrument.stream_count(StreamKind::ControlInput)
+    }
+
+    fn out_value_streams(&self) -> usize {
+        self.inner.instrument.stream_count(StreamKind::ValueOutput)
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        self.inner.stream_info(kind, index)
+    }
+
+    fn process_next(&mut self) {
+        self.inner.process_next();
+    }
+
+    fn process_bypassed(&mut self, input_index: usize, output_index: usize) {
+        self.check_stream(StreamKind::ValueInput, input_index);
+        self.check_stream(StreamKind::ValueOutput, output_index);
+        self.inner.process_bypassed(input_index, output_index);
+    }
+
+    fn process_muted(&mut self, process: bool) {
+        self.inner.process_muted(process);
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.inner.parameter_count()
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        self.inner.parameter_info(id)
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        self.inner.parameter(id)
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        self.inner.set_parameter(id, value);
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.inner.save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.inner.load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.inner.check_state(reader)
+    }
+
+    fn latency(&self) -> usize {
+        self.inner.latency()
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        self.inner.tail_length()
+    }
+
+    fn is_silent(&self) -> bool {
+        self.inner.is_silent()
+    }
+
+    fn feed_parameter_event(&mut self, event: ParameterEvent) -> bool {
+        self.inner.feed_parameter_event(event)
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        self.inner.prepare(sampling_rate, max_block);
+    }
+
+    fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE] {
+        self.check_stream(StreamKind::ValueOutput, index);
+        self.inner.get_output(index)
+    }
+
+    fn feed_control_stream(&mut self, stream_index: usize, stream: &[NoteCommand<Note>]) {
+        self.check_stream(StreamKind::ControlInput, stream_index);
+        self.inner.feed_control_stream(stream_index, stream);
+    }
+
+    fn feed_value_stream(&mut self, stream_index: usize, stream: &[MusicalValue]) {
+        self.check_stream(StreamKind::ValueInput, stream_index);
+        self.inner.feed_value_stream(stream_index, stream);
+    }
+}
+
+/// Wraps a `DynInstrument` into a container processing blocks of `STANDARD_BLOCK_SIZE` samples
+///
+/// The instrument can have up to `MAX_DYN_VALUE_STREAMS` value streams and `MAX_DYN_CONTROL_STREAMS` control
+/// streams, more panic.
+pub fn dyn_container<D: DynInstrument<Note> + Send, Note: Sized + Default + Copy + Send>(instrument: D) -> impl InstrumentContainer<Note> {
+    dyn_container_with_block_size::<STANDARD_BLOCK_SIZE, D, Note>(instrument)
+}
+
+/// Wraps a `DynInstrument` into a container processing blocks of `BLOCK_SIZE` samples
+pub fn dyn_container_with_block_size<const BLOCK_SIZE: usize, D: DynInstrument<Note> + Send, Note: Sized + Default + Copy + Send>(
+    instrument: D,
+) -> impl InstrumentContainer<Note, BLOCK_SIZE> {
+    DynContainer::<D, Note, BLOCK_SIZE> {
+        inner: InstrumentContainerImpl::new(StaticAdapter::new(instrument)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::graph::InstrumentGraph;
+    use crate::instrument::{Amplifier, Constant};
+
+    /// Sums its value streams into one
+    struct Sum(usize);
+
+    impl DynInstrument for Sum {
+        fn in_value_streams(&self) -> usize {
+            self.0
+        }
+
+        fn in_control_streams(&self) -> usize {
+            0
+        }
+
+        fn out_value_streams(&self) -> usize {
+            1
+        }
+
+        fn process_block(&mut self, input: &DynInput, output: &mut DynOutput) {
+            let sum = output.value_stream_mut(0);
+            sum.fill(0.0);
+            for index in 0..self.0 {
+                for (sum, value) in sum.iter_mut().zip(input.value_stream(index)) {
+                    *sum += value;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dyn_container_processes_in_graph() {
+        let mut first = container(Constant::<MidiNote>::new(1.0));
+        let mut second = container(Constant::<MidiNote>::new(2.0));
+        let mut sum = dyn_container(Sum(2));
+        assert_eq!((sum.in_value_streams(), sum.in_control_streams(), sum.out_value_streams()), (2, 0, 1));
+
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let first = graph.add_instrument(&mut first);
+        let second = graph.add_instrument(&mut second);
+        let sum = graph.add_instrument(&mut sum);
+        graph.connect_value_stream(first, 0, sum, 0);
+        graph.connect_value_stream(second, 0, sum, 1);
+        graph.connect_destination(0, sum, 0);
+        graph.process_next();
+        assert!(graph.get_output(0).iter().all(|&value| value == 3.0));
+    }
+
+    #[test]
+    fn static_adapter_leaves_spare_streams() {
+        let mut adapter = StaticAdapter::<_, 4, 0, 2>::new(Sum(2));
+        assert_eq!(adapter.stream_count(StreamKind::ValueInput), 2);
+
+        let mut input = InstrumentInput::<4, 0, MidiNote, 8, 1> {
+            control_streams: [],
+            value_streams: [[1.0; 8], [0.5; 8], [7.0; 8], [7.0; 8]],
+            parameter_events: [ParameterEvent::default(); MAX_PARAMETER_EVENTS],
+            parameter_event_count: 0,
+        };
+        input.value_streams[1][3] = 2.0;
+        let mut output = InstrumentOutput { value_streams: [[9.0; 8]; 2] };
+        adapter.process_block(&input, &mut output);
+        assert_eq!(output.value_streams[0], [1.5, 1.5, 1.5, 3.0, 1.5, 1.5, 1.5, 1.5]);
+        assert_eq!(output.value_streams[1], [9.0; 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stream count out of bounds")]
+    fn static_adapter_rejects_too_many_streams() {
+        StaticAdapter::<_, 1, 0, 1>::new(Sum(2));
+    }
+
+    #[test]
+    fn dyn_adapter_round_trip() {
+        let mut adapter = StaticAdapter::<_, 2, 0, 1>::new(DynAdapter::<_, 2, 0, 1, MidiNote, 8>::new(Amplifier::<MidiNote>::new()));
+        let input = InstrumentInput::<2, 0, MidiNote, 8, 1> {
+            control_streams: [],
+            value_streams: [[0.5; 8], [0.5; 8]],
+            parameter_events: [ParameterEvent::default(); MAX_PARAMETER_EVENTS],
+            parameter_event_count: 0,
+        };
+        let mut output = InstrumentOutput { value_streams: [[0.0; 8]; 1] };
+        adapter.process_block(&input, &mut output);
+        assert_eq!(output.value_streams[0], [0.25; 8]);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "Block size must match the adapter"))]
+    fn dyn_adapter_rejects_other_block_sizes() {
+        let mut adapter = DynAdapter::<_, 2, 0, 1, MidiNote, 8>::new(Amplifier::<MidiNote>::new());
+        let values = [0.5; 8];
+        let mut output = [1.0; 4];
+        adapter.process_block(
+            &DynInput { value_streams: &values, control_streams: &[], parameter_events: &[], block_size: 4, control_elements: 0 },
+            &mut DynOutput { value_streams: &mut output, block_size: 4 },
+        );
+        assert_eq!(output, [0.0; 4]);
+    }
+}
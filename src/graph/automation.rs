@@ -0,0 +1,204 @@
+//! Parameter automation sent from a control thread.
+//!
+//! The control thread pushes `ParameterChange`s into a `Queue`, and the audio thread moves them into
+//! the graph with `InstrumentGraph::apply_parameter_changes` between blocks. Changes are timed in
+//! samples since the graph started processing and handed to their instrument in the block they
+//! fall into, at the right offset.
+
+use core::ops::Deref;
+
+use crate::parameter::{ParameterEvent, ParameterId};
+use crate::queue::{Consumer, Queue};
+use crate::MusicalValue;
+
+use super::InstrumentGraph;
+
+/// The number of parameter changes a graph holds until they are due
+pub const MAX_PENDING_PARAMETER_CHANGES: usize = 64;
+
+/// A change of a parameter of an instrument in a graph
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ParameterChange {
+    pub instrument_index: usize,
+    pub id: ParameterId,
+    pub value: MusicalValue,
+
+    /// The sample position at which the value takes effect, see `InstrumentGraph::sample_position`.
+    ///
+    /// Changes in the past take effect at the start of the next block.
+    pub time: u64,
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// The number of samples processed so far, which is the position of the first sample of the next block
+    pub fn sample_position(&self) -> u64 {
+        self.sample_position
+    }
+
+    /// Finds the id of a parameter of the instrument at the given index by its name
+    pub fn find_parameter(&self, instrument_index: usize, name: &str) -> Option<ParameterId> {
+        let instrument = self.instruments.get(instrument_index)?.as_ref()?;
+        (0..instrument.parameter_count()).find(|&id| instrument.parameter_info(id).is_some_and(|info| info.name == name))
+    }
+
+    /// Gets the current value of a parameter of the instrument at the given index
+    pub fn parameter(&self, instrument_index: usize, id: ParameterId) -> Option<MusicalValue> {
+        self.instruments.get(instrument_index)?.as_ref()?.parameter(id)
+    }
+
+    /// Schedules a parameter change, giving it back if too many changes are pending
+    pub fn schedule_parameter_change(&mut self, change: ParameterChange) -> Result<(), ParameterChange> {
+        if self.pending_parameter_count == MAX_PENDING_PARAMETER_CHANGES {
+            return Err(change);
+        }
+
+        self.pending_parameters[self.pending_parameter_count] = change;
+        self.pending_parameter_count += 1;
+        Ok(())
+    }
+
+    /// Sets a parameter at the start of the next block
+    pub fn set_parameter(&mut self, instrument_index: usize, id: ParameterId, value: MusicalValue) {
+        if instrument_index >= SIZE {
+            panic!("Instrument index out of bounds");
+        }
+
+        if self.schedule_parameter_change(ParameterChange { instrument_index, id, value, time: 0 }).is_err() {
+            panic!("No more space for pending parameter changes");
+        }
+    }
+
+    /// Moves every sent parameter change into the graph, to be called on the audio thread before `process_next`.
+    ///
+    /// When too many changes are pending, the rest stay in the queue until the next call.
+    pub fn apply_parameter_changes<C, const CHANGES: usize>(&mut self, changes: &mut Consumer<C>)
+    where
+        C: Deref<Target = Queue<ParameterChange, CHANGES>>,
+    {
+        while self.pending_parameter_count < MAX_PENDING_PARAMETER_CHANGES {
+            match changes.pop() {
+                Some(change) => {
+                    self.pending_parameters[self.pending_parameter_count] = change;
+                    self.pending_parameter_count += 1;
+                },
+                None => return,
+            }
+        }
+    }
+
+    /// Hands the changes falling into the next block to their instruments, keeping the others pending in order
+    pub(crate) fn dispatch_parameter_changes(&mut self) {
+        let block_end = self.sample_position + BLOCK_SIZE as u64;
+        let mut kept = 0;
+        for i in 0..self.pending_parameter_count {
+            let change = self.pending_parameters[i];
+
+            // Changes for empty slots are dropped, changes that do not fit in the block are retried in the next one
+            let done = change.time < block_end && match self.instruments.get_mut(change.instrument_index) {
+                Some(Some(instrument)) => instrument.feed_parameter_event(ParameterEvent {
+                    id: change.id,
+                    value: change.value,
+                    offset: change.time.saturating_sub(self.sample_position) as usize,
+                }),
+                _ => true,
+            };
+
+            if !done {
+                self.pending_parameters[kept] = change;
+                kept += 1;
+            }
+        }
+        self.pending_parameter_count = kept;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::Constant;
+    use crate::parameter::{ParameterInfo, ParameterUnit, MAX_PARAMETER_EVENTS};
+    use crate::{container, Instrument, InstrumentInput, InstrumentOutput, MidiNote};
+
+    /// Outputs its level without following the parameter segments
+    struct Level {
+        level: MusicalValue,
+    }
+
+    impl Instrument<0, 0, 1> for Level {
+        fn process_block<const BLOCK_SIZE: usize, const CONTROL_ELEMENTS: usize>(
+            &mut self,
+            _input: &InstrumentInput<0, 0, MidiNote, BLOCK_SIZE, CONTROL_ELEMENTS>,
+            output: &mut InstrumentOutput<1, BLOCK_SIZE>,
+        ) {
+            output.value_streams[0] = [self.level; BLOCK_SIZE];
+        }
+
+        fn parameter_count(&self) -> usize {
+            1
+        }
+
+        fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+            (id == 0).then(|| ParameterInfo::new("level", ParameterUnit::Gain, 0.0, 10.0, 0.0))
+        }
+
+        fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+            if id == 0 {
+                self.level = value;
+            }
+        }
+    }
+
+    #[test]
+    fn changes_take_effect_at_their_sample() {
+        let mut constant = container(Constant::<MidiNote>::new(0.0));
+        let mut graph: InstrumentGraph<1, 1, 1, 1> = InstrumentGraph::new();
+        let index = graph.add_instrument(&mut constant);
+        graph.connect_destination(0, index, 0);
+
+        graph.schedule_parameter_change(ParameterChange { instrument_index: index, id: 0, value: 1.0, time: 10 }).unwrap();
+        graph.schedule_parameter_change(ParameterChange { instrument_index: index, id: 0, value: 2.0, time: 200 }).unwrap();
+
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[9], 0.0);
+        assert_eq!(graph.get_output(0)[10], 1.0);
+        assert_eq!(graph.get_output(0)[127], 1.0);
+
+        // The second change falls into the next block
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[71], 1.0);
+        assert_eq!(graph.get_output(0)[72], 2.0);
+    }
+
+    #[test]
+    fn changes_that_do_not_fit_are_carried_to_the_next_block() {
+        let mut constant = container(Constant::<MidiNote>::new(0.0));
+        let mut graph: InstrumentGraph<1, 1, 1, 1> = InstrumentGraph::new();
+        let index = graph.add_instrument(&mut constant);
+        graph.connect_destination(0, index, 0);
+
+        for i in 0..=MAX_PARAMETER_EVENTS {
+            graph.schedule_parameter_change(ParameterChange { instrument_index: index, id: 0, value: i as MusicalValue, time: i as u64 }).unwrap();
+        }
+
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[127], (MAX_PARAMETER_EVENTS - 1) as MusicalValue);
+
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], MAX_PARAMETER_EVENTS as MusicalValue);
+    }
+
+    #[test]
+    fn instruments_ignoring_segments_follow_per_block() {
+        let mut level = container(Level { level: 0.0 });
+        let mut graph: InstrumentGraph<1, 1, 1, 1> = InstrumentGraph::new();
+        let index = graph.add_instrument(&mut level);
+        graph.connect_destination(0, index, 0);
+
+        graph.schedule_parameter_change(ParameterChange { instrument_index: index, id: 0, value: 1.0, time: 10 }).unwrap();
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[127], 0.0);
+
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 1.0);
+    }
+}
@@ -0,0 +1,569 @@
+//! Send/return buses.
+//!
+//! A bus sums the outputs sent into it, each scaled by its send level, so that many instruments can share
+//! a single effect. The sum feeds the value streams of the instruments connected to the bus, typically an
+//! effect, whose output is returned to output channels at a return level. Instruments fed by a bus are
+//! processed after every instrument sending into it.
+//!
+//! Level changes are ramped over a block to avoid clicks.
+
+use crate::{InstrumentContainer, MusicalValue};
+
+use super::{InstrumentGraph, NodeMode};
+
+/// The maximum number of buses in a graph
+pub const MAX_BUSES: usize = 8;
+
+/// The maximum number of sends, instrument connections and returns of each bus
+pub const MAX_BUS_CONNECTIONS: usize = 16;
+
+/// Where the signal of a send is taken from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SendPosition {
+    /// Before the node mode, the send keeps sending while the instrument is muted,
+    /// unless it is muted with `skip_processing`
+    Pre,
+
+    /// After the node mode, the send is silent while the instrument is muted
+    Post,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BusSend {
+    pub source_index: usize,
+    pub source_stream_index: usize,
+    pub level: MusicalValue,
+
+    /// The level of the previous block, ramped from
+    pub previous_level: MusicalValue,
+    pub position: SendPosition,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BusConnection {
+    pub destination_index: usize,
+    pub destination_stream_index: usize,
+}
+
+/// The reason a bus route could not be changed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BusError {
+    /// The bus, instrument or output channel index is out of bounds
+    IndexOutOfBounds,
+
+    /// There is no bus at the given index
+    NoBus,
+
+    /// The instrument does not have the given stream
+    StreamOutOfBounds,
+
+    /// There is no more space for the route
+    NoSpace,
+
+    /// The route would make an instrument depend on itself
+    Cycle,
+
+    /// The bus has no send from the given stream, or no return to the given output channel
+    NoRoute,
+}
+
+impl core::fmt::Display for BusError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BusError::IndexOutOfBounds => write!(f, "index out of bounds"),
+            BusError::NoBus => write!(f, "no bus at the given index"),
+            BusError::StreamOutOfBounds => write!(f, "stream index out of bounds"),
+            BusError::NoSpace => write!(f, "no more space for the route"),
+            BusError::Cycle => write!(f, "the route would create a cycle"),
+            BusError::NoRoute => write!(f, "no such send or return"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BusReturn {
+    /// The instrument output returned, typically of the effect fed by the bus
+    pub source_index: usize,
+    pub source_stream_index: usize,
+    pub output_channel_index: usize,
+    pub level: MusicalValue,
+    pub previous_level: MusicalValue,
+}
+
+pub(crate) struct Bus<const BLOCK_SIZE: usize> {
+    pub name: &'static str,
+    pub sends: [Option<BusSend>; MAX_BUS_CONNECTIONS],
+    pub connections: [Option<BusConnection>; MAX_BUS_CONNECTIONS],
+    pub returns: [Option<BusReturn>; MAX_BUS_CONNECTIONS],
+
+    /// The sum of the sends for the current block, valid once `mixed` is set
+    pub buffer: [MusicalValue; BLOCK_SIZE],
+    pub mixed: bool,
+}
+
+/// Adds `source` to `destination`, scaled by a gain ramping from `from` to `to` over the block
+pub(crate) fn mix_ramped<const BLOCK_SIZE: usize>(destination: &mut [MusicalValue; BLOCK_SIZE], source: &[MusicalValue; BLOCK_SIZE], from: MusicalValue, to: MusicalValue) {
+    let step = (to - from) / BLOCK_SIZE as MusicalValue;
+    for (k, (sample, source)) in destination.iter_mut().zip(source).enumerate() {
+        *sample += source * (from + step * (k + 1) as MusicalValue);
+    }
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Adds a bus with the given name, returning its index
+    pub fn add_bus(&mut self, name: &'static str) -> usize {
+        for i in 0..MAX_BUSES {
+            if self.buses[i].is_none() {
+                self.buses[i] = Some(Bus {
+                    name,
+                    sends: core::array::from_fn(|_| None),
+                    connections: core::array::from_fn(|_| None),
+                    returns: core::array::from_fn(|_| None),
+                    buffer: [0.0; BLOCK_SIZE],
+                    mixed: false,
+                });
+                return i;
+            }
+        }
+        panic!("No more space for buses");
+    }
+
+    /// Removes a bus with its sends, connections and returns
+    pub fn remove_bus(&mut self, bus_index: usize) -> Result<(), BusError> {
+        if bus_index >= MAX_BUSES {
+            return Err(BusError::IndexOutOfBounds);
+        }
+
+        self.buses[bus_index] = None;
+        self.process_order_dirty = true;
+        Ok(())
+    }
+
+    /// Finds the index of a bus by its name
+    pub fn find_bus(&self, name: &str) -> Option<usize> {
+        self.buses.iter().position(|bus| bus.as_ref().is_some_and(|bus| bus.name == name))
+    }
+
+    /// The name of the bus at the given index, if there is one
+    pub fn bus_name(&self, bus_index: usize) -> Option<&'static str> {
+        self.buses.get(bus_index)?.as_ref().map(|bus| bus.name)
+    }
+
+    /// Sends an output stream of an instrument into a bus at the given level.
+    ///
+    /// Sending the same stream again changes the level and position of the existing send. Sending from an
+    /// instrument fed by the bus, directly or through other instruments, is rejected.
+    pub fn send_to_bus(&mut self, bus_index: usize, source_index: usize, source_stream_index: usize, level: MusicalValue, position: SendPosition) -> Result<(), BusError> {
+        if source_index >= SIZE {
+            return Err(BusError::IndexOutOfBounds);
+        }
+        self.check_output_stream(source_index, source_stream_index)?;
+
+        let bus = self.bus(bus_index)?;
+        let fed_by_bus = |index: usize| bus.connections.iter().flatten().any(|connection| connection.destination_index == index);
+        if (0..SIZE).any(|index| fed_by_bus(index) && (index == source_index || self.depends_on(source_index, index))) {
+            return Err(BusError::Cycle);
+        }
+
+        let bus = self.bus_mut(bus_index)?;
+        if let Some(send) = bus.sends.iter_mut().flatten().find(|send| send.source_index == source_index && send.source_stream_index == source_stream_index) {
+            send.level = level;
+            send.position = position;
+            return Ok(());
+        }
+
+        let send = bus.sends.iter_mut().find(|send| send.is_none()).ok_or(BusError::NoSpace)?;
+        *send = Some(BusSend {
+            source_index,
+            source_stream_index,
+            level,
+            previous_level: level,
+            position,
+        });
+        self.process_order_dirty = true;
+        Ok(())
+    }
+
+    /// Changes the level of the given send, ramping to it over the next block
+    pub fn set_send_level(&mut self, bus_index: usize, source_index: usize, source_stream_index: usize, level: MusicalValue) -> Result<(), BusError> {
+        let send = self.bus_mut(bus_index)?.sends.iter_mut().flatten()
+            .find(|send| send.source_index == source_index && send.source_stream_index == source_stream_index)
+            .ok_or(BusError::NoRoute)?;
+        send.level = level;
+        Ok(())
+    }
+
+    /// The level of the given send, if it exists
+    pub fn send_level(&self, bus_index: usize, source_index: usize, source_stream_index: usize) -> Option<MusicalValue> {
+        let bus = self.buses.get(bus_index)?.as_ref()?;
+        bus.sends.iter().flatten()
+            .find(|send| send.source_index == source_index && send.source_stream_index == source_stream_index)
+            .map(|send| send.level)
+    }
+
+    /// Removes the given send, if it exists
+    pub fn remove_send(&mut self, bus_index: usize, source_index: usize, source_stream_index: usize) -> Result<(), BusError> {
+        let bus = self.bus_mut(bus_index)?;
+        if let Some(send) = bus.sends.iter_mut().find(|send| send.as_ref().is_some_and(|send| send.source_index == source_index && send.source_stream_index == source_stream_index)) {
+            *send = None;
+            self.process_order_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Feeds the sum of a bus into a value stream of an instrument, such as the input of an effect.
+    ///
+    /// Feeding an instrument sending into the bus, directly or through other instruments, is rejected.
+    pub fn connect_bus(&mut self, bus_index: usize, destination_index: usize, destination_stream_index: usize) -> Result<(), BusError> {
+        if destination_index >= SIZE {
+            return Err(BusError::IndexOutOfBounds);
+        }
+        if let Some(instrument) = &self.instruments[destination_index] {
+            if destination_stream_index >= instrument.in_value_streams() {
+                return Err(BusError::StreamOutOfBounds);
+            }
+        }
+
+        let bus = self.bus(bus_index)?;
+        if bus.sends.iter().flatten().any(|send| send.source_index == destination_index || self.depends_on(send.source_index, destination_index)) {
+            return Err(BusError::Cycle);
+        }
+
+        let connection = self.bus_mut(bus_index)?.connections.iter_mut().find(|connection| connection.is_none()).ok_or(BusError::NoSpace)?;
+        *connection = Some(BusConnection {
+            destination_index,
+            destination_stream_index,
+        });
+        self.process_order_dirty = true;
+        Ok(())
+    }
+
+    /// Removes the given bus connection, if it exists
+    pub fn disconnect_bus(&mut self, bus_index: usize, destination_index: usize, destination_stream_index: usize) -> Result<(), BusError> {
+        let bus = self.bus_mut(bus_index)?;
+        if let Some(connection) = bus.connections.iter_mut().find(|connection| connection.as_ref().is_some_and(|connection| connection.destination_index == destination_index && connection.destination_stream_index == destination_stream_index)) {
+            *connection = None;
+            self.process_order_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Returns an output stream of an instrument, typically the effect fed by the bus, to an output channel at the
+    /// given level.
+    ///
+    /// Returns are not affected by soloed destination connections, and are silent while their source is muted.
+    /// Each bus has at most one return per output channel, returning to the same channel again replaces the
+    /// source and level of the existing return.
+    pub fn connect_bus_return(&mut self, bus_index: usize, source_index: usize, source_stream_index: usize, output_channel_index: usize, level: MusicalValue) -> Result<(), BusError> {
+        if source_index >= SIZE || output_channel_index >= OUTPUT_CHANNELS {
+            return Err(BusError::IndexOutOfBounds);
+        }
+        self.check_output_stream(source_index, source_stream_index)?;
+
+        let bus = self.bus_mut(bus_index)?;
+        if let Some(bus_return) = bus.returns.iter_mut().flatten().find(|bus_return| bus_return.output_channel_index == output_channel_index) {
+            bus_return.source_index = source_index;
+            bus_return.source_stream_index = source_stream_index;
+            bus_return.level = level;
+            return Ok(());
+        }
+
+        let bus_return = bus.returns.iter_mut().find(|bus_return| bus_return.is_none()).ok_or(BusError::NoSpace)?;
+        *bus_return = Some(BusReturn {
+            source_index,
+            source_stream_index,
+            output_channel_index,
+            level,
+            previous_level: level,
+        });
+        Ok(())
+    }
+
+    /// Removes the return of a bus to an output channel, if it exists
+    pub fn disconnect_bus_return(&mut self, bus_index: usize, output_channel_index: usize) -> Result<(), BusError> {
+        let bus = self.bus_mut(bus_index)?;
+        if let Some(bus_return) = bus.returns.iter_mut().find(|bus_return| bus_return.as_ref().is_some_and(|bus_return| bus_return.output_channel_index == output_channel_index)) {
+            *bus_return = None;
+        }
+        Ok(())
+    }
+
+    /// Changes the level of the return of a bus to an output channel, ramping to it over the next block
+    pub fn set_return_level(&mut self, bus_index: usize, output_channel_index: usize, level: MusicalValue) -> Result<(), BusError> {
+        let bus_return = self.bus_mut(bus_index)?.returns.iter_mut().flatten()
+            .find(|bus_return| bus_return.output_channel_index == output_channel_index)
+            .ok_or(BusError::NoRoute)?;
+        bus_return.level = level;
+        Ok(())
+    }
+
+    fn bus(&self, bus_index: usize) -> Result<&Bus<BLOCK_SIZE>, BusError> {
+        match self.buses.get(bus_index) {
+            Some(Some(bus)) => Ok(bus),
+            Some(None) => Err(BusError::NoBus),
+            None => Err(BusError::IndexOutOfBounds),
+        }
+    }
+
+    fn bus_mut(&mut self, bus_index: usize) -> Result<&mut Bus<BLOCK_SIZE>, BusError> {
+        match self.buses.get_mut(bus_index) {
+            Some(Some(bus)) => Ok(bus),
+            Some(None) => Err(BusError::NoBus),
+            None => Err(BusError::IndexOutOfBounds),
+        }
+    }
+
+    /// Checks that the instrument in a slot has the given output stream, an empty slot has every stream
+    fn check_output_stream(&self, index: usize, stream_index: usize) -> Result<(), BusError> {
+        match &self.instruments[index] {
+            Some(instrument) if stream_index >= instrument.out_value_streams() => Err(BusError::StreamOutOfBounds),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the sends from, connections to and returns from an instrument
+    pub(crate) fn remove_bus_routes(&mut self, instrument_index: usize) {
+        for bus in self.buses.iter_mut().flatten() {
+            for send in bus.sends.iter_mut() {
+                if send.as_ref().is_some_and(|send| send.source_index == instrument_index) {
+                    *send = None;
+                }
+            }
+            for bus_return in bus.returns.iter_mut() {
+                if bus_return.as_ref().is_some_and(|bus_return| bus_return.source_index == instrument_index) {
+                    *bus_return = None;
+                }
+            }
+            for connection in bus.connections.iter_mut() {
+                if connection.as_ref().is_some_and(|connection| connection.destination_index == instrument_index) {
+                    *connection = None;
+                }
+            }
+        }
+    }
+
+    /// The instruments sending into the buses connected to an instrument
+    pub(crate) fn bus_dependencies(&self, instrument_index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.buses.iter().flatten()
+            .filter(move |bus| bus.connections.iter().flatten().any(|connection| connection.destination_index == instrument_index))
+            .flat_map(|bus| bus.sends.iter().flatten().map(|send| send.source_index))
+    }
+
+    /// Sums the sends of a bus for the current block, unless it already has been
+    pub(crate) fn mix_bus(&mut self, bus_index: usize) {
+        let Some(bus) = &mut self.buses[bus_index] else {
+            return;
+        };
+        if bus.mixed {
+            return;
+        }
+
+        bus.buffer = [0.0; BLOCK_SIZE];
+        for send in bus.sends.iter_mut().flatten() {
+            let muted = matches!(self.node_modes[send.source_index], NodeMode::Muted { .. });
+            if let Some(instrument) = &self.instruments[send.source_index] {
+                if !muted || send.position == SendPosition::Pre {
+                    mix_ramped(&mut bus.buffer, instrument.get_output(send.source_stream_index), send.previous_level, send.level);
+                }
+            }
+            send.previous_level = send.level;
+        }
+        bus.mixed = true;
+    }
+
+    /// Feeds the buses connected to an instrument, which is taken out of the graph while it is fed
+    pub(crate) fn feed_buses(&mut self, instrument_index: usize, instrument: &mut dyn InstrumentContainer<Note, BLOCK_SIZE>) {
+        for bus_index in 0..MAX_BUSES {
+            let Some(bus) = &self.buses[bus_index] else {
+                continue;
+            };
+            if !bus.connections.iter().flatten().any(|connection| connection.destination_index == instrument_index) {
+                continue;
+            }
+
+            self.mix_bus(bus_index);
+            if let Some(bus) = &self.buses[bus_index] {
+                for connection in bus.connections.iter().flatten().filter(|connection| connection.destination_index == instrument_index) {
+                    instrument.feed_value_stream(connection.destination_stream_index, &bus.buffer);
+                }
+            }
+        }
+    }
+
+    /// Adds the returns of the buses to an output channel, returning whether anything was added.
+    ///
+    /// Unless `written` is set, the channel is cleared before the first return is added.
+    pub(crate) fn mix_bus_returns(&mut self, output_channel_index: usize, mut written: bool) -> bool {
+        for bus in self.buses.iter_mut().flatten() {
+            for bus_return in bus.returns.iter_mut().flatten().filter(|bus_return| bus_return.output_channel_index == output_channel_index) {
+                if let Some(source) = return_source(&self.instruments, &self.node_modes, bus_return) {
+                    if !written {
+                        self.output_channels[output_channel_index] = [0.0; BLOCK_SIZE];
+                        written = true;
+                    }
+                    mix_ramped(&mut self.output_channels[output_channel_index], source, bus_return.previous_level, bus_return.level);
+                }
+                bus_return.previous_level = bus_return.level;
+            }
+        }
+        written
+    }
+}
+
+/// The output stream of a return, `None` while its source is missing or muted
+pub(crate) fn return_source<'s, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize>(
+    instruments: &'s [Option<&mut dyn InstrumentContainer<Note, BLOCK_SIZE>>],
+    node_modes: &[NodeMode],
+    bus_return: &BusReturn,
+) -> Option<&'s [MusicalValue; BLOCK_SIZE]> {
+    if matches!(node_modes[bus_return.source_index], NodeMode::Muted { .. }) {
+        return None;
+    }
+    instruments[bus_return.source_index].as_ref().map(|instrument| instrument.get_output(bus_return.source_stream_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::{Amplifier, Constant, Mixer};
+    use crate::MidiNote;
+
+    #[test]
+    fn returns_effect_output() {
+        let mut dry = container(Constant::<MidiNote>::new(1.0));
+        let mut gain = container(Constant::<MidiNote>::new(3.0));
+        let mut effect = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 2> = InstrumentGraph::new();
+        let dry = graph.add_instrument(&mut dry);
+        let gain = graph.add_instrument(&mut gain);
+        let effect = graph.add_instrument(&mut effect);
+
+        let bus = graph.add_bus("fx");
+        graph.send_to_bus(bus, dry, 0, 0.5, SendPosition::Post).unwrap();
+        graph.connect_bus(bus, effect, 0).unwrap();
+        graph.connect_value_stream(gain, 0, effect, 1);
+        graph.connect_bus_return(bus, effect, 0, 1, 0.5).unwrap();
+        graph.connect_destination(0, dry, 0);
+        graph.process_next();
+
+        assert_eq!(graph.get_output(0)[0], 1.0);
+        assert_eq!(graph.get_output(1)[0], 0.75);
+
+        graph.set_node_mode(effect, NodeMode::Muted { skip_processing: false });
+        graph.process_next();
+        assert_eq!(graph.get_output(1)[0], 0.0);
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        let mut source = container(Constant::<MidiNote>::new(1.0));
+        let mut effect = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let source = graph.add_instrument(&mut source);
+        let effect = graph.add_instrument(&mut effect);
+
+        assert_eq!(graph.send_to_bus(0, source, 0, 1.0, SendPosition::Post), Err(BusError::NoBus));
+        assert_eq!(graph.set_return_level(MAX_BUSES, 0, 1.0), Err(BusError::IndexOutOfBounds));
+
+        let bus = graph.add_bus("fx");
+        assert_eq!(graph.send_to_bus(bus, source, 1, 1.0, SendPosition::Post), Err(BusError::StreamOutOfBounds));
+        assert_eq!(graph.connect_bus(bus, source, 0), Err(BusError::StreamOutOfBounds));
+        assert_eq!(graph.connect_bus_return(bus, effect, 0, 1, 1.0), Err(BusError::IndexOutOfBounds));
+
+        graph.send_to_bus(bus, source, 0, 1.0, SendPosition::Post).unwrap();
+        graph.connect_bus(bus, effect, 0).unwrap();
+        assert_eq!(graph.send_to_bus(bus, effect, 0, 1.0, SendPosition::Post), Err(BusError::Cycle));
+        assert!(graph.check_connections().is_ok());
+
+        assert_eq!(graph.set_send_level(bus, effect, 0, 0.5), Err(BusError::NoRoute));
+        assert_eq!(graph.set_return_level(bus, 0, 0.5), Err(BusError::NoRoute));
+    }
+
+    #[test]
+    fn pre_sends_keep_sending_while_muted() {
+        let mut pre = container(Constant::<MidiNote>::new(1.0));
+        let mut post = container(Constant::<MidiNote>::new(1.0));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let pre = graph.add_instrument(&mut pre);
+        let post = graph.add_instrument(&mut post);
+        let mixer = graph.add_instrument(&mut mixer);
+
+        let bus = graph.add_bus("fx");
+        graph.send_to_bus(bus, pre, 0, 0.5, SendPosition::Pre).unwrap();
+        graph.send_to_bus(bus, post, 0, 0.25, SendPosition::Post).unwrap();
+        graph.connect_bus(bus, mixer, 0).unwrap();
+        graph.connect_destination(0, mixer, 0);
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 0.75);
+
+        graph.set_node_mode(pre, NodeMode::Muted { skip_processing: false });
+        graph.set_node_mode(post, NodeMode::Muted { skip_processing: false });
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 0.5);
+
+        // Instruments that are not processed have nothing to send
+        graph.set_node_mode(pre, NodeMode::Muted { skip_processing: true });
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 0.0);
+    }
+
+    #[test]
+    fn ramps_level_changes() {
+        let mut destination = [0.0; 4];
+        mix_ramped(&mut destination, &[2.0; 4], 0.0, 1.0);
+        assert_eq!(destination, [0.5, 1.0, 1.5, 2.0]);
+
+        let mut source = container(Constant::<MidiNote>::new(1.0));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let source = graph.add_instrument(&mut source);
+        let mixer = graph.add_instrument(&mut mixer);
+
+        let bus = graph.add_bus("fx");
+        graph.send_to_bus(bus, source, 0, 0.5, SendPosition::Post).unwrap();
+        graph.connect_bus(bus, mixer, 0).unwrap();
+        graph.connect_bus_return(bus, mixer, 0, 0, 1.0).unwrap();
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.5; 128]);
+
+        graph.set_send_level(bus, source, 0, 1.0).unwrap();
+        graph.set_return_level(bus, 0, 0.5).unwrap();
+        graph.process_next();
+        let output = graph.get_output(0);
+        for (k, &sample) in output.iter().enumerate() {
+            let position = (k + 1) as MusicalValue / 128.0;
+            let expected = (0.5 + 0.5 * position) * (1.0 - 0.5 * position);
+            assert!((sample - expected).abs() < 1e-6);
+        }
+
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.5; 128]);
+    }
+
+    #[test]
+    fn removing_a_bus_removes_its_routes() {
+        let mut source = container(Constant::<MidiNote>::new(1.0));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let source = graph.add_instrument(&mut source);
+        let mixer = graph.add_instrument(&mut mixer);
+
+        let bus = graph.add_bus("fx");
+        graph.send_to_bus(bus, source, 0, 1.0, SendPosition::Post).unwrap();
+        graph.connect_bus(bus, mixer, 0).unwrap();
+        graph.connect_bus_return(bus, mixer, 0, 0, 1.0).unwrap();
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 1.0);
+
+        graph.remove_bus(bus).unwrap();
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 0.0);
+        assert_eq!(graph.find_bus("fx"), None);
+        assert_eq!(graph.send_level(bus, source, 0), None);
+        assert_eq!(graph.set_send_level(bus, source, 0, 0.5), Err(BusError::NoBus));
+        assert_eq!(graph.remove_bus(MAX_BUSES), Err(BusError::IndexOutOfBounds));
+        assert_eq!(graph.add_bus("reverb"), bus);
+    }
+}
@@ -0,0 +1,214 @@
+//! Checking connections against stream descriptions.
+//!
+//! Connections are made by index and are not checked as they are made, since instruments can be inserted
+//! after their connections. `InstrumentGraph::check_connections` checks them all at once.
+
+use crate::stream::{StreamKind, StreamRole};
+
+use super::InstrumentGraph;
+
+/// A problem with a connection of a graph
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConnectionIssue {
+    /// A connection uses a stream the instrument does not have
+    StreamOutOfBounds {
+        instrument_index: usize,
+        kind: StreamKind,
+        stream_index: usize,
+    },
+
+    /// A value stream feeds an input expecting a different role, such as audio into a frequency input.
+    ///
+    /// This can be intended, for example for amplitude modulation.
+    RoleMismatch {
+        source_index: usize,
+        source_stream_index: usize,
+        destination_index: usize,
+        destination_stream_index: usize,
+        source_role: StreamRole,
+        destination_role: StreamRole,
+    },
+
+    /// An instrument depends on itself through value streams or buses, it and the instruments depending on it
+    /// are never processed
+    Cycle {
+        instrument_index: usize,
+    },
+
+    /// Latency compensation would delay a path by more than `MAX_LATENCY_COMPENSATION` minus the block size, the
+    /// delay is cut short. The instrument is the source of a path to the outputs, or the one where paths merge
+    CompensationOutOfRange {
+        instrument_index: usize,
+        delay: usize,
+    },
+
+    /// A faster path merges into an instrument input and no merge delay line is left to align it
+    UnalignedMerge {
+        destination_index: usize,
+        destination_stream_index: usize,
+    },
+}
+
+impl core::fmt::Display for ConnectionIssue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConnectionIssue::StreamOutOfBounds { instrument_index, kind, stream_index } => {
+                write!(f, "instrument {} has no {:?} stream {}", instrument_index, kind, stream_index)
+            },
+            ConnectionIssue::RoleMismatch { source_index, source_stream_index, destination_index, destination_stream_index, source_role, destination_role } => {
+                write!(f, "{:?} stream {} of instrument {} feeds {:?} stream {} of instrument {}", source_role, source_stream_index, source_index, destination_role, destination_stream_index, destination_index)
+            },
+            ConnectionIssue::Cycle { instrument_index } => {
+                write!(f, "instrument {} depends on itself", instrument_index)
+            },
+            ConnectionIssue::CompensationOutOfRange { instrument_index, delay } => {
+                write!(f, "a path at instrument {} needs a delay of {} samples, more than latency compensation allows", instrument_index, delay)
+            },
+            ConnectionIssue::UnalignedMerge { destination_index, destination_stream_index } => {
+                write!(f, "no merge delay line is left to align value stream {} of instrument {}", destination_stream_index, destination_index)
+            },
+        }
+    }
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Checks every connection to and from an occupied slot, returning the first issue found.
+    ///
+    /// Streams must exist, and connected value streams must have fitting roles, see `StreamRole::fits`.
+    /// Streams without a description fit every role. No instrument may depend on itself. With latency
+    /// compensation, every path must be delayed within range and every merge must have a delay line.
+    pub fn check_connections(&self) -> Result<(), ConnectionIssue> {
+        for (destination_index, connections) in self.value_stream_connections.iter().enumerate() {
+            for connection in connections.iter().flatten() {
+                let source_role = self.stream_role(connection.source_index, StreamKind::ValueOutput, connection.source_stream_index)?;
+                let destination_role = self.stream_role(destination_index, StreamKind::ValueInput, connection.destination_stream_index)?;
+                if let (Some(source_role), Some(destination_role)) = (source_role, destination_role) {
+                    if !source_role.fits(destination_role) {
+                        return Err(ConnectionIssue::RoleMismatch {
+                            source_index: connection.source_index,
+                            source_stream_index: connection.source_stream_index,
+                            destination_index,
+                            destination_stream_index: connection.destination_stream_index,
+                            source_role,
+                            destination_role,
+                        });
+                    }
+                }
+            }
+        }
+
+        for (destination_index, connections) in self.input_connections.iter().enumerate() {
+            for connection in connections.iter().flatten() {
+                self.stream_role(destination_index, StreamKind::ValueInput, connection.destination_stream_index)?;
+            }
+        }
+
+        for (instrument_index, connection) in self.instruments_control_sources.iter().enumerate() {
+            if connection.is_some() {
+                self.stream_role(instrument_index, StreamKind::ControlInput, 0)?;
+            }
+        }
+
+        for connection in self.destination_connections.iter().flatten().flatten() {
+            self.stream_role(connection.source_index, StreamKind::ValueOutput, connection.source_stream_index)?;
+        }
+
+        for bus in self.buses.iter().flatten() {
+            for send in bus.sends.iter().flatten() {
+                self.stream_role(send.source_index, StreamKind::ValueOutput, send.source_stream_index)?;
+            }
+            for connection in bus.connections.iter().flatten() {
+                self.stream_role(connection.destination_index, StreamKind::ValueInput, connection.destination_stream_index)?;
+            }
+            for bus_return in bus.returns.iter().flatten() {
+                self.stream_role(bus_return.source_index, StreamKind::ValueOutput, bus_return.source_stream_index)?;
+            }
+        }
+
+        for instrument_index in 0..SIZE {
+            if self.depends_on(instrument_index, instrument_index) {
+                return Err(ConnectionIssue::Cycle { instrument_index });
+            }
+        }
+
+        self.check_latencies()
+    }
+
+    /// The role of a stream of an instrument, `None` if the slot is empty or the stream is not described
+    fn stream_role(&self, instrument_index: usize, kind: StreamKind, stream_index: usize) -> Result<Option<StreamRole>, ConnectionIssue> {
+        let Some(instrument) = &self.instruments[instrument_index] else {
+            return Ok(None);
+        };
+
+        let count = match kind {
+            StreamKind::ValueInput => instrument.in_value_streams(),
+            StreamKind::ControlInput => instrument.in_control_streams(),
+            StreamKind::ValueOutput => instrument.out_value_streams(),
+        };
+        if stream_index >= count {
+            return Err(ConnectionIssue::StreamOutOfBounds { instrument_index, kind, stream_index });
+        }
+
+        Ok(instrument.stream_info(kind, stream_index).map(|info| info.role))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::oscillators::SineOscillator;
+    use crate::instrument::{Amplifier, Mixer};
+    use crate::MidiNote;
+
+    #[test]
+    fn reports_streams_out_of_bounds() {
+        let mut sine = container(SineOscillator::<MidiNote>::new(48000));
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<2, 1, 2, 1> = InstrumentGraph::new();
+        let sine = graph.add_instrument(&mut sine);
+        let amplifier = graph.add_instrument(&mut amplifier);
+        graph.connect_value_stream(sine, 0, amplifier, 0);
+        graph.connect_destination(0, amplifier, 0);
+        assert_eq!(graph.check_connections(), Ok(()));
+
+        graph.connect_destination(0, sine, 1);
+        assert_eq!(graph.check_connections(), Err(ConnectionIssue::StreamOutOfBounds { instrument_index: sine, kind: StreamKind::ValueOutput, stream_index: 1 }));
+    }
+
+    #[test]
+    fn reports_role_mismatches() {
+        let mut modulator = container(SineOscillator::<MidiNote>::new(48000));
+        let mut carrier = container(SineOscillator::<MidiNote>::new(48000));
+        let mut graph: InstrumentGraph<2, 1, 2, 1> = InstrumentGraph::new();
+        let modulator = graph.add_instrument(&mut modulator);
+        let carrier = graph.add_instrument(&mut carrier);
+        graph.connect_value_stream(modulator, 0, carrier, 0);
+
+        assert_eq!(
+            graph.check_connections(),
+            Err(ConnectionIssue::RoleMismatch {
+                source_index: modulator,
+                source_stream_index: 0,
+                destination_index: carrier,
+                destination_stream_index: 0,
+                source_role: StreamRole::Audio,
+                destination_role: StreamRole::Frequency,
+            })
+        );
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let mut first = container(Mixer::<2, MidiNote>::new());
+        let mut second = container(Mixer::<1, MidiNote>::new());
+        let mut graph: InstrumentGraph<2, 1, 2, 1> = InstrumentGraph::new();
+        let first = graph.add_instrument(&mut first);
+        let second = graph.add_instrument(&mut second);
+        graph.connect_value_stream(first, 0, second, 0);
+        assert_eq!(graph.check_connections(), Ok(()));
+
+        graph.connect_value_stream(second, 0, first, 1);
+        assert_eq!(graph.check_connections(), Err(ConnectionIssue::Cycle { instrument_index: first }));
+    }
+}
@@ -0,0 +1,242 @@
+//! Graphviz DOT export of instrument graphs.
+//!
+//! Instruments are rendered as boxes labelled with their index, stream counts and position in the process order,
+//! control sources as diamonds, buses as hexagons, input channels as circles and output channels as double circles.
+//! Value stream connections are labelled `source stream -> destination stream`, with the stream names if they are described,
+//! control routes are dashed and bus sends and returns are labelled with their level. Quotes and backslashes in
+//! stream and bus names are escaped.
+
+use core::fmt::{Display, Formatter, Result, Write};
+
+use crate::stream::StreamKind;
+use crate::InstrumentContainer;
+
+use super::bus::SendPosition;
+use super::{ControlStreamConnection, DestinationConnection, InstrumentGraph, ValueStreamConnection};
+
+/// A name written inside a quoted DOT label
+struct Escaped<'s>(&'s str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for c in self.0.chars() {
+            match c {
+                '"' | '\\' => write!(f, "\\{}", c)?,
+                '\n' => f.write_str("\\n")?,
+                _ => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_header<W: Write>(w: &mut W, order: &[usize]) -> Result {
+    writeln!(w, "digraph instruments {{")?;
+    writeln!(w, "    rankdir=LR;")?;
+    write!(w, "    label=\"process order:")?;
+    for index in order {
+        write!(w, " {}", index)?;
+    }
+    writeln!(w, "\";")
+}
+
+pub(crate) fn write_instrument<W: Write, Note: Sized + Default + Copy, const BLOCK_SIZE: usize>(w: &mut W, index: usize, instrument: &dyn InstrumentContainer<Note, BLOCK_SIZE>, order: &[usize]) -> Result {
+    write!(w, "    i{} [shape=box, label=\"instrument {}\\nin: {}, control: {}, out: {}", index, index, instrument.in_value_streams(), instrument.in_control_streams(), instrument.out_value_streams())?;
+    if let Some(position) = order.iter().position(|&i| i == index) {
+        write!(w, "\\norder: {}", position)?;
+    }
+    writeln!(w, "\"];")
+}
+
+pub(crate) fn write_control_source<W: Write>(w: &mut W, index: usize) -> Result {
+    writeln!(w, "    c{} [shape=diamond, label=\"control {}\"];", index, index)
+}
+
+pub(crate) fn write_output_channel<W: Write>(w: &mut W, index: usize) -> Result {
+    writeln!(w, "    o{} [shape=doublecircle, label=\"output {}\"];", index, index)
+}
+
+/// Writes the index of a stream, followed by its name if it is described
+fn write_stream<W: Write, Note: Sized + Default + Copy, const BLOCK_SIZE: usize>(w: &mut W, instrument: Option<&dyn InstrumentContainer<Note, BLOCK_SIZE>>, kind: StreamKind, index: usize) -> Result {
+    write!(w, "{}", index)?;
+    if let Some(info) = instrument.and_then(|instrument| instrument.stream_info(kind, index)) {
+        write!(w, " {}", Escaped(info.name))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_value_stream_connection<W: Write, Note: Sized + Default + Copy, const BLOCK_SIZE: usize>(w: &mut W, destination_index: usize, connection: &ValueStreamConnection, source: Option<&dyn InstrumentContainer<Note, BLOCK_SIZE>>, destination: Option<&dyn InstrumentContainer<Note, BLOCK_SIZE>>) -> Result {
+    write!(w, "    i{} -> i{} [label=\"", connection.source_index, destination_index)?;
+    write_stream(w, source, StreamKind::ValueOutput, connection.source_stream_index)?;
+    write!(w, " -> ")?;
+    write_stream(w, destination, StreamKind::ValueInput, connection.destination_stream_index)?;
+    writeln!(w, "\"];")
+}
+
+pub(crate) fn write_control_connection<W: Write>(w: &mut W, instrument_index: usize, connection: &ControlStreamConnection) -> Result {
+    writeln!(w, "    c{} -> i{} [style=dashed];", connection.source_index, instrument_index)
+}
+
+pub(crate) fn write_destination_connection<W: Write, Note: Sized + Default + Copy, const BLOCK_SIZE: usize>(w: &mut W, output_channel_index: usize, connection: &DestinationConnection, source: Option<&dyn InstrumentContainer<Note, BLOCK_SIZE>>) -> Result {
+    write!(w, "    i{} -> o{} [label=\"", connection.source_index, output_channel_index)?;
+    write_stream(w, source, StreamKind::ValueOutput, connection.source_stream_index)?;
+    writeln!(w, "\"];")
+}
+
+pub(crate) fn write_footer<W: Write>(w: &mut W) -> Result {
+    writeln!(w, "}}")
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Writes the graph as a Graphviz DOT document, including the computed process order
+    pub fn write_dot<W: Write>(&self, w: &mut W) -> Result {
+        let mut order = [usize::MAX; SIZE];
+        let mut order_len = 0;
+        if self.process_order_dirty {
+            for index in self.get_instrument_process_order() {
+                if index == usize::MAX {
+                    break;
+                }
+                if self.instruments[index].is_some() {
+                    order[order_len] = index;
+                    order_len += 1;
+                }
+            }
+        } else {
+            order_len = self.process_order_len;
+            order[..order_len].copy_from_slice(&self.process_order[..order_len]);
+        }
+        let order = &order[..order_len];
+
+        write_header(w, order)?;
+
+        for (index, instrument) in self.instruments.iter().enumerate() {
+            if let Some(instrument) = instrument {
+                write_instrument::<W, Note, BLOCK_SIZE>(w, index, &**instrument, order)?;
+            }
+        }
+
+        for (index, control_source) in self.control_sources.iter().enumerate() {
+            if control_source.is_some() {
+                write_control_source(w, index)?;
+            }
+        }
+
+        for (index, bus) in self.buses.iter().enumerate() {
+            if let Some(bus) = bus {
+                writeln!(w, "    b{} [shape=hexagon, label=\"bus {}\\n{}\"];", index, index, Escaped(bus.name))?;
+            }
+        }
+
+        for index in 0..INPUT_CHANNELS {
+            writeln!(w, "    n{} [shape=circle, label=\"input {}\"];", index, index)?;
+        }
+
+        for index in 0..OUTPUT_CHANNELS {
+            write_output_channel(w, index)?;
+        }
+
+        for (destination_index, connections) in self.value_stream_connections.iter().enumerate() {
+            for connection in connections.iter().flatten() {
+                write_value_stream_connection(w, destination_index, connection, self.instruments[connection.source_index].as_deref(), self.instruments[destination_index].as_deref())?;
+            }
+        }
+
+        for (destination_index, connections) in self.input_connections.iter().enumerate() {
+            for connection in connections.iter().flatten() {
+                writeln!(w, "    n{} -> i{} [label=\"{}\"];", connection.input_channel_index, destination_index, connection.destination_stream_index)?;
+            }
+        }
+
+        for (instrument_index, connection) in self.instruments_control_sources.iter().enumerate() {
+            if let Some(connection) = connection {
+                write_control_connection(w, instrument_index, connection)?;
+            }
+        }
+
+        for (output_channel_index, connections) in self.destination_connections.iter().enumerate() {
+            for connection in connections.iter().flatten() {
+                write_destination_connection(w, output_channel_index, connection, self.instruments[connection.source_index].as_deref())?;
+            }
+        }
+
+        for (bus_index, bus) in self.buses.iter().enumerate() {
+            if let Some(bus) = bus {
+                for send in bus.sends.iter().flatten() {
+                    let position = if send.position == SendPosition::Pre { "pre" } else { "post" };
+                    writeln!(w, "    i{} -> b{} [label=\"{}: {} {}\"];", send.source_index, bus_index, send.source_stream_index, send.level, position)?;
+                }
+                for connection in bus.connections.iter().flatten() {
+                    writeln!(w, "    b{} -> i{} [label=\"{}\"];", bus_index, connection.destination_index, connection.destination_stream_index)?;
+                }
+                for bus_return in bus.returns.iter().flatten() {
+                    writeln!(w, "    i{} -> o{} [label=\"{}: b{} {}\"];", bus_return.source_index, bus_return.output_channel_index, bus_return.source_stream_index, bus_index, bus_return.level)?;
+                }
+            }
+        }
+
+        write_footer(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::oscillators::SineOscillator;
+    use crate::instrument::Amplifier;
+    use crate::{ControlStreamSource, MidiNote, NoteCommand};
+    use alloc::string::String;
+
+    struct Silence;
+
+    impl ControlStreamSource<MidiNote> for Silence {
+        fn get_control_stream(&self) -> &[NoteCommand<MidiNote>] {
+            &[]
+        }
+
+        fn fetch_next_stream(&mut self) {}
+    }
+
+    #[test]
+    fn escapes_names() {
+        let mut escaped = String::new();
+        write!(escaped, "{}", Escaped("a \"b\" \\ c")).unwrap();
+        assert_eq!(escaped, "a \\\"b\\\" \\\\ c");
+    }
+
+    #[test]
+    fn writes_dot() {
+        let mut silence = Silence;
+        let mut sine = container(SineOscillator::<MidiNote>::new(48000));
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let control = graph.add_control_source(&mut silence);
+        let amplifier = graph.add_instrument(&mut amplifier);
+        let sine = graph.add_instrument(&mut sine);
+        graph.connect_control_source(control, sine);
+        graph.connect_value_stream(sine, 0, amplifier, 0);
+        graph.connect_destination(0, amplifier, 0);
+        graph.add_bus("say \"hi\"");
+
+        let mut dot = String::new();
+        graph.write_dot(&mut dot).unwrap();
+        assert_eq!(
+            dot,
+            concat!(
+                "digraph instruments {\n",
+                "    rankdir=LR;\n",
+                "    label=\"process order: 1 0\";\n",
+                "    i0 [shape=box, label=\"instrument 0\\nin: 2, control: 0, out: 1\\norder: 1\"];\n",
+                "    i1 [shape=box, label=\"instrument 1\\nin: 2, control: 0, out: 1\\norder: 0\"];\n",
+                "    c0 [shape=diamond, label=\"control 0\"];\n",
+                "    b0 [shape=hexagon, label=\"bus 0\\nsay \\\"hi\\\"\"];\n",
+                "    o0 [shape=doublecircle, label=\"output 0\"];\n",
+                "    i1 -> i0 [label=\"0 output -> 0 input\"];\n",
+                "    c0 -> i1 [style=dashed];\n",
+                "    i0 -> o0 [label=\"0 output\"];\n",
+                "}\n",
+            )
+        );
+    }
+}
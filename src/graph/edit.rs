@@ -0,0 +1,652 @@
+//! Topology edits sent from a control thread.
+//!
+//! The control thread pushes `GraphEdit`s into a `Queue`, and the audio thread applies them with
+//! `InstrumentGraph::apply_edits` between blocks. Applying edits does not lock or allocate,
+//! and removed instruments are sent back so that the control thread can drop or reuse them.
+//!
+//! Every edit is checked before it is applied, an edit that is out of bounds, does not fit or would make an
+//! instrument depend on itself is rejected and leaves the graph unchanged. `InstrumentGraph::check_edit` lets
+//! the control thread check an edit before sending it.
+//!
+//! With `InstrumentGraph::set_edit_crossfade`, the output channels affected by a batch of edits fade out with
+//! the old topology and back in with the new one.
+
+use core::ops::Deref;
+
+use crate::queue::{Consumer, Producer, Queue};
+use crate::stream::StreamKind;
+use crate::{InstrumentContainer, MidiNote, MusicalValue, STANDARD_BLOCK_SIZE};
+
+use super::bus::{BusError, MAX_BUSES};
+use super::{InstrumentGraph, NodeMode};
+
+/// A change to the topology of an `InstrumentGraph`
+pub enum GraphEdit<'a, Note: Sized + Default + Copy + Send = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> {
+    /// Puts an instrument into the given slot, see `InstrumentGraph::insert_instrument`
+    InsertInstrument {
+        index: usize,
+        instrument: &'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>,
+    },
+
+    /// Removes an instrument and its connections, see `InstrumentGraph::remove_instrument`
+    RemoveInstrument {
+        index: usize,
+    },
+
+    ConnectControlSource {
+        control_source_index: usize,
+        instrument_index: usize,
+    },
+
+    DisconnectControlSource {
+        instrument_index: usize,
+    },
+
+    ConnectValueStream {
+        source_index: usize,
+        source_stream_index: usize,
+        destination_index: usize,
+        destination_stream_index: usize,
+    },
+
+    DisconnectValueStream {
+        source_index: usize,
+        source_stream_index: usize,
+        destination_index: usize,
+        destination_stream_index: usize,
+    },
+
+    ConnectDestination {
+        output_channel_index: usize,
+        source_index: usize,
+        source_stream_index: usize,
+    },
+
+    DisconnectDestination {
+        output_channel_index: usize,
+        source_index: usize,
+        source_stream_index: usize,
+    },
+
+    ConnectInput {
+        input_channel_index: usize,
+        destination_index: usize,
+        destination_stream_index: usize,
+    },
+
+    DisconnectInput {
+        input_channel_index: usize,
+        destination_index: usize,
+        destination_stream_index: usize,
+    },
+
+    /// Bypasses, mutes or reactivates an instrument, see `InstrumentGraph::set_node_mode`
+    SetNodeMode {
+        index: usize,
+        mode: NodeMode,
+    },
+
+    /// Solos or unsolos a destination connection, see `InstrumentGraph::set_destination_solo`
+    SetDestinationSolo {
+        output_channel_index: usize,
+        source_index: usize,
+        source_stream_index: usize,
+        solo: bool,
+    },
+
+    /// Changes the level of a bus send, see `InstrumentGraph::set_send_level`
+    SetSendLevel {
+        bus_index: usize,
+        source_index: usize,
+        source_stream_index: usize,
+        level: MusicalValue,
+    },
+
+    /// Changes the level of a bus return, see `InstrumentGraph::set_return_level`
+    SetReturnLevel {
+        bus_index: usize,
+        output_channel_index: usize,
+        level: MusicalValue,
+    },
+}
+
+/// The reason an edit was rejected
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EditError {
+    /// An instrument, control source, input channel, output channel or bus index is out of bounds
+    IndexOutOfBounds,
+
+    /// A stream index is out of bounds for the instrument, or the inserted instrument does not have the
+    /// streams used by the connections of its slot
+    StreamOutOfBounds,
+
+    /// There is no more space for the connection
+    NoSpace,
+
+    /// The connection would make an instrument depend on itself
+    Cycle,
+
+    /// There is no bus at the given index
+    NoBus,
+
+    /// The bus has no send from the given stream, or no return to the given output channel
+    NoRoute,
+}
+
+impl core::fmt::Display for EditError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EditError::IndexOutOfBounds => write!(f, "index out of bounds"),
+            EditError::StreamOutOfBounds => write!(f, "stream index out of bounds"),
+            EditError::NoSpace => write!(f, "no more space for the connection"),
+            EditError::Cycle => write!(f, "the connection would create a cycle"),
+            EditError::NoBus => write!(f, "no bus at the given index"),
+            EditError::NoRoute => write!(f, "no such bus send or return"),
+        }
+    }
+}
+
+impl From<BusError> for EditError {
+    fn from(error: BusError) -> Self {
+        match error {
+            BusError::IndexOutOfBounds => EditError::IndexOutOfBounds,
+            BusError::NoBus => EditError::NoBus,
+            BusError::StreamOutOfBounds => EditError::StreamOutOfBounds,
+            BusError::NoSpace => EditError::NoSpace,
+            BusError::Cycle => EditError::Cycle,
+            BusError::NoRoute => EditError::NoRoute,
+        }
+    }
+}
+
+/// An edit that was not applied, given back with the reason
+pub struct RejectedEdit<'a, Note: Sized + Default + Copy + Send = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> {
+    pub error: EditError,
+    pub edit: GraphEdit<'a, Note, BLOCK_SIZE>,
+}
+
+/// The outcome of `InstrumentGraph::apply_edits`
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AppliedEdits {
+    pub applied: usize,
+    pub rejected: usize,
+
+    /// The displaced or rejected instruments that could not be pushed to the full `removed` queue. They stay
+    /// borrowed for `'a` without being processed
+    pub lost: usize,
+}
+
+/// The progress of an edit crossfade, see `InstrumentGraph::set_edit_crossfade`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum EditFade<const CHANNELS: usize> {
+    Idle,
+
+    /// The channels fade out in the next block, after which the first `edits` pending edits are applied
+    FadingOut { channels: [bool; CHANNELS], edits: usize },
+
+    /// The channels are silent until the next `apply_edits` applies the held edits
+    FadedOut { channels: [bool; CHANNELS], edits: usize },
+
+    /// The channels fade in with the new topology in the next block
+    FadingIn { channels: [bool; CHANNELS] },
+}
+
+/// Fails with `error` unless `condition` holds
+fn ensure(condition: bool, error: EditError) -> Result<(), EditError> {
+    if condition { Ok(()) } else { Err(error) }
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Enables or disables crossfading the output channels affected by the edits of `apply_edits`.
+    ///
+    /// When enabled, `apply_edits` holds a batch of edits for one block, in which the output channels the edits
+    /// affect fade out with the old topology. The next call applies the batch, and the channels fade back in
+    /// with the new topology over the following block. Channels the edits do not affect are left as they are.
+    ///
+    /// The fade passes through silence rather than overlapping the old and new outputs, since both topologies
+    /// share their instruments, which cannot be processed twice in a block. `apply_edit` applies edits
+    /// immediately, without a crossfade.
+    pub fn set_edit_crossfade(&mut self, enabled: bool) {
+        self.edit_crossfade = enabled;
+    }
+
+    /// Marks the output channels an edit affects, on the current topology: those fed by the instruments at or
+    /// after the edited slot, the edited channel, or every channel for solos, which silence the other connections
+    fn mark_edited_channels(&self, edit: &GraphEdit<'a, Note, BLOCK_SIZE>, channels: &mut [bool; OUTPUT_CHANNELS]) {
+        if self.check_edit(edit).is_err() {
+            return;
+        }
+
+        match edit {
+            GraphEdit::InsertInstrument { index, .. }
+            | GraphEdit::RemoveInstrument { index }
+            | GraphEdit::SetNodeMode { index, .. }
+            | GraphEdit::ConnectControlSource { instrument_index: index, .. }
+            | GraphEdit::DisconnectControlSource { instrument_index: index }
+            | GraphEdit::ConnectValueStream { destination_index: index, .. }
+            | GraphEdit::DisconnectValueStream { destination_index: index, .. }
+            | GraphEdit::ConnectInput { destination_index: index, .. }
+            | GraphEdit::DisconnectInput { destination_index: index, .. } => self.mark_channels_fed_by(*index, channels),
+            GraphEdit::ConnectDestination { output_channel_index, .. }
+            | GraphEdit::DisconnectDestination { output_channel_index, .. }
+            | GraphEdit::SetReturnLevel { output_channel_index, .. } => channels[*output_channel_index] = true,
+            GraphEdit::SetDestinationSolo { .. } => *channels = [true; OUTPUT_CHANNELS],
+            GraphEdit::SetSendLevel { bus_index, .. } => {
+                let Some(bus) = &self.buses[*bus_index] else {
+                    return;
+                };
+                for bus_return in bus.returns.iter().flatten() {
+                    channels[bus_return.output_channel_index] = true;
+                }
+                for connection in bus.connections.iter().flatten() {
+                    self.mark_channels_fed_by(connection.destination_index, channels);
+                }
+            },
+        }
+    }
+
+    /// Marks the output channels fed by the instrument at the given index, directly or through other instruments
+    fn mark_channels_fed_by(&self, index: usize, channels: &mut [bool; OUTPUT_CHANNELS]) {
+        let fed = |source_index: usize| source_index == index || self.depends_on(source_index, index);
+        for (channel, connections) in channels.iter_mut().zip(&self.destination_connections) {
+            if connections.iter().flatten().any(|connection| fed(connection.source_index)) {
+                *channel = true;
+            }
+        }
+        for bus_return in self.buses.iter().flatten().flat_map(|bus| bus.returns.iter().flatten()) {
+            if fed(bus_return.source_index) {
+                channels[bus_return.output_channel_index] = true;
+            }
+        }
+    }
+
+    /// Fades the output channels of an edit crossfade, at the end of a block
+    pub(crate) fn fade_edited_channels(&mut self) {
+        let (channels, fade_in) = match self.edit_fade {
+            EditFade::Idle => return,
+            EditFade::FadingOut { channels, edits } => {
+                self.edit_fade = EditFade::FadedOut { channels, edits };
+                (channels, false)
+            },
+            EditFade::FadedOut { channels, .. } => {
+                for (output, _) in self.output_channels.iter_mut().zip(channels).filter(|(_, faded)| *faded) {
+                    *output = [0.0; BLOCK_SIZE];
+                }
+                return;
+            },
+            EditFade::FadingIn { channels } => {
+                self.edit_fade = EditFade::Idle;
+                (channels, true)
+            },
+        };
+
+        for (output, _) in self.output_channels.iter_mut().zip(channels).filter(|(_, faded)| *faded) {
+            for (k, sample) in output.iter_mut().enumerate() {
+                let gain = (k + 1) as MusicalValue / BLOCK_SIZE as MusicalValue;
+                *sample *= if fade_in { gain } else { 1.0 - gain };
+            }
+        }
+    }
+
+    /// Checks whether an edit can be applied to the graph in its current state, without applying it
+    pub fn check_edit(&self, edit: &GraphEdit<'a, Note, BLOCK_SIZE>) -> Result<(), EditError> {
+        match edit {
+            GraphEdit::InsertInstrument { index, instrument } => {
+                ensure(*index < SIZE, EditError::IndexOutOfBounds)?;
+                self.check_slot_streams(*index, &**instrument)
+            },
+            GraphEdit::RemoveInstrument { index } | GraphEdit::DisconnectControlSource { instrument_index: index } => {
+                ensure(*index < SIZE, EditError::IndexOutOfBounds)
+            },
+            GraphEdit::ConnectControlSource { control_source_index, instrument_index } => {
+                ensure(*control_source_index < CONTROL_SIZE && *instrument_index < SIZE, EditError::IndexOutOfBounds)
+            },
+            GraphEdit::ConnectValueStream { source_index, source_stream_index, destination_index, destination_stream_index } => {
+                ensure(*source_index < SIZE && *destination_index < SIZE, EditError::IndexOutOfBounds)?;
+                ensure(self.stream_fits(*source_index, StreamKind::ValueOutput, *source_stream_index), EditError::StreamOutOfBounds)?;
+                ensure(self.stream_fits(*destination_index, StreamKind::ValueInput, *destination_stream_index), EditError::StreamOutOfBounds)?;
+                ensure(self.value_stream_connections[*destination_index].iter().any(|connection| connection.is_none()), EditError::NoSpace)?;
+                ensure(source_index != destination_index && !self.depends_on(*source_index, *destination_index), EditError::Cycle)
+            },
+            GraphEdit::DisconnectValueStream { destination_index, .. } => {
+                ensure(*destination_index < SIZE, EditError::IndexOutOfBounds)
+            },
+            GraphEdit::ConnectDestination { output_channel_index, source_index, source_stream_index } => {
+                ensure(*output_channel_index < OUTPUT_CHANNELS && *source_index < SIZE, EditError::IndexOutOfBounds)?;
+                ensure(self.stream_fits(*source_index, StreamKind::ValueOutput, *source_stream_index), EditError::StreamOutOfBounds)?;
+                ensure(self.destination_connections[*output_channel_index].iter().any(|connection| connection.is_none()), EditError::NoSpace)
+            },
+            GraphEdit::DisconnectDestination { output_channel_index, .. } | GraphEdit::SetDestinationSolo { output_channel_index, .. } => {
+                ensure(*output_channel_index < OUTPUT_CHANNELS, EditError::IndexOutOfBounds)
+            },
+            GraphEdit::ConnectInput { input_channel_index, destination_index, destination_stream_index } => {
+                ensure(*input_channel_index < INPUT_CHANNELS && *destination_index < SIZE, EditError::IndexOutOfBounds)?;
+                ensure(self.stream_fits(*destination_index, StreamKind::ValueInput, *destination_stream_index), EditError::StreamOutOfBounds)?;
+                ensure(self.input_connections[*destination_index].iter().any(|connection| connection.is_none()), EditError::NoSpace)
+            },
+            GraphEdit::DisconnectInput { destination_index, .. } => {
+                ensure(*destination_index < SIZE, EditError::IndexOutOfBounds)
+            },
+            GraphEdit::SetNodeMode { index, mode } => {
+                ensure(*index < SIZE, EditError::IndexOutOfBounds)?;
+                match mode {
+                    NodeMode::Bypassed { input_stream_index, output_stream_index } => ensure(
+                        self.stream_fits(*index, StreamKind::ValueInput, *input_stream_index) && self.stream_fits(*index, StreamKind::ValueOutput, *output_stream_index),
+                        EditError::StreamOutOfBounds,
+                    ),
+                    _ => Ok(()),
+                }
+            },
+            GraphEdit::SetSendLevel { bus_index, source_index, source_stream_index, .. } => {
+                ensure(*bus_index < MAX_BUSES, EditError::IndexOutOfBounds)?;
+                let bus = self.buses[*bus_index].as_ref().ok_or(EditError::NoBus)?;
+                ensure(
+                    bus.sends.iter().flatten().any(|send| send.source_index == *source_index && send.source_stream_index == *source_stream_index),
+                    EditError::NoRoute,
+                )
+            },
+            GraphEdit::SetReturnLevel { bus_index, output_channel_index, .. } => {
+                ensure(*bus_index < MAX_BUSES, EditError::IndexOutOfBounds)?;
+                let bus = self.buses[*bus_index].as_ref().ok_or(EditError::NoBus)?;
+                ensure(bus.returns.iter().flatten().any(|bus_return| bus_return.output_channel_index == *output_channel_index), EditError::NoRoute)
+            },
+        }
+    }
+
+    /// Whether the instrument in a slot has the given stream, an empty slot has every stream
+    fn stream_fits(&self, index: usize, kind: StreamKind, stream_index: usize) -> bool {
+        let Some(instrument) = &self.instruments[index] else {
+            return true;
+        };
+
+        stream_index < match kind {
+            StreamKind::ValueInput => instrument.in_value_streams(),
+            StreamKind::ControlInput => instrument.in_control_streams(),
+            StreamKind::ValueOutput => instrument.out_value_streams(),
+        }
+    }
+
+    /// Checks that an instrument has the streams used by the connections to and from a slot
+    fn check_slot_streams(&self, index: usize, instrument: &dyn InstrumentContainer<Note, BLOCK_SIZE>) -> Result<(), EditError> {
+        let inputs = instrument.in_value_streams();
+        let outputs = instrument.out_value_streams();
+
+        let inputs_fit = self.value_stream_connections[index].iter().flatten().all(|connection| connection.destination_stream_index < inputs)
+            && self.input_connections[index].iter().flatten().all(|connection| connection.destination_stream_index < inputs)
+            && self.buses.iter().flatten()
+                .flat_map(|bus| bus.connections.iter().flatten())
+                .all(|connection| connection.destination_index != index || connection.destination_stream_index < inputs);
+
+        let outputs_fit = self.value_stream_connections.iter().flatten().flatten()
+                .all(|connection| connection.source_index != index || connection.source_stream_index < outputs)
+            && self.destination_connections.iter().flatten().flatten()
+                .all(|connection| connection.source_index != index || connection.source_stream_index < outputs)
+            && self.buses.iter().flatten()
+                .flat_map(|bus| bus.sends.iter().flatten())
+                .all(|send| send.source_index != index || send.source_stream_index < outputs)
+            && self.buses.iter().flatten()
+                .flat_map(|bus| bus.returns.iter().flatten())
+                .all(|bus_return| bus_return.source_index != index || bus_return.source_stream_index < outputs);
+
+        ensure(inputs_fit && outputs_fit, EditError::StreamOutOfBounds)
+    }
+
+    /// Applies a single edit, returning the instrument it displaced, if any.
+    ///
+    /// An edit failing `check_edit` is given back unapplied.
+    pub fn apply_edit(&mut self, edit: GraphEdit<'a, Note, BLOCK_SIZE>) -> Result<Option<&'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>>, RejectedEdit<'a, Note, BLOCK_SIZE>> {
+        if let Err(error) = self.check_edit(&edit) {
+            return Err(RejectedEdit { error, edit });
+        }
+
+        match edit {
+            GraphEdit::InsertInstrument { index, instrument } => {
+                return Ok(self.insert_instrument(index, instrument));
+            },
+            GraphEdit::RemoveInstrument { index } => {
+                return Ok(self.remove_instrument(index));
+            },
+            GraphEdit::ConnectControlSource { control_source_index, instrument_index } => {
+                self.connect_control_source(control_source_index, instrument_index);
+            },
+            GraphEdit::DisconnectControlSource { instrument_index } => {
+                self.disconnect_control_source(instrument_index);
+            },
+            GraphEdit::ConnectValueStream { source_index, source_stream_index, destination_index, destination_stream_index } => {
+                self.connect_value_stream(source_index, source_stream_index, destination_index, destination_stream_index);
+            },
+            GraphEdit::DisconnectValueStream { source_index, source_stream_index, destination_index, destination_stream_index } => {
+                self.disconnect_value_stream(source_index, source_stream_index, destination_index, destination_stream_index);
+            },
+            GraphEdit::ConnectDestination { output_channel_index, source_index, source_stream_index } => {
+                self.connect_destination(output_channel_index, source_index, source_stream_index);
+            },
+            GraphEdit::DisconnectDestination { output_channel_index, source_index, source_stream_index } => {
+                self.disconnect_destination(output_channel_index, source_index, source_stream_index);
+            },
+            GraphEdit::ConnectInput { input_channel_index, destination_index, destination_stream_index } => {
+                self.connect_input(input_channel_index, destination_index, destination_stream_index);
+            },
+            GraphEdit::DisconnectInput { input_channel_index, destination_index, destination_stream_index } => {
+                self.disconnect_input(input_channel_index, destination_index, destination_stream_index);
+            },
+            GraphEdit::SetNodeMode { index, mode } => {
+                self.set_node_mode(index, mode);
+            },
+            GraphEdit::SetDestinationSolo { output_channel_index, source_index, source_stream_index, solo } => {
+                self.set_destination_solo(output_channel_index, source_index, source_stream_index, solo);
+            },
+            GraphEdit::SetSendLevel { bus_index, source_index, source_stream_index, level } => {
+                if let Err(error) = self.set_send_level(bus_index, source_index, source_stream_index, level) {
+                    return Err(RejectedEdit { error: error.into(), edit: GraphEdit::SetSendLevel { bus_index, source_index, source_stream_index, level } });
+                }
+            },
+            GraphEdit::SetReturnLevel { bus_index, output_channel_index, level } => {
+                if let Err(error) = self.set_return_level(bus_index, output_channel_index, level) {
+                    return Err(RejectedEdit { error: error.into(), edit: GraphEdit::SetReturnLevel { bus_index, output_channel_index, level } });
+                }
+            },
+        }
+        Ok(None)
+    }
+
+    /// Applies the pending edits, to be called on the audio thread before `process_next`.
+    ///
+    /// Displaced instruments, and the instruments of rejected insertions, are pushed to `removed`, and counted as
+    /// lost if it is full. The process order is rebuilt at most once, in the next block. With an edit crossfade,
+    /// the edits are held while the affected channels fade out, see `set_edit_crossfade`.
+    pub fn apply_edits<E, R, const EDITS: usize, const REMOVED: usize>(&mut self, edits: &mut Consumer<E>, removed: &mut Producer<R>) -> AppliedEdits
+    where
+        E: Deref<Target = Queue<GraphEdit<'a, Note, BLOCK_SIZE>, EDITS>>,
+        R: Deref<Target = Queue<&'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>, REMOVED>>,
+    {
+        let mut result = AppliedEdits::default();
+        let count = match self.edit_fade {
+            EditFade::Idle if self.edit_crossfade => {
+                let mut channels = [false; OUTPUT_CHANNELS];
+                let mut count = 0;
+                for edit in edits.peek() {
+                    self.mark_edited_channels(edit, &mut channels);
+                    count += 1;
+                }
+                if channels.contains(&true) {
+                    self.edit_fade = EditFade::FadingOut { channels, edits: count };
+                    return result;
+                }
+                count
+            },
+            EditFade::Idle => usize::MAX,
+            EditFade::FadedOut { channels, edits } => {
+                self.edit_fade = EditFade::FadingIn { channels };
+                edits
+            },
+            EditFade::FadingOut { .. } | EditFade::FadingIn { .. } => return result,
+        };
+
+        for _ in 0..count {
+            let Some(edit) = edits.pop() else {
+                break;
+            };
+            let instrument = match self.apply_edit(edit) {
+                Ok(instrument) => {
+                    result.applied += 1;
+                    instrument
+                },
+                Err(RejectedEdit { edit, .. }) => {
+                    result.rejected += 1;
+                    match edit {
+                        GraphEdit::InsertInstrument { instrument, .. } => Some(instrument),
+                        _ => None,
+                    }
+                },
+            };
+            if let Some(instrument) = instrument {
+                if removed.push(instrument).is_err() {
+                    result.lost += 1;
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::{Amplifier, Constant};
+
+    #[test]
+    fn rejects_invalid_edits() {
+        let mut constant = container(Constant::<MidiNote>::new(1.0));
+        let mut graph: InstrumentGraph<4, 1, 1, 1> = InstrumentGraph::new();
+        let index = graph.add_instrument(&mut constant);
+
+        let out_of_bounds = GraphEdit::ConnectDestination { output_channel_index: 1, source_index: index, source_stream_index: 0 };
+        assert_eq!(graph.check_edit(&out_of_bounds), Err(EditError::IndexOutOfBounds));
+
+        let missing_stream = GraphEdit::ConnectDestination { output_channel_index: 0, source_index: index, source_stream_index: 1 };
+        assert_eq!(graph.check_edit(&missing_stream), Err(EditError::StreamOutOfBounds));
+
+        let bypass = GraphEdit::SetNodeMode { index, mode: NodeMode::Bypassed { input_stream_index: 0, output_stream_index: 0 } };
+        assert_eq!(graph.check_edit(&bypass), Err(EditError::StreamOutOfBounds));
+
+        let no_bus = GraphEdit::SetReturnLevel { bus_index: 0, output_channel_index: 0, level: 1.0 };
+        assert_eq!(graph.apply_edit(no_bus).err().map(|rejected| rejected.error), Some(EditError::NoBus));
+
+        assert!(graph.apply_edit(GraphEdit::ConnectDestination { output_channel_index: 0, source_index: index, source_stream_index: 0 }).is_ok());
+        let full = GraphEdit::ConnectDestination { output_channel_index: 0, source_index: index, source_stream_index: 0 };
+        assert_eq!(graph.check_edit(&full), Err(EditError::NoSpace));
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut first = container(Amplifier::<MidiNote>::new());
+        let mut second = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let first = graph.add_instrument(&mut first);
+        let second = graph.add_instrument(&mut second);
+
+        let to_itself = GraphEdit::ConnectValueStream { source_index: first, source_stream_index: 0, destination_index: first, destination_stream_index: 0 };
+        assert_eq!(graph.check_edit(&to_itself), Err(EditError::Cycle));
+
+        graph.connect_value_stream(first, 0, second, 0);
+        let back = GraphEdit::ConnectValueStream { source_index: second, source_stream_index: 0, destination_index: first, destination_stream_index: 0 };
+        assert_eq!(graph.check_edit(&back), Err(EditError::Cycle));
+
+        graph.connect_value_stream(second, 0, first, 0);
+        assert!(graph.check_connections().is_err());
+        graph.process_next();
+    }
+
+    #[test]
+    fn gives_back_rejected_insertions() {
+        let mut constant = container(Constant::<MidiNote>::new(1.0));
+        let mut other_constant = container(Constant::<MidiNote>::new(1.0));
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let source = graph.add_instrument(&mut constant);
+        graph.connect_value_stream(source, 0, 1, 1);
+
+        let mut edits = Queue::<GraphEdit, 4>::new();
+        let mut removed = Queue::<&mut dyn InstrumentContainer, 4>::new();
+        let (mut edit_producer, mut edit_consumer) = edits.split();
+        let (mut removed_producer, mut removed_consumer) = removed.split();
+
+        let _ = edit_producer.push(GraphEdit::ConnectDestination { output_channel_index: 2, source_index: source, source_stream_index: 0 });
+        let _ = edit_producer.push(GraphEdit::InsertInstrument { index: 1, instrument: &mut other_constant });
+        let _ = edit_producer.push(GraphEdit::InsertInstrument { index: 1, instrument: &mut amplifier });
+        assert_eq!(graph.apply_edits(&mut edit_consumer, &mut removed_producer), AppliedEdits { applied: 1, rejected: 2, lost: 0 });
+        assert!(removed_consumer.pop().is_some());
+        assert!(removed_consumer.pop().is_none());
+    }
+
+    #[test]
+    fn reports_lost_instruments() {
+        let mut first = container(Constant::<MidiNote>::new(1.0));
+        let mut second = container(Constant::<MidiNote>::new(1.0));
+        let mut third = container(Constant::<MidiNote>::new(1.0));
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        graph.add_instrument(&mut first);
+        graph.add_instrument(&mut second);
+
+        let mut edits = Queue::<GraphEdit, 4>::new();
+        let mut removed = Queue::<&mut dyn InstrumentContainer, 2>::new();
+        let (mut edit_producer, mut edit_consumer) = edits.split();
+        let (mut removed_producer, _) = removed.split();
+
+        let _ = edit_producer.push(GraphEdit::RemoveInstrument { index: 0 });
+        let _ = edit_producer.push(GraphEdit::InsertInstrument { index: 1, instrument: &mut third });
+        assert_eq!(graph.apply_edits(&mut edit_consumer, &mut removed_producer), AppliedEdits { applied: 2, rejected: 0, lost: 1 });
+    }
+
+    #[test]
+    fn rejects_missing_bus_routes() {
+        let mut constant = container(Constant::<MidiNote>::new(1.0));
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let index = graph.add_instrument(&mut constant);
+        let bus = graph.add_bus("fx");
+
+        let send = GraphEdit::SetSendLevel { bus_index: bus, source_index: index, source_stream_index: 0, level: 0.5 };
+        assert_eq!(graph.check_edit(&send), Err(EditError::NoRoute));
+        let bus_return = GraphEdit::SetReturnLevel { bus_index: bus, output_channel_index: 0, level: 0.5 };
+        assert_eq!(graph.apply_edit(bus_return).err().map(|rejected| rejected.error), Some(EditError::NoRoute));
+    }
+
+    #[test]
+    fn crossfades_the_edited_channels() {
+        let mut first = container(Constant::<MidiNote>::new(1.0));
+        let mut second = container(Constant::<MidiNote>::new(1.0));
+        let mut graph: InstrumentGraph<4, 1, 2, 2> = InstrumentGraph::new();
+        let first = graph.add_instrument(&mut first);
+        let second = graph.add_instrument(&mut second);
+        graph.connect_destination(0, first, 0);
+        graph.connect_destination(1, second, 0);
+        graph.set_edit_crossfade(true);
+        graph.process_next();
+
+        let mut edits = Queue::<GraphEdit, 4>::new();
+        let mut removed = Queue::<&mut dyn InstrumentContainer, 4>::new();
+        let (mut edit_producer, mut edit_consumer) = edits.split();
+        let (mut removed_producer, _) = removed.split();
+        let _ = edit_producer.push(GraphEdit::ConnectDestination { output_channel_index: 0, source_index: second, source_stream_index: 0 });
+
+        // The edit is held while the edited channel fades out, the other channel is unchanged
+        assert_eq!(graph.apply_edits(&mut edit_consumer, &mut removed_producer).applied, 0);
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 1.0 - 1.0 / 128.0);
+        assert_eq!(graph.get_output(0)[127], 0.0);
+        assert_eq!(graph.get_output(1)[127], 1.0);
+
+        // Then it is applied and the channel fades in with the new topology
+        assert_eq!(graph.apply_edits(&mut edit_consumer, &mut removed_producer).applied, 1);
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 2.0 / 128.0);
+        assert_eq!(graph.get_output(0)[127], 2.0);
+        assert_eq!(graph.get_output(1)[0], 1.0);
+
+        assert_eq!(graph.apply_edits(&mut edit_consumer, &mut removed_producer), AppliedEdits::default());
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 2.0);
+    }
+}
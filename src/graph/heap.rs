@@ -0,0 +1,463 @@
+//! Heap-backed instrument graph.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{ControlStreamSource, InstrumentContainer, MidiNote, MusicalValue, STANDARD_BLOCK_SIZE};
+
+use super::{dot, group_by_level, AudioGraph, ControlStreamConnection, DestinationConnection, ValueStreamConnection};
+
+/// An instrument graph that owns its instruments and control sources and grows as needed.
+///
+/// This is the dynamic counterpart of `InstrumentGraph`: there are no compile-time capacities,
+/// and since every node is owned, the graph is `'static` and can be moved into an audio callback.
+///
+/// It only covers the core of `InstrumentGraph`: instruments, control sources, value stream and destination
+/// connections, sequential and parallel processing, reset and silence detection, and DOT output. Node modes
+/// (bypass, mute and solo), meters, profiling, parameters and automation, presets, buses, graph inputs,
+/// latency reporting and compensation, tail lengths, topology edits and connection checks are not supported.
+pub struct HeapInstrumentGraph<Note: Sized + Default + Copy + Send = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> {
+    /// The instruments in the graph
+    pub instruments: Vec<Option<Box<dyn InstrumentContainer<Note, BLOCK_SIZE>>>>,
+
+    /// The control sources in the graph
+    pub control_sources: Vec<Option<Box<dyn ControlStreamSource<Note>>>>,
+
+    /// The connections between control sources and instruments, for each instrument
+    pub(crate) instruments_control_sources: Vec<Option<ControlStreamConnection>>,
+
+    /// The connections between value streams, for each instrument
+    pub(crate) value_stream_connections: Vec<Vec<ValueStreamConnection>>,
+
+    pub(crate) destination_connections: Vec<Vec<DestinationConnection>>,
+
+    pub(crate) output_channels: Vec<[MusicalValue; BLOCK_SIZE]>,
+
+    pub(crate) process_order: Vec<usize>,
+
+    /// The end position in `process_order` of each dependency level
+    pub(crate) process_level_ends: Vec<usize>,
+
+    /// Set whenever the topology changes, the process order is rebuilt on the next use
+    pub(crate) process_order_dirty: bool,
+
+    /// The sampling rate the graph was prepared for, 0 if it was never prepared
+    pub(crate) sampling_rate: usize,
+}
+
+impl<Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize> HeapInstrumentGraph<Note, BLOCK_SIZE> {
+    pub fn new(output_channels: usize) -> Self {
+        Self {
+            instruments: Vec::new(),
+            control_sources: Vec::new(),
+            instruments_control_sources: Vec::new(),
+            value_stream_connections: Vec::new(),
+            destination_connections: (0..output_channels).map(|_| Vec::new()).collect(),
+            output_channels: vec![[0.0; BLOCK_SIZE]; output_channels],
+            process_order: Vec::new(),
+            process_level_ends: Vec::new(),
+            process_order_dirty: true,
+            sampling_rate: 0,
+        }
+    }
+
+    /// The number of output channels of the graph
+    pub fn output_channel_count(&self) -> usize {
+        self.output_channels.len()
+    }
+
+    pub fn add_instrument(&mut self, mut instrument: Box<dyn InstrumentContainer<Note, BLOCK_SIZE>>) -> usize {
+        if self.sampling_rate > 0 {
+            instrument.prepare(self.sampling_rate, BLOCK_SIZE);
+        }
+
+        self.instruments.push(Some(instrument));
+        self.instruments_control_sources.push(None);
+        self.value_stream_connections.push(Vec::new());
+        self.process_order_dirty = true;
+        self.instruments.len() - 1
+    }
+
+    /// Prepares every instrument for processing at the given sampling rate.
+    ///
+    /// Instruments added afterwards are prepared as they are added.
+    pub fn prepare(&mut self, sampling_rate: usize) {
+        self.sampling_rate = sampling_rate;
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.prepare(sampling_rate, BLOCK_SIZE);
+        }
+    }
+
+    /// The sampling rate the graph was last prepared for, if any
+    pub fn sampling_rate(&self) -> Option<usize> {
+        if self.sampling_rate > 0 { Some(self.sampling_rate) } else { None }
+    }
+
+    pub fn add_control_source(&mut self, control_source: Box<dyn ControlStreamSource<Note>>) -> usize {
+        self.control_sources.push(Some(control_source));
+        self.control_sources.len() - 1
+    }
+
+    pub fn connect_control_source(&mut self, control_source_index: usize, instrument_index: usize) {
+        if instrument_index >= self.instruments.len() {
+            panic!("Instrument index out of bounds");
+        }
+
+        self.instruments_control_sources[instrument_index] = Some(ControlStreamConnection {
+            source_index: control_source_index,
+        });
+    }
+
+    /// Connects an output stream of an instrument to an input stream of another.
+    ///
+    /// Connections making an instrument depend on itself, directly or through other instruments, panic.
+    pub fn connect_value_stream(&mut self, source_index: usize, source_stream_index: usize, destination_index: usize, destination_stream_index: usize) {
+        if source_index >= self.instruments.len() {
+            panic!("Source index out of bounds");
+        }
+
+        if destination_index >= self.instruments.len() {
+            panic!("Destination index out of bounds");
+        }
+
+        if source_index == destination_index || self.depends_on(source_index, destination_index) {
+            panic!("Connection would create a cycle");
+        }
+
+        self.value_stream_connections[destination_index].push(ValueStreamConnection {
+            source_index,
+            source_stream_index,
+            destination_stream_index,
+        });
+        self.process_order_dirty = true;
+    }
+
+    pub fn connect_destination(&mut self, output_channel_index: usize, source_index: usize, source_stream_index: usize) {
+        if output_channel_index >= self.output_channels.len() {
+            panic!("Output channel index out of bounds");
+        }
+
+        self.destination_connections[output_channel_index].push(DestinationConnection {
+            source_index,
+            source_stream_index,
+            solo: false,
+        });
+    }
+
+    /// Whether the instrument at `index` depends on the one at `ancestor`, directly or through other instruments
+    pub(crate) fn depends_on(&self, index: usize, ancestor: usize) -> bool {
+        let mut visited = vec![false; self.instruments.len()];
+        let mut stack = vec![index];
+        visited[index] = true;
+
+        while let Some(current) = stack.pop() {
+            for connection in &self.value_stream_connections[current] {
+                if connection.source_index == ancestor {
+                    return true;
+                }
+                if !visited[connection.source_index] {
+                    visited[connection.source_index] = true;
+                    stack.push(connection.source_index);
+                }
+            }
+        }
+        false
+    }
+
+    /// Marks the compiled process order as stale.
+    ///
+    /// Only needed after modifying `instruments` directly.
+    pub fn invalidate_process_order(&mut self) {
+        self.process_order_dirty = true;
+    }
+
+    /// Returns the compiled process order, rebuilding it first if the topology has changed.
+    ///
+    /// Only indexes of occupied instrument slots are included, grouped by dependency level.
+    pub fn process_order(&mut self) -> &[usize] {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        &self.process_order
+    }
+
+    /// Returns the number of dependency levels in the compiled process order.
+    ///
+    /// Instruments in the same level do not depend on each other and can be processed in parallel.
+    pub fn process_level_count(&mut self) -> usize {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        self.process_level_ends.len()
+    }
+
+    /// Returns the instrument indexes in the given dependency level of the compiled process order.
+    pub fn process_level(&mut self, level: usize) -> &[usize] {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        let start = if level == 0 { 0 } else { self.process_level_ends[level - 1] };
+        &self.process_order[start..self.process_level_ends[level]]
+    }
+
+    fn rebuild_process_order(&mut self) {
+        let (order, level_ends) = self.compute_process_order();
+        self.process_order = order;
+        self.process_level_ends = level_ends;
+        self.process_order_dirty = false;
+    }
+
+    /// Computes the process order, grouped by dependency level, and the end position of each level
+    fn compute_process_order(&self) -> (Vec<usize>, Vec<usize>) {
+        let count = self.instruments.len();
+        let mut pending = vec![0usize; count];
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); count];
+        for (destination_index, connections) in self.value_stream_connections.iter().enumerate() {
+            for connection in connections {
+                pending[destination_index] += 1;
+                dependents[connection.source_index].push(destination_index);
+            }
+        }
+
+        let mut order = Vec::with_capacity(count);
+        let mut queued = vec![false; count];
+        for (i, &pending_count) in pending.iter().enumerate() {
+            if pending_count == 0 {
+                order.push(i);
+                queued[i] = true;
+            }
+        }
+
+        let mut next = 0;
+        while next < order.len() {
+            let index = order[next];
+            next += 1;
+            for &dependent in &dependents[index] {
+                pending[dependent] -= 1;
+                if pending[dependent] == 0 && !queued[dependent] {
+                    order.push(dependent);
+                    queued[dependent] = true;
+                }
+            }
+        }
+
+        for (i, &was_queued) in queued.iter().enumerate() {
+            if !was_queued {
+                order.push(i);
+            }
+        }
+
+        order.retain(|&i| self.instruments[i].is_some());
+
+        let mut levels = vec![0usize; count];
+        let mut level_ends = vec![0usize; order.len()];
+        let level_count = group_by_level(&mut order, &mut levels, &mut level_ends, |index| {
+            self.value_stream_connections[index].iter().map(|connection| connection.source_index)
+        });
+        level_ends.truncate(level_count);
+
+        (order, level_ends)
+    }
+
+    /// Rebuilds the process order if needed and fetches the control streams
+    pub(super) fn begin_block(&mut self) {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+
+        for control_source in self.control_sources.iter_mut().flatten() {
+            control_source.fetch_next_stream();
+        }
+    }
+
+    /// Feeds the value and control streams connected to an instrument
+    pub(super) fn feed_instrument(&mut self, instrument_index: usize) {
+        // The instrument is taken out of its slot while it is fed, so its sources can be borrowed without copying
+        let Some(mut instrument) = self.instruments[instrument_index].take() else {
+            return;
+        };
+
+        for connection in &self.value_stream_connections[instrument_index] {
+            if let Some(source_instrument) = &self.instruments[connection.source_index] {
+                instrument.feed_value_stream(connection.destination_stream_index, source_instrument.get_output(connection.source_stream_index));
+            }
+        }
+
+        if let Some(connection) = &self.instruments_control_sources[instrument_index] {
+            if let Some(Some(control_source)) = self.control_sources.get(connection.source_index) {
+                for j in 0..instrument.in_control_streams() {
+                    instrument.feed_control_stream(j, control_source.get_control_stream());
+                }
+            }
+        }
+
+        self.instruments[instrument_index] = Some(instrument);
+    }
+
+    /// Writes the sum of the connected instrument outputs to each output channel, the first source is copied rather than added
+    pub(super) fn mix_destinations(&mut self) {
+        for (channel, connections) in self.output_channels.iter_mut().zip(&self.destination_connections) {
+            let mut written = false;
+            for connection in connections {
+                if let Some(source_instrument) = &self.instruments[connection.source_index] {
+                    let source_stream = source_instrument.get_output(connection.source_stream_index);
+                    if written {
+                        for (sample, source_sample) in channel.iter_mut().zip(source_stream) {
+                            *sample += source_sample;
+                        }
+                    } else {
+                        *channel = *source_stream;
+                        written = true;
+                    }
+                }
+            }
+
+            if !written {
+                *channel = [0.0; BLOCK_SIZE];
+            }
+        }
+    }
+
+    pub fn process_next(&mut self) {
+        self.begin_block();
+
+        for i in 0..self.process_order.len() {
+            let instrument_index = self.process_order[i];
+
+            self.feed_instrument(instrument_index);
+            if let Some(instrument) = &mut self.instruments[instrument_index] {
+                instrument.process_next();
+            }
+        }
+
+        self.mix_destinations();
+    }
+
+    pub fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE] {
+        &self.output_channels[index]
+    }
+
+    /// Clears the runtime state of every instrument and the output channels, keeping the topology
+    pub fn reset(&mut self) {
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.reset();
+        }
+
+        for channel in self.output_channels.iter_mut() {
+            *channel = [0.0; BLOCK_SIZE];
+        }
+    }
+
+    /// Whether every instrument is silent, so the output channels stay silent
+    pub fn is_silent(&self) -> bool {
+        self.instruments.iter().flatten().all(|instrument| instrument.is_silent())
+    }
+
+    /// Writes the graph as a Graphviz DOT document, including the computed process order
+    pub fn write_dot<W: core::fmt::Write>(&self, w: &mut W) -> core::fmt::Result {
+        let computed_order;
+        let order = if self.process_order_dirty {
+            computed_order = self.compute_process_order().0;
+            &computed_order
+        } else {
+            &self.process_order
+        };
+
+        dot::write_header(w, order)?;
+
+        for (index, instrument) in self.instruments.iter().enumerate() {
+            if let Some(instrument) = instrument {
+                dot::write_instrument::<W, Note, BLOCK_SIZE>(w, index, instrument.as_ref(), order)?;
+            }
+        }
+
+        for (index, control_source) in self.control_sources.iter().enumerate() {
+            if control_source.is_some() {
+                dot::write_control_source(w, index)?;
+            }
+        }
+
+        for index in 0..self.output_channels.len() {
+            dot::write_output_channel(w, index)?;
+        }
+
+        for (destination_index, connections) in self.value_stream_connections.iter().enumerate() {
+            for connection in connections {
+                dot::write_value_stream_connection(w, destination_index, connection, self.instruments[connection.source_index].as_deref(), self.instruments[destination_index].as_deref())?;
+            }
+        }
+
+        for (instrument_index, connection) in self.instruments_control_sources.iter().enumerate() {
+            if let Some(connection) = connection {
+                dot::write_control_connection(w, instrument_index, connection)?;
+            }
+        }
+
+        for (output_channel_index, connections) in self.destination_connections.iter().enumerate() {
+            for connection in connections {
+                dot::write_destination_connection(w, output_channel_index, connection, self.instruments[connection.source_index].as_deref())?;
+            }
+        }
+
+        dot::write_footer(w)
+    }
+}
+
+impl<Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize> AudioGraph<Note, BLOCK_SIZE> for HeapInstrumentGraph<Note, BLOCK_SIZE> {
+    fn output_channel_count(&self) -> usize {
+        self.output_channels.len()
+    }
+
+    fn instrument_mut(&mut self, index: usize) -> Option<&mut dyn InstrumentContainer<Note, BLOCK_SIZE>> {
+        match self.instruments.get_mut(index) {
+            Some(Some(instrument)) => Some(instrument.as_mut()),
+            _ => None,
+        }
+    }
+
+    fn prepare(&mut self, sampling_rate: usize) {
+        HeapInstrumentGraph::prepare(self, sampling_rate);
+    }
+
+    fn process_next(&mut self) {
+        HeapInstrumentGraph::process_next(self);
+    }
+
+    fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE] {
+        HeapInstrumentGraph::get_output(self, index)
+    }
+
+    fn reset(&mut self) {
+        HeapInstrumentGraph::reset(self);
+    }
+
+    fn is_silent(&self) -> bool {
+        HeapInstrumentGraph::is_silent(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::Amplifier;
+
+    #[test]
+    #[should_panic(expected = "Connection would create a cycle")]
+    fn rejects_self_connections() {
+        let mut graph: HeapInstrumentGraph = HeapInstrumentGraph::new(1);
+        let amplifier = graph.add_instrument(Box::new(container(Amplifier::<MidiNote>::new())));
+        graph.connect_value_stream(amplifier, 0, amplifier, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Connection would create a cycle")]
+    fn rejects_cycles() {
+        let mut graph: HeapInstrumentGraph = HeapInstrumentGraph::new(1);
+        let first = graph.add_instrument(Box::new(container(Amplifier::<MidiNote>::new())));
+        let second = graph.add_instrument(Box::new(container(Amplifier::<MidiNote>::new())));
+        graph.connect_value_stream(first, 0, second, 0);
+        graph.connect_value_stream(second, 0, first, 1);
+    }
+}
@@ -0,0 +1,119 @@
+//! External audio inputs.
+//!
+//! The host fills the input channels of a graph before each block, with `InstrumentGraph::set_input`
+//! or in place through `InstrumentGraph::input_mut`, and the channels feed the value streams of the
+//! instruments connected to them. This lets a graph process audio, such as a microphone, rather than
+//! only generate it. Input channels keep their samples until they are written again.
+
+use crate::{InstrumentContainer, MusicalValue};
+
+use super::latency::feed_aligned;
+use super::InstrumentGraph;
+
+#[derive(Debug, Clone)]
+pub(crate) struct InputConnection {
+    pub input_channel_index: usize,
+    pub destination_stream_index: usize,
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Copies the next block of an input channel from the host
+    pub fn set_input(&mut self, input_channel_index: usize, input: &[MusicalValue; BLOCK_SIZE]) {
+        *self.input_mut(input_channel_index) = *input;
+    }
+
+    /// Gets an input channel, to be filled with the next block
+    pub fn input_mut(&mut self, input_channel_index: usize) -> &mut [MusicalValue; BLOCK_SIZE] {
+        if input_channel_index >= INPUT_CHANNELS {
+            panic!("Input channel index out of bounds");
+        }
+
+        &mut self.input_channels[input_channel_index]
+    }
+
+    /// Gets the current block of an input channel
+    pub fn get_input(&self, input_channel_index: usize) -> &[MusicalValue; BLOCK_SIZE] {
+        &self.input_channels[input_channel_index]
+    }
+
+    /// Feeds an input channel into a value stream of an instrument
+    pub fn connect_input(&mut self, input_channel_index: usize, destination_index: usize, destination_stream_index: usize) {
+        if input_channel_index >= INPUT_CHANNELS {
+            panic!("Input channel index out of bounds");
+        }
+
+        if destination_index >= SIZE {
+            panic!("Destination index out of bounds");
+        }
+
+        for i in 0..CONNECTION_SIZE {
+            if self.input_connections[destination_index][i].is_none() {
+                self.input_connections[destination_index][i] = Some(InputConnection {
+                    input_channel_index,
+                    destination_stream_index,
+                });
+                return;
+            }
+        }
+        panic!("No more space for input connections");
+    }
+
+    /// Removes the given input connection, if it exists
+    pub fn disconnect_input(&mut self, input_channel_index: usize, destination_index: usize, destination_stream_index: usize) {
+        if destination_index >= SIZE {
+            panic!("Destination index out of bounds");
+        }
+
+        for connection in self.input_connections[destination_index].iter_mut() {
+            if connection.as_ref().is_some_and(|connection| connection.input_channel_index == input_channel_index && connection.destination_stream_index == destination_stream_index) {
+                *connection = None;
+                return;
+            }
+        }
+    }
+
+    /// Feeds the input channels connected to an instrument, which is taken out of the graph while it is fed
+    pub(crate) fn feed_inputs(&mut self, instrument_index: usize, instrument: &mut dyn InstrumentContainer<Note, BLOCK_SIZE>) {
+        for (j, connection) in self.input_connections[instrument_index].iter().enumerate() {
+            if let Some(connection) = connection {
+                let input = &self.input_channels[connection.input_channel_index];
+                feed_aligned(self.merge_delays.as_deref_mut(), instrument, instrument_index, CONNECTION_SIZE + j, connection.destination_stream_index, Some(input));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::Amplifier;
+    use crate::MidiNote;
+
+    #[test]
+    fn inputs_feed_connected_instruments() {
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<2, 1, 2, 1, MidiNote, 128, 2> = InstrumentGraph::new();
+        let amplifier = graph.add_instrument(&mut amplifier);
+        graph.connect_input(0, amplifier, 0);
+        graph.connect_input(1, amplifier, 1);
+        graph.connect_destination(0, amplifier, 0);
+
+        let ramp = core::array::from_fn(|i| i as MusicalValue / 128.0);
+        graph.set_input(0, &ramp);
+        *graph.input_mut(1) = [2.0; 128];
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[64], 1.0);
+        assert_eq!(graph.get_output(0)[127], 127.0 / 64.0);
+
+        // The channels keep their samples until they are written again
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[64], 1.0);
+        assert_eq!(graph.get_input(0), &ramp);
+
+        // A disconnected gain input reads as silence
+        graph.disconnect_input(1, amplifier, 1);
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+    }
+}
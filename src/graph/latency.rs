@@ -0,0 +1,499 @@
+//! Latency reporting and compensation.
+//!
+//! Every instrument reports its latency with `Instrument::latency`. The latency of a path through the
+//! graph is the sum of the latencies along it, and the latency of the graph is that of its slowest path
+//! to an output channel. With a `LatencyCompensator`, the graph delays every faster path to the output
+//! channels by the difference, so that parallel paths are summed aligned.
+//!
+//! Paths can also merge before the output, where an instrument has inputs of different latencies. With
+//! `MergeDelay` lines, the graph delays the value stream and input channel connections of the faster paths into
+//! that instrument.
+//! Sends of different latencies summed into a bus are not aligned.
+//!
+//! The latencies are computed again only when the topology, a node mode or the latency of an instrument changes.
+//! `InstrumentGraph::check_connections` reports delays the compensation cannot apply.
+
+use crate::{InstrumentContainer, MusicalValue};
+
+use super::bus::{mix_ramped, return_source};
+use super::check::ConnectionIssue;
+use super::{InstrumentGraph, NodeMode};
+
+/// The length of the compensation buffers, paths are delayed by at most this minus the block size
+pub const MAX_LATENCY_COMPENSATION: usize = 8192;
+
+/// The delay lines aligning the paths to the output channels of a graph
+pub struct LatencyCompensator<const CHANNELS: usize = 1> {
+    /// The samples to be output, for each channel, with faster paths added further ahead
+    pub(crate) buffers: [[MusicalValue; MAX_LATENCY_COMPENSATION]; CHANNELS],
+
+    /// The position of the next output sample in the buffers
+    pub(crate) position: usize,
+
+    /// The number of samples until every delayed sound has been output
+    pub(crate) remaining: usize,
+}
+
+impl<const CHANNELS: usize> LatencyCompensator<CHANNELS> {
+    pub const fn new() -> Self {
+        Self {
+            buffers: [[0.0; MAX_LATENCY_COMPENSATION]; CHANNELS],
+            position: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Clears the delayed samples
+    pub fn reset(&mut self) {
+        self.buffers = [[0.0; MAX_LATENCY_COMPENSATION]; CHANNELS];
+        self.position = 0;
+        self.remaining = 0;
+    }
+
+    /// Whether every delayed sound has been output
+    pub fn is_silent(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Adds a block to a channel, to be output `delay` samples after the next block
+    fn add<const BLOCK_SIZE: usize>(&mut self, channel: usize, delay: usize, block: &[MusicalValue; BLOCK_SIZE]) {
+        let delay = delay.min(MAX_LATENCY_COMPENSATION - BLOCK_SIZE);
+        if block.iter().any(|&sample| sample != 0.0) {
+            self.remaining = self.remaining.max(delay + BLOCK_SIZE);
+        }
+
+        let start = self.position + delay;
+        for (k, sample) in block.iter().enumerate() {
+            self.buffers[channel][(start + k) % MAX_LATENCY_COMPENSATION] += sample;
+        }
+    }
+
+    /// Moves the next block of a channel into `block`
+    fn take<const BLOCK_SIZE: usize>(&mut self, channel: usize, block: &mut [MusicalValue; BLOCK_SIZE]) {
+        for (k, sample) in block.iter_mut().enumerate() {
+            let index = (self.position + k) % MAX_LATENCY_COMPENSATION;
+            *sample = self.buffers[channel][index];
+            self.buffers[channel][index] = 0.0;
+        }
+    }
+}
+
+impl<const CHANNELS: usize> Default for LatencyCompensator<CHANNELS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A delay line aligning a value stream connection with slower paths into the same instrument
+pub struct MergeDelay {
+    pub(crate) buffer: [MusicalValue; MAX_LATENCY_COMPENSATION],
+
+    /// The position of the next output sample in the buffer
+    pub(crate) position: usize,
+
+    /// The number of samples until every delayed sound has been output
+    pub(crate) remaining: usize,
+
+    /// The destination instrument index, connection slot and delay the line is assigned to
+    pub(crate) assignment: Option<(usize, usize, usize)>,
+}
+
+impl MergeDelay {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0.0; MAX_LATENCY_COMPENSATION],
+            position: 0,
+            remaining: 0,
+            assignment: None,
+        }
+    }
+
+    /// Clears the delayed samples
+    pub fn reset(&mut self) {
+        self.buffer = [0.0; MAX_LATENCY_COMPENSATION];
+        self.position = 0;
+        self.remaining = 0;
+    }
+
+    /// Whether every delayed sound has been output
+    pub fn is_silent(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Assigns the line to a connection, clearing it if it delayed another one
+    fn assign(&mut self, destination_index: usize, connection_index: usize, delay: usize) {
+        if !self.delays(destination_index, connection_index) {
+            self.reset();
+        }
+        self.assignment = Some((destination_index, connection_index, delay));
+    }
+
+    /// Whether the line delays the given connection slot of the given instrument
+    fn delays(&self, destination_index: usize, connection_index: usize) -> bool {
+        matches!(self.assignment, Some((destination, connection, _)) if destination == destination_index && connection == connection_index)
+    }
+
+    /// Delays a block by the assigned delay
+    fn process<const BLOCK_SIZE: usize>(&mut self, input: &[MusicalValue; BLOCK_SIZE], output: &mut [MusicalValue; BLOCK_SIZE]) {
+        let delay = self.assignment.map_or(0, |(_, _, delay)| delay).min(MAX_LATENCY_COMPENSATION - BLOCK_SIZE);
+        if input.iter().any(|&sample| sample != 0.0) {
+            self.remaining = self.remaining.max(delay + BLOCK_SIZE);
+        }
+
+        for (k, sample) in input.iter().enumerate() {
+            self.buffer[(self.position + delay + k) % MAX_LATENCY_COMPENSATION] += sample;
+        }
+        for (k, sample) in output.iter_mut().enumerate() {
+            let index = (self.position + k) % MAX_LATENCY_COMPENSATION;
+            *sample = self.buffer[index];
+            self.buffer[index] = 0.0;
+        }
+        self.position = (self.position + BLOCK_SIZE) % MAX_LATENCY_COMPENSATION;
+        self.remaining = self.remaining.saturating_sub(BLOCK_SIZE);
+    }
+}
+
+impl Default for MergeDelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Feeds a block to an instrument input, through the merge delay line assigned to the connection if there is one.
+///
+/// `block` is `None` for missing or muted sources, which still advance the line to flush it.
+pub(crate) fn feed_aligned<Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize>(
+    merge_delays: Option<&mut [MergeDelay]>,
+    instrument: &mut dyn InstrumentContainer<Note, BLOCK_SIZE>,
+    destination_index: usize,
+    connection_index: usize,
+    destination_stream_index: usize,
+    block: Option<&[MusicalValue; BLOCK_SIZE]>,
+) {
+    let line = merge_delays.and_then(|delays| delays.iter_mut().find(|line| line.delays(destination_index, connection_index)));
+    match (line, block) {
+        (Some(line), block) => {
+            let mut delayed = [0.0; BLOCK_SIZE];
+            line.process(block.unwrap_or(&[0.0; BLOCK_SIZE]), &mut delayed);
+            instrument.feed_value_stream(destination_stream_index, &delayed);
+        },
+        (None, Some(block)) => instrument.feed_value_stream(destination_stream_index, block),
+        (None, None) => {},
+    }
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Enables latency compensation through the given compensator, or disables it.
+    ///
+    /// The compensator is reset, changes of the latency while it is enabled shift the delayed samples.
+    pub fn set_latency_compensation(&mut self, compensator: Option<&'a mut LatencyCompensator<OUTPUT_CHANNELS>>) {
+        self.compensator = compensator;
+        if let Some(compensator) = &mut self.compensator {
+            compensator.reset();
+        }
+        self.latencies_dirty = true;
+    }
+
+    /// Enables the alignment of paths merging inside the graph through the given delay lines, or disables it.
+    ///
+    /// Every value stream or input channel connection from a faster path into an instrument with inputs of different
+    /// latencies takes a line, connections left without one are not aligned. The lines are reset.
+    pub fn set_merge_delays(&mut self, delays: Option<&'a mut [MergeDelay]>) {
+        self.merge_delays = delays;
+        for line in self.merge_delays.iter_mut().flat_map(|delays| delays.iter_mut()) {
+            line.reset();
+            line.assignment = None;
+        }
+        self.latencies_dirty = true;
+    }
+
+    /// The latency of the graph in samples, which is the latency of its slowest path to an output channel
+    pub fn latency(&self) -> usize {
+        self.compute_latencies(&mut [0; SIZE])
+    }
+
+    /// The latency of the path from the graph inputs to the outputs of the instrument at the given index
+    pub fn node_latency(&self, index: usize) -> usize {
+        let mut latencies = [0; SIZE];
+        self.compute_latencies(&mut latencies);
+        latencies[index]
+    }
+
+    /// Computes the latency at the output of every instrument, returning the latency of the graph
+    fn compute_latencies(&self, latencies: &mut [usize; SIZE]) -> usize {
+        for index in self.dependency_order() {
+            if self.instruments[index].is_some() {
+                latencies[index] = self.input_latency(latencies, index) + self.own_latency(index);
+            }
+        }
+        self.output_latency(latencies)
+    }
+
+    /// The latency of the instrument at the given index itself, 0 while it is bypassed
+    fn own_latency(&self, index: usize) -> usize {
+        match (&self.instruments[index], self.node_modes[index]) {
+            (Some(instrument), NodeMode::Active | NodeMode::Muted { .. }) => instrument.latency(),
+            _ => 0,
+        }
+    }
+
+    /// The latency of the slowest path into the instrument at the given index
+    fn input_latency(&self, latencies: &[usize; SIZE], index: usize) -> usize {
+        self.value_stream_connections[index].iter().flatten()
+            .map(|connection| connection.source_index)
+            .chain(self.bus_dependencies(index))
+            .map(|source_index| latencies[source_index])
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The latency of the slowest path to an output channel
+    fn output_latency(&self, latencies: &[usize; SIZE]) -> usize {
+        let mut latency = 0;
+        for bus_return in self.buses.iter().flatten().flat_map(|bus| bus.returns.iter().flatten()) {
+            if self.instruments[bus_return.source_index].is_some() {
+                latency = latency.max(latencies[bus_return.source_index]);
+            }
+        }
+
+        for connection in self.destination_connections.iter().flatten().flatten() {
+            if self.instruments[connection.source_index].is_some() {
+                latency = latency.max(latencies[connection.source_index]);
+            }
+        }
+        latency
+    }
+
+    /// Calls `f` with the destination index, connection slot, destination stream and required delay of every
+    /// connection from a faster path into an instrument with inputs of different latencies.
+    ///
+    /// Input channel connections have the slots following the value stream connections, from `CONNECTION_SIZE`.
+    fn for_each_merge_delay(&self, latencies: &[usize; SIZE], mut f: impl FnMut(usize, usize, usize, usize)) {
+        for destination_index in 0..SIZE {
+            if self.instruments[destination_index].is_none() {
+                continue;
+            }
+
+            let input_latency = self.input_latency(latencies, destination_index);
+            let value_streams = self.value_stream_connections[destination_index].iter().enumerate()
+                .filter_map(|(j, connection)| connection.as_ref().map(|connection| (j, connection.destination_stream_index, latencies[connection.source_index])));
+            let inputs = self.input_connections[destination_index].iter().enumerate()
+                .filter_map(|(j, connection)| connection.as_ref().map(|connection| (CONNECTION_SIZE + j, connection.destination_stream_index, 0)));
+            for (connection_index, destination_stream_index, latency) in value_streams.chain(inputs) {
+                if latency < input_latency {
+                    f(destination_index, connection_index, destination_stream_index, input_latency - latency);
+                }
+            }
+        }
+    }
+
+    /// Recomputes the cached latencies if the topology, a node mode or the latency of an instrument changed, and
+    /// assigns the merge delay lines
+    pub(crate) fn update_latencies(&mut self) {
+        if self.compensator.is_none() && self.merge_delays.is_none() {
+            return;
+        }
+
+        for index in 0..SIZE {
+            let own_latency = self.own_latency(index);
+            if own_latency != self.own_latencies[index] {
+                self.own_latencies[index] = own_latency;
+                self.latencies_dirty = true;
+            }
+        }
+        if !self.latencies_dirty {
+            return;
+        }
+
+        let mut latencies = [0; SIZE];
+        self.compute_latencies(&mut latencies);
+        self.latencies = latencies;
+        self.latencies_dirty = false;
+
+        if let Some(delays) = self.merge_delays.take() {
+            let mut lines = delays.iter_mut();
+            self.for_each_merge_delay(&latencies, |destination_index, connection_index, _, delay| {
+                if let Some(line) = lines.next() {
+                    line.assign(destination_index, connection_index, delay);
+                }
+            });
+            for line in lines {
+                line.assignment = None;
+            }
+            self.merge_delays = Some(delays);
+        }
+    }
+
+    /// Checks that every path can be delayed as far as latency compensation requires, when it is enabled
+    pub(crate) fn check_latencies(&self) -> Result<(), ConnectionIssue> {
+        if self.compensator.is_none() && self.merge_delays.is_none() {
+            return Ok(());
+        }
+
+        let max_delay = MAX_LATENCY_COMPENSATION - BLOCK_SIZE;
+        let mut latencies = [0; SIZE];
+        let latency = self.compute_latencies(&mut latencies);
+
+        if self.compensator.is_some() {
+            let return_sources = self.buses.iter().flatten()
+                .flat_map(|bus| bus.returns.iter().flatten())
+                .map(|bus_return| bus_return.source_index);
+            let destination_sources = self.destination_connections.iter().flatten().flatten()
+                .map(|connection| connection.source_index);
+            for source_index in return_sources.chain(destination_sources) {
+                let delay = latency - latencies[source_index];
+                if self.instruments[source_index].is_some() && delay > max_delay {
+                    return Err(ConnectionIssue::CompensationOutOfRange { instrument_index: source_index, delay });
+                }
+            }
+        }
+
+        let line_count = self.merge_delays.as_ref().map_or(0, |delays| delays.len());
+        let mut lines_needed = 0;
+        let mut issue = None;
+        self.for_each_merge_delay(&latencies, |destination_index, _, destination_stream_index, delay| {
+            lines_needed += 1;
+            if issue.is_some() {
+                return;
+            }
+            if delay > max_delay {
+                issue = Some(ConnectionIssue::CompensationOutOfRange { instrument_index: destination_index, delay });
+            } else if lines_needed > line_count {
+                issue = Some(ConnectionIssue::UnalignedMerge { destination_index, destination_stream_index });
+            }
+        });
+        issue.map_or(Ok(()), Err)
+    }
+
+    /// Writes the connected instrument outputs and bus returns to the output channels through the compensator,
+    /// delaying each by the difference between its latency and the latency of the graph
+    pub(crate) fn mix_compensated(&mut self) {
+        let Some(compensator) = self.compensator.take() else {
+            return;
+        };
+
+        let latencies = self.latencies;
+        let latency = self.output_latency(&latencies);
+
+        let any_solo = self.destination_connections.iter().flatten().flatten().any(|connection| connection.solo);
+        for (i, connections) in self.destination_connections.iter().enumerate() {
+            for connection in connections.iter().flatten() {
+                if (any_solo && !connection.solo) || matches!(self.node_modes[connection.source_index], NodeMode::Muted { .. }) {
+                    continue;
+                }
+
+                if let Some(source_instrument) = &self.instruments[connection.source_index] {
+                    compensator.add(i, latency - latencies[connection.source_index], source_instrument.get_output(connection.source_stream_index));
+                }
+            }
+        }
+
+        for bus in self.buses.iter_mut().flatten() {
+            for bus_return in bus.returns.iter_mut().flatten() {
+                if let Some(source) = return_source(&self.instruments, &self.node_modes, bus_return) {
+                    let mut block = [0.0; BLOCK_SIZE];
+                    mix_ramped(&mut block, source, bus_return.previous_level, bus_return.level);
+                    compensator.add(bus_return.output_channel_index, latency - latencies[bus_return.source_index], &block);
+                }
+                bus_return.previous_level = bus_return.level;
+            }
+        }
+
+        for (i, channel) in self.output_channels.iter_mut().enumerate() {
+            compensator.take(i, channel);
+        }
+        compensator.position = (compensator.position + BLOCK_SIZE) % MAX_LATENCY_COMPENSATION;
+        compensator.remaining = compensator.remaining.saturating_sub(BLOCK_SIZE);
+
+        self.compensator = Some(compensator);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::{Delay, Mixer};
+    use crate::{container, MidiNote};
+
+    fn impulse() -> [MusicalValue; 128] {
+        let mut block = [0.0; 128];
+        block[5] = 1.0;
+        block
+    }
+
+    fn reported_delay(samples: u16) -> Delay {
+        let mut delay = Delay::new(samples);
+        delay.report_latency = true;
+        delay
+    }
+
+    #[test]
+    fn aligns_merging_paths() {
+        let mut delay = container(reported_delay(10));
+        let mut mixer = container(Mixer::<2, MidiNote>::new());
+        let mut lines = [MergeDelay::new()];
+        let mut graph: InstrumentGraph<2, 1, 2, 1, MidiNote, 128, 1> = InstrumentGraph::new();
+        let delay_index = graph.add_instrument(&mut delay);
+        let mixer_index = graph.add_instrument(&mut mixer);
+        graph.connect_input(0, delay_index, 0);
+        graph.connect_value_stream(delay_index, 0, mixer_index, 0);
+        graph.connect_input(0, mixer_index, 1);
+        graph.connect_destination(0, mixer_index, 0);
+
+        graph.set_merge_delays(Some(&mut []));
+        assert_eq!(graph.check_connections(), Err(ConnectionIssue::UnalignedMerge { destination_index: mixer_index, destination_stream_index: 1 }));
+
+        graph.set_merge_delays(Some(&mut lines));
+        assert_eq!(graph.check_connections(), Ok(()));
+        graph.set_input(0, &impulse());
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[5], 0.0);
+        assert_eq!(graph.get_output(0)[15], 2.0);
+        assert!(!graph.is_silent());
+    }
+
+    #[test]
+    fn follows_latency_changes() {
+        let mut delay = container(reported_delay(10));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut compensator = LatencyCompensator::new();
+        let mut graph: InstrumentGraph<2, 1, 2, 1, MidiNote, 128, 1> = InstrumentGraph::new();
+        let delay_index = graph.add_instrument(&mut delay);
+        let mixer_index = graph.add_instrument(&mut mixer);
+        graph.connect_input(0, delay_index, 0);
+        graph.connect_input(0, mixer_index, 0);
+        graph.connect_destination(0, delay_index, 0);
+        graph.connect_destination(0, mixer_index, 0);
+        graph.set_latency_compensation(Some(&mut compensator));
+
+        graph.set_input(0, &impulse());
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[15], 2.0);
+
+        graph.set_parameter(delay_index, 0, 20.0);
+        graph.set_input(0, &[0.0; 128]);
+        graph.process_next();
+        graph.set_input(0, &impulse());
+        graph.process_next();
+        assert_eq!(graph.latency(), 20);
+        assert_eq!(graph.get_output(0)[25], 2.0);
+    }
+
+    #[test]
+    fn reports_delays_out_of_range() {
+        let mut delay = container(reported_delay(9000));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut compensator = LatencyCompensator::new();
+        let mut graph: InstrumentGraph<2, 1, 2, 1, MidiNote, 128, 1> = InstrumentGraph::new();
+        let delay_index = graph.add_instrument(&mut delay);
+        let mixer_index = graph.add_instrument(&mut mixer);
+        graph.connect_input(0, delay_index, 0);
+        graph.connect_input(0, mixer_index, 0);
+        graph.connect_destination(0, delay_index, 0);
+        graph.connect_destination(0, mixer_index, 0);
+        assert_eq!(graph.check_connections(), Ok(()));
+
+        graph.set_latency_compensation(Some(&mut compensator));
+        assert_eq!(graph.check_connections(), Err(ConnectionIssue::CompensationOutOfRange { instrument_index: mixer_index, delay: 9000 }));
+    }
+}
@@ -0,0 +1,198 @@
+//! Resetting graphs and detecting when they fall silent.
+//!
+//! `InstrumentGraph::reset` clears the runtime state of every instrument and of the graph itself, for
+//! example when the transport stops or seeks. `InstrumentGraph::tail_length` and
+//! `InstrumentGraph::is_silent` tell the host how long the graph keeps sounding once its input stops.
+
+use super::edit::EditFade;
+use super::{InstrumentGraph, NodeMode};
+
+/// The longer of two tails, where `None` is unbounded
+fn longest_tail(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a?.max(b?))
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Clears the runtime state of every instrument, the buses, the output channels, the latency compensator,
+    /// the merge delay lines and the meters.
+    ///
+    /// The topology, parameters, pending parameter changes and the sample position are kept.
+    pub fn reset(&mut self) {
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.reset();
+        }
+
+        for bus in self.buses.iter_mut().flatten() {
+            bus.buffer = [0.0; BLOCK_SIZE];
+            bus.mixed = false;
+            for send in bus.sends.iter_mut().flatten() {
+                send.previous_level = send.level;
+            }
+            for bus_return in bus.returns.iter_mut().flatten() {
+                bus_return.previous_level = bus_return.level;
+            }
+        }
+
+        self.output_channels = [[0.0; BLOCK_SIZE]; OUTPUT_CHANNELS];
+        self.edit_fade = EditFade::Idle;
+
+        if let Some(compensator) = &mut self.compensator {
+            compensator.reset();
+        }
+        for line in self.merge_delays.iter_mut().flat_map(|delays| delays.iter_mut()) {
+            line.reset();
+        }
+
+        if let Some(meters) = self.meters {
+            meters.reset();
+        }
+    }
+
+    /// The number of samples the graph can keep sounding once its input falls silent and notes are released,
+    /// `None` if it can sound indefinitely.
+    ///
+    /// The tails along each path to an output channel are added up, along with the latency compensation and merge
+    /// delays.
+    pub fn tail_length(&self) -> Option<usize> {
+        let mut tails = [Some(0); SIZE];
+        for index in self.dependency_order() {
+            let Some(instrument) = &self.instruments[index] else {
+                continue;
+            };
+
+            let input_tail = self.value_stream_connections[index].iter().flatten()
+                .map(|connection| connection.source_index)
+                .chain(self.bus_dependencies(index))
+                .try_fold(0, |tail, source_index| longest_tail(Some(tail), tails[source_index]));
+            let own_tail = match self.node_modes[index] {
+                NodeMode::Bypassed { .. } => Some(0),
+                _ => instrument.tail_length(),
+            };
+            tails[index] = input_tail.zip(own_tail).map(|(input_tail, own_tail)| input_tail.saturating_add(own_tail));
+        }
+
+        let mut tail = Some(0);
+        for bus_return in self.buses.iter().flatten().flat_map(|bus| bus.returns.iter().flatten()) {
+            if self.instruments[bus_return.source_index].is_some() {
+                tail = longest_tail(tail, tails[bus_return.source_index]);
+            }
+        }
+
+        for connection in self.destination_connections.iter().flatten().flatten() {
+            if self.instruments[connection.source_index].is_some() {
+                tail = longest_tail(tail, tails[connection.source_index]);
+            }
+        }
+
+        if self.compensator.is_some() || self.merge_delays.is_some() {
+            tail = Some(tail?.saturating_add(self.latency()));
+        }
+        tail
+    }
+
+    /// Whether the output channels stay silent for as long as the input channels do.
+    ///
+    /// This holds when every instrument is silent, bypassed instruments pass their silent input on, and nothing is
+    /// left in the latency compensator or the merge delay lines.
+    pub fn is_silent(&self) -> bool {
+        let instruments_silent = self.instruments.iter().zip(&self.node_modes).all(|(instrument, mode)| match (instrument, mode) {
+            (Some(_), NodeMode::Bypassed { .. }) | (None, _) => true,
+            (Some(instrument), _) => instrument.is_silent(),
+        });
+
+        instruments_silent
+            && self.compensator.as_ref().is_none_or(|compensator| compensator.is_silent())
+            && self.merge_delays.iter().flat_map(|delays| delays.iter()).all(|line| line.is_silent())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::envelope::LinearEnvelope;
+    use crate::instrument::{Constant, Delay};
+    use crate::{ControlStreamSource, MidiNote, NoteCommand, NoteCommandType};
+
+    /// Plays one command per block, then nothing
+    struct Script {
+        commands: [NoteCommandType; 3],
+        block: usize,
+        stream: [NoteCommand<MidiNote>; 1],
+    }
+
+    impl Script {
+        fn new(commands: [NoteCommandType; 3]) -> Self {
+            Self { commands, block: 0, stream: [NoteCommand { command_type: NoteCommandType::Noop, velocity: 255, note: 60 }] }
+        }
+    }
+
+    impl ControlStreamSource<MidiNote> for Script {
+        fn get_control_stream(&self) -> &[NoteCommand<MidiNote>] {
+            &self.stream
+        }
+
+        fn fetch_next_stream(&mut self) {
+            self.stream[0].command_type = self.commands.get(self.block).copied().unwrap_or(NoteCommandType::Noop);
+            self.block += 1;
+        }
+    }
+
+    #[test]
+    fn reset_clears_delays_and_envelopes() {
+        let mut script = Script::new([NoteCommandType::NoteOn, NoteCommandType::Noop, NoteCommandType::Noop]);
+        let mut envelope = container(LinearEnvelope::<1, MidiNote>::new([0], [1.0], 256));
+        let mut constant = container(Constant::<MidiNote>::new(1.0));
+        let mut delay = container(Delay::<MidiNote>::new(64));
+        let mut graph: InstrumentGraph<4, 1, 1, 2> = InstrumentGraph::new();
+        let script = graph.add_control_source(&mut script);
+        let envelope = graph.add_instrument(&mut envelope);
+        let constant = graph.add_instrument(&mut constant);
+        let delay = graph.add_instrument(&mut delay);
+        graph.connect_control_source(script, envelope);
+        graph.connect_value_stream(constant, 0, delay, 0);
+        graph.connect_destination(0, envelope, 0);
+        graph.connect_destination(1, delay, 0);
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[127], 1.0);
+        assert_eq!(graph.get_output(1)[127], 1.0);
+
+        // Without the reset, the held note and the delayed samples would still sound
+        graph.remove_instrument(constant);
+        graph.reset();
+        assert!(graph.is_silent());
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+        assert_eq!(graph.get_output(1), &[0.0; 128]);
+    }
+
+    #[test]
+    fn tail_reaches_silence() {
+        let mut script = Script::new([NoteCommandType::NoteOn, NoteCommandType::Noop, NoteCommandType::NoteOff]);
+        let mut envelope = container(LinearEnvelope::<1, MidiNote>::new([0], [1.0], 200));
+        let mut delay = container(Delay::<MidiNote>::new(100));
+        let mut graph: InstrumentGraph<2, 1, 1, 1> = InstrumentGraph::new();
+        let script = graph.add_control_source(&mut script);
+        let envelope = graph.add_instrument(&mut envelope);
+        let delay = graph.add_instrument(&mut delay);
+        graph.connect_control_source(script, envelope);
+        graph.connect_value_stream(envelope, 0, delay, 0);
+        graph.connect_destination(0, delay, 0);
+
+        // The release, then one sample more than the delay
+        assert_eq!(graph.tail_length(), Some(200 + 101));
+
+        // The note is released at sample 256, and sounds until sample 256 + 199 + 100 = 555
+        for _ in 0..4 {
+            graph.process_next();
+            assert!(!graph.is_silent());
+        }
+        graph.process_next();
+        assert!(graph.is_silent());
+        assert!(graph.get_output(0)[555 - 512] > 0.0);
+        assert!(graph.get_output(0)[556 - 512..].iter().all(|&sample| sample == 0.0));
+
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Level metering of graph outputs.
+//!
+//! An `InstrumentGraph` with meters attached measures every instrument output stream and every
+//! output channel at the end of each block. The meters are plain atomics, so a UI thread can read
+//! them while the audio thread writes them, without locking.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::MusicalValue;
+
+/// The number of output streams metered per instrument, further streams are not metered
+pub const MAX_METERED_STREAMS: usize = 8;
+
+/// The peak and RMS level of a single stream
+pub struct LevelMeter {
+    /// The peak of the last block
+    peak: AtomicU32,
+
+    /// The RMS of the last block, decaying instead of dropping when the signal gets quieter
+    rms: AtomicU32,
+
+    /// The highest recent peak, held for a while before it decays
+    peak_hold: AtomicU32,
+
+    /// The number of blocks the held peak is kept before it starts to decay, only written by the audio thread
+    hold_remaining: AtomicUsize,
+}
+
+impl Default for LevelMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LevelMeter {
+    pub const fn new() -> Self {
+        Self {
+            peak: AtomicU32::new(0),
+            rms: AtomicU32::new(0),
+            peak_hold: AtomicU32::new(0),
+            hold_remaining: AtomicUsize::new(0),
+        }
+    }
+
+    /// The absolute peak of the last block
+    pub fn peak(&self) -> MusicalValue {
+        MusicalValue::from_bits(self.peak.load(Ordering::Relaxed))
+    }
+
+    /// The RMS level, decaying after the signal gets quieter
+    pub fn rms(&self) -> MusicalValue {
+        MusicalValue::from_bits(self.rms.load(Ordering::Relaxed))
+    }
+
+    /// The held peak, decaying after the hold time
+    pub fn peak_hold(&self) -> MusicalValue {
+        MusicalValue::from_bits(self.peak_hold.load(Ordering::Relaxed))
+    }
+
+    /// Resets every level to silence
+    pub fn reset(&self) {
+        self.peak.store(0, Ordering::Relaxed);
+        self.rms.store(0, Ordering::Relaxed);
+        self.peak_hold.store(0, Ordering::Relaxed);
+        self.hold_remaining.store(0, Ordering::Relaxed);
+    }
+
+    /// Measures a block, to be called by the audio thread only
+    pub(crate) fn update(&self, block: &[MusicalValue], hold_blocks: usize, decay: MusicalValue) {
+        let mut peak: MusicalValue = 0.0;
+        let mut sum_of_squares = 0.0;
+        for sample in block {
+            peak = peak.max(sample.abs());
+            sum_of_squares += sample * sample;
+        }
+        let rms = if block.is_empty() { 0.0 } else { libm::sqrtf(sum_of_squares / block.len() as MusicalValue) };
+
+        self.peak.store(peak.to_bits(), Ordering::Relaxed);
+        self.rms.store(rms.max(self.rms() * decay).to_bits(), Ordering::Relaxed);
+
+        let hold_remaining = self.hold_remaining.load(Ordering::Relaxed);
+        if peak >= self.peak_hold() {
+            self.peak_hold.store(peak.to_bits(), Ordering::Relaxed);
+            self.hold_remaining.store(hold_blocks, Ordering::Relaxed);
+        } else if hold_remaining > 0 {
+            self.hold_remaining.store(hold_remaining - 1, Ordering::Relaxed);
+        } else {
+            self.peak_hold.store(peak.max(self.peak_hold() * decay).to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// The meters of an `InstrumentGraph` with `NODES` instrument slots and `CHANNELS` output channels
+///
+/// Held peaks are kept for `hold_blocks` blocks, after which they and the RMS levels are multiplied by `decay` every block.
+pub struct GraphMeters<const NODES: usize, const CHANNELS: usize = 1usize> {
+    pub(crate) nodes: [[LevelMeter; MAX_METERED_STREAMS]; NODES],
+    pub(crate) channels: [LevelMeter; CHANNELS],
+    pub(crate) hold_blocks: usize,
+    pub(crate) decay: MusicalValue,
+}
+
+impl<const NODES: usize, const CHANNELS: usize> GraphMeters<NODES, CHANNELS> {
+    pub const fn new(hold_blocks: usize, decay: MusicalValue) -> Self {
+        Self {
+            nodes: [const { [const { LevelMeter::new() }; MAX_METERED_STREAMS] }; NODES],
+            channels: [const { LevelMeter::new() }; CHANNELS],
+            hold_blocks,
+            decay,
+        }
+    }
+
+    /// The meter of an output stream of the instrument at the given index
+    pub fn node(&self, index: usize, stream_index: usize) -> &LevelMeter {
+        if index >= NODES || stream_index >= MAX_METERED_STREAMS {
+            panic!("Metered stream index out of bounds");
+        }
+
+        &self.nodes[index][stream_index]
+    }
+
+    /// The meter of an output channel
+    pub fn channel(&self, index: usize) -> &LevelMeter {
+        if index >= CHANNELS {
+            panic!("Output channel index out of bounds");
+        }
+
+        &self.channels[index]
+    }
+
+    /// Resets every meter to silence
+    pub fn reset(&self) {
+        for meter in self.nodes.iter().flatten().chain(&self.channels) {
+            meter.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::graph::InstrumentGraph;
+    use crate::instrument::Constant;
+    use crate::MidiNote;
+
+    #[test]
+    fn measures_peak_and_rms() {
+        let meter = LevelMeter::new();
+        meter.update(&[0.5, -1.0, 0.5, 0.0], 0, 0.0);
+        assert_eq!(meter.peak(), 1.0);
+        assert_eq!(meter.peak_hold(), 1.0);
+        assert!((meter.rms() - libm::sqrtf(0.375)).abs() < 1e-6);
+
+        meter.reset();
+        assert_eq!((meter.peak(), meter.rms(), meter.peak_hold()), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn holds_the_peak_then_decays() {
+        let meter = LevelMeter::new();
+        meter.update(&[1.0; 4], 2, 0.5);
+        assert_eq!((meter.peak(), meter.rms(), meter.peak_hold()), (1.0, 1.0, 1.0));
+
+        // The RMS decays right away, the peak is held for two blocks
+        let expected = [(0.5, 1.0), (0.25, 1.0), (0.125, 0.5), (0.0625, 0.25)];
+        for (rms, peak_hold) in expected {
+            meter.update(&[0.0; 4], 2, 0.5);
+            assert_eq!((meter.peak(), meter.rms(), meter.peak_hold()), (0.0, rms, peak_hold));
+        }
+
+        // A louder peak replaces the held one
+        meter.update(&[0.75; 4], 2, 0.5);
+        assert_eq!((meter.peak(), meter.rms(), meter.peak_hold()), (0.75, 0.75, 0.75));
+    }
+
+    #[test]
+    fn meters_instruments_and_channels() {
+        let meters = GraphMeters::<2, 1>::new(0, 0.0);
+        let mut constant = container(Constant::<MidiNote>::new(-0.5));
+        let mut graph: InstrumentGraph<2, 1, 1, 1> = InstrumentGraph::new();
+        let constant = graph.add_instrument(&mut constant);
+        graph.connect_destination(0, constant, 0);
+        graph.set_meters(Some(&meters));
+        graph.process_next();
+
+        assert_eq!(meters.node(constant, 0).peak(), 0.5);
+        assert_eq!(meters.node(constant, 0).rms(), 0.5);
+        assert_eq!(meters.channel(0).peak(), 0.5);
+        assert_eq!(meters.node(1, 0).peak(), 0.0);
+    }
+}
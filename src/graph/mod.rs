@@ -0,0 +1,1094 @@
+
+#[cfg(feature = "alloc")]
+pub mod heap;
+mod dot;
+pub mod automation;
+pub mod bus;
+pub mod check;
+pub mod edit;
+mod input;
+pub mod latency;
+mod lifecycle;
+pub mod meter;
+pub mod preset;
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod parallel;
+pub mod subgraph;
+
+use self::automation::{ParameterChange, MAX_PENDING_PARAMETER_CHANGES};
+use self::bus::{Bus, MAX_BUSES};
+use self::edit::EditFade;
+use self::input::InputConnection;
+use self::latency::{feed_aligned, LatencyCompensator, MergeDelay};
+use self::meter::{GraphMeters, MAX_METERED_STREAMS};
+use self::profile::GraphProfiler;
+
+use crate::{ControlStreamSource, InstrumentContainer, MidiNote, MusicalValue, STANDARD_BLOCK_SIZE};
+
+#[derive(Debug, Clone)]
+pub(crate) struct ControlStreamConnection {
+    pub source_index: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ValueStreamConnection {
+    pub source_index: usize,
+    pub source_stream_index: usize,
+    pub destination_stream_index: usize,
+}
+
+pub(crate) struct DestinationConnection {
+    pub(crate) source_index: usize,
+    pub(crate) source_stream_index: usize,
+
+    /// Whether the connection is soloed, silencing every connection that is not
+    pub(crate) solo: bool,
+}
+
+/// How an instrument of an `InstrumentGraph` is processed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NodeMode {
+    /// The instrument is processed normally
+    Active,
+
+    /// The instrument is not processed, its input stream `input_stream_index` is passed through
+    /// to its output stream `output_stream_index` and its other outputs are silent
+    Bypassed {
+        input_stream_index: usize,
+        output_stream_index: usize,
+    },
+
+    /// The outputs of the instrument are silent, and unless `skip_processing` is set the instrument still runs
+    /// so that it resumes in the state it would have had.
+    ///
+    /// A running muted instrument keeps its output, which the graph silences wherever it reads it,
+    /// except in sends taken before the node mode.
+    Muted {
+        skip_processing: bool,
+    },
+}
+
+/// Processes the next block of an instrument in the given mode
+pub(crate) fn process_instrument<Note: Sized + Default + Copy, const BLOCK_SIZE: usize>(instrument: &mut dyn InstrumentContainer<Note, BLOCK_SIZE>, mode: NodeMode) {
+    match mode {
+        NodeMode::Active => instrument.process_next(),
+        NodeMode::Bypassed { input_stream_index, output_stream_index } => instrument.process_bypassed(input_stream_index, output_stream_index),
+        NodeMode::Muted { skip_processing: false } => instrument.process_next(),
+        NodeMode::Muted { skip_processing: true } => instrument.process_muted(false),
+    }
+}
+
+/// Sorts a dependency order by level, placing every instrument one level after its highest source, and writes
+/// the end position in `order` of each level to `level_ends`, returning the number of levels.
+///
+/// `levels` is indexed by instrument and must be zeroed.
+pub(crate) fn group_by_level<S: IntoIterator<Item = usize>>(order: &mut [usize], levels: &mut [usize], level_ends: &mut [usize], sources: impl Fn(usize) -> S) -> usize {
+    for &index in order.iter() {
+        for source_index in sources(index) {
+            levels[index] = levels[index].max(levels[source_index] + 1);
+        }
+    }
+
+    // Stable insertion sort by level keeps the order a valid dependency order
+    for i in 1..order.len() {
+        let mut j = i;
+        while j > 0 && levels[order[j - 1]] > levels[order[j]] {
+            order.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    let mut level_count = 0;
+    for i in 0..order.len() {
+        if i + 1 == order.len() || levels[order[i + 1]] != levels[order[i]] {
+            level_ends[level_count] = i + 1;
+            level_count += 1;
+        }
+    }
+    level_count
+}
+
+/// The interface shared by the instrument graph types
+/// 
+/// This allows code such as `Subgraph` to drive any graph without depending on how it stores its instruments.
+pub trait AudioGraph<Note: Sized + Default + Copy + Send = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE>: Send {
+    /// The number of output channels of the graph
+    fn output_channel_count(&self) -> usize;
+
+    /// The number of input channels of the graph, graphs without inputs have none
+    fn input_channel_count(&self) -> usize {
+        0
+    }
+
+    /// Copies the next block of an input channel from the host
+    /// 
+    /// Out of bounds channel indexes panic.
+    fn set_input(&mut self, _index: usize, _input: &[MusicalValue; BLOCK_SIZE]) {
+        panic!("Input channel index out of bounds");
+    }
+
+    /// The latency of the graph in samples, for graphs that report it
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Clears the runtime state of every instrument and of the graph, keeping its topology
+    fn reset(&mut self);
+
+    /// The number of samples the graph can keep sounding once its input falls silent, `None` if unbounded or unknown
+    fn tail_length(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether the output stays silent for as long as the input does
+    fn is_silent(&self) -> bool {
+        false
+    }
+
+    /// Gets the instrument at the given index, if the slot is occupied
+    fn instrument_mut(&mut self, index: usize) -> Option<&mut dyn InstrumentContainer<Note, BLOCK_SIZE>>;
+
+    /// Prepares every instrument for processing at the given sampling rate
+    fn prepare(&mut self, sampling_rate: usize);
+
+    /// Processes the next block of data
+    fn process_next(&mut self);
+
+    /// Gets the output channel at the given index
+    /// 
+    /// Out of bounds channel indexes may panic.
+    fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE];
+}
+
+pub struct InstrumentGraph<'a, const SIZE: usize, const CONTROL_SIZE: usize = 16usize, const CONNECTION_SIZE: usize = 16usize, const OUTPUT_CHANNELS: usize = 1usize, Note: Sized + Default + Copy + Send = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE, const INPUT_CHANNELS: usize = 0usize> {
+    /// The instruments in the graph.
+    ///
+    /// After adding, replacing or removing an instrument here directly, call `invalidate_process_order`, otherwise
+    /// the graph keeps processing in the order compiled for the previous instruments.
+    pub instruments: [Option<&'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>>; SIZE],
+
+    /// The control sources in the graph
+    pub control_sources: [Option<&'a mut dyn ControlStreamSource<Note>>; CONTROL_SIZE],
+
+    /// The connections between control sources and instruments, for each instrument
+    pub(crate) instruments_control_sources: [Option<ControlStreamConnection>; SIZE],
+
+    /// The connections between value streams, for each instrument
+    pub(crate) value_stream_connections: [[Option<ValueStreamConnection>; CONNECTION_SIZE]; SIZE],
+
+    pub(crate) destination_connections: [[Option<DestinationConnection>; CONNECTION_SIZE]; OUTPUT_CHANNELS],
+
+    /// The connections from input channels, for each instrument
+    pub(crate) input_connections: [[Option<InputConnection>; CONNECTION_SIZE]; SIZE],
+
+    /// The input channels, filled by the host before each block
+    pub(crate) input_channels: [[MusicalValue; BLOCK_SIZE]; INPUT_CHANNELS],
+
+    /// The processing mode of each instrument
+    pub(crate) node_modes: [NodeMode; SIZE],
+
+    /// The send/return buses
+    pub(crate) buses: [Option<Bus<BLOCK_SIZE>>; MAX_BUSES],
+
+    pub(crate) output_channels: [[MusicalValue; BLOCK_SIZE]; OUTPUT_CHANNELS],
+
+    /// The compiled process order, valid for the first `process_order_len` entries
+    pub(crate) process_order: [usize; SIZE],
+
+    pub(crate) process_order_len: usize,
+
+    /// The end position in `process_order` of each dependency level
+    pub(crate) process_level_ends: [usize; SIZE],
+
+    pub(crate) process_level_count: usize,
+
+    /// Set whenever the topology changes, the process order is rebuilt on the next use
+    pub(crate) process_order_dirty: bool,
+
+    /// The sampling rate the graph was prepared for, 0 if it was never prepared
+    pub(crate) sampling_rate: usize,
+
+    /// Whether `apply_edits` crossfades the output channels affected by the edits
+    pub(crate) edit_crossfade: bool,
+
+    /// The progress of the current edit crossfade
+    pub(crate) edit_fade: EditFade<OUTPUT_CHANNELS>,
+
+    /// The meters updated at the end of every block, if metering is enabled
+    pub(crate) meters: Option<&'a GraphMeters<SIZE, OUTPUT_CHANNELS>>,
+
+    /// The profile of the processing times, if profiling is enabled
+    pub(crate) profiler: Option<GraphProfiler<SIZE>>,
+
+    /// The delay lines aligning the paths to the output channels, if latency compensation is enabled
+    pub(crate) compensator: Option<&'a mut LatencyCompensator<OUTPUT_CHANNELS>>,
+
+    /// The delay lines aligning paths merging into an instrument, if enabled
+    pub(crate) merge_delays: Option<&'a mut [MergeDelay]>,
+
+    /// The latency at the output of each instrument, cached while latency compensation is enabled
+    pub(crate) latencies: [usize; SIZE],
+
+    /// The latency of each instrument itself when `latencies` was computed
+    pub(crate) own_latencies: [usize; SIZE],
+
+    /// Set whenever the topology changes, `latencies` is computed again on the next block
+    pub(crate) latencies_dirty: bool,
+
+    /// The parameter changes that are not due yet, in the order they were scheduled
+    pub(crate) pending_parameters: [ParameterChange; MAX_PENDING_PARAMETER_CHANGES],
+
+    pub(crate) pending_parameter_count: usize,
+
+    /// The number of samples processed so far
+    pub(crate) sample_position: u64,
+}
+
+//unsafe impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy> Send for InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note> {}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    pub fn new() -> Self {
+        Self {
+            instruments: core::array::from_fn(|_| None),
+            control_sources: core::array::from_fn(|_| None),
+            instruments_control_sources: core::array::from_fn(|_| None),
+            value_stream_connections: core::array::from_fn(|_| core::array::from_fn(|_| None)),
+            destination_connections: core::array::from_fn(|_| core::array::from_fn(|_| None)),
+            input_connections: core::array::from_fn(|_| core::array::from_fn(|_| None)),
+            input_channels: [[0.0; BLOCK_SIZE]; INPUT_CHANNELS],
+            node_modes: [NodeMode::Active; SIZE],
+            buses: core::array::from_fn(|_| None),
+            output_channels: [[0.0; BLOCK_SIZE]; OUTPUT_CHANNELS],
+            process_order: [usize::MAX; SIZE],
+            process_order_len: 0,
+            process_level_ends: [0; SIZE],
+            process_level_count: 0,
+            process_order_dirty: true,
+            sampling_rate: 0,
+            edit_crossfade: false,
+            edit_fade: EditFade::Idle,
+            meters: None,
+            profiler: None,
+            compensator: None,
+            merge_delays: None,
+            latencies: [0; SIZE],
+            own_latencies: [0; SIZE],
+            latencies_dirty: true,
+            pending_parameters: [ParameterChange { instrument_index: 0, id: 0, value: 0.0, time: 0 }; MAX_PENDING_PARAMETER_CHANGES],
+            pending_parameter_count: 0,
+            sample_position: 0,
+        }
+    }
+
+    pub fn add_instrument(&mut self, instrument: &'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>) -> usize {
+        if self.sampling_rate > 0 {
+            instrument.prepare(self.sampling_rate, BLOCK_SIZE);
+        }
+
+        for i in 0..SIZE {
+            if self.instruments[i].is_none() {
+                self.instruments[i] = Some(instrument);
+                self.process_order_dirty = true;
+                return i;
+            }
+        }
+        panic!("No more space for instruments");
+    }
+
+    pub fn add_control_source(&mut self, control_source: &'a mut dyn ControlStreamSource<Note>) -> usize {
+        for i in 0..CONTROL_SIZE {
+            if self.control_sources[i].is_none() {
+                self.control_sources[i] = Some(control_source);
+                return i;
+            }
+        }
+        panic!("No more space for control sources");
+    }
+
+    pub fn connect_control_source(&mut self, control_source_index: usize, instrument_index: usize) {
+        if instrument_index >= SIZE {
+            panic!("Instrument index out of bounds");
+        }
+
+        self.instruments_control_sources[instrument_index] = Some(ControlStreamConnection {
+            source_index: control_source_index,
+        });
+    }
+
+    pub fn connect_value_stream(&mut self, source_index: usize, source_stream_index: usize, destination_index: usize, destination_stream_index: usize) {
+        if source_index >= SIZE {
+            panic!("Source index out of bounds");
+        }
+
+        if destination_index >= SIZE {
+            panic!("Destination index out of bounds");
+        }
+
+        for i in 0..CONNECTION_SIZE {
+            if self.value_stream_connections[destination_index][i].is_none() {
+                self.value_stream_connections[destination_index][i] = Some(ValueStreamConnection {
+                    source_index,
+                    source_stream_index,
+                    destination_stream_index,
+                });
+                self.process_order_dirty = true;
+                return;
+            }
+        }
+        panic!("No more space for value stream connections");
+    }
+
+    pub fn connect_destination(&mut self, output_channel_index: usize, source_index: usize, source_stream_index: usize) {
+        for i in 0..CONNECTION_SIZE {
+            if self.destination_connections[output_channel_index][i].is_none() {
+                self.destination_connections[output_channel_index][i] = Some(DestinationConnection {
+                    source_index,
+                    source_stream_index,
+                    solo: false,
+                });
+                return;
+            }
+        }
+        panic!("No more space for destination connections");
+    }
+
+    /// Prepares every instrument for processing at the given sampling rate.
+    /// 
+    /// Instruments added afterwards are prepared as they are added.
+    pub fn prepare(&mut self, sampling_rate: usize) {
+        self.sampling_rate = sampling_rate;
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.prepare(sampling_rate, BLOCK_SIZE);
+        }
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.set_budget(sampling_rate, BLOCK_SIZE);
+        }
+    }
+
+    /// The sampling rate the graph was last prepared for, if any
+    pub fn sampling_rate(&self) -> Option<usize> {
+        if self.sampling_rate > 0 { Some(self.sampling_rate) } else { None }
+    }
+
+    /// Puts an instrument into the given slot, returning the instrument previously in it.
+    /// 
+    /// Connections to and from the slot are kept.
+    pub fn insert_instrument(&mut self, index: usize, instrument: &'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>) -> Option<&'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>> {
+        if index >= SIZE {
+            panic!("Instrument index out of bounds");
+        }
+
+        if self.sampling_rate > 0 {
+            instrument.prepare(self.sampling_rate, BLOCK_SIZE);
+        }
+
+        self.node_modes[index] = NodeMode::Active;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.nodes[index] = Default::default();
+        }
+        self.process_order_dirty = true;
+        self.instruments[index].replace(instrument)
+    }
+
+    /// Removes an instrument and every connection to and from it, returning the instrument.
+    pub fn remove_instrument(&mut self, index: usize) -> Option<&'a mut dyn InstrumentContainer<Note, BLOCK_SIZE>> {
+        if index >= SIZE {
+            panic!("Instrument index out of bounds");
+        }
+
+        self.instruments_control_sources[index] = None;
+        self.value_stream_connections[index] = core::array::from_fn(|_| None);
+        self.input_connections[index] = core::array::from_fn(|_| None);
+        for connections in self.value_stream_connections.iter_mut() {
+            for connection in connections.iter_mut() {
+                if connection.as_ref().is_some_and(|connection| connection.source_index == index) {
+                    *connection = None;
+                }
+            }
+        }
+        for connections in self.destination_connections.iter_mut() {
+            for connection in connections.iter_mut() {
+                if connection.as_ref().is_some_and(|connection| connection.source_index == index) {
+                    *connection = None;
+                }
+            }
+        }
+        self.remove_bus_routes(index);
+
+        self.node_modes[index] = NodeMode::Active;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.nodes[index] = Default::default();
+        }
+        self.process_order_dirty = true;
+        self.instruments[index].take()
+    }
+
+    /// Sets how the instrument at the given index is processed, the mode is reset to `Active` when the slot is replaced
+    pub fn set_node_mode(&mut self, index: usize, mode: NodeMode) {
+        if index >= SIZE {
+            panic!("Instrument index out of bounds");
+        }
+
+        if let (NodeMode::Bypassed { input_stream_index, output_stream_index }, Some(instrument)) = (mode, &self.instruments[index]) {
+            if input_stream_index >= instrument.in_value_streams() || output_stream_index >= instrument.out_value_streams() {
+                panic!("Bypass stream index out of bounds");
+            }
+        }
+
+        self.node_modes[index] = mode;
+    }
+
+    /// The processing mode of the instrument at the given index
+    pub fn node_mode(&self, index: usize) -> NodeMode {
+        self.node_modes[index]
+    }
+
+    /// Solos or unsolos the given destination connection, if it exists.
+    ///
+    /// While any destination connection is soloed, only soloed connections are mixed into the output channels.
+    pub fn set_destination_solo(&mut self, output_channel_index: usize, source_index: usize, source_stream_index: usize, solo: bool) {
+        for connection in self.destination_connections[output_channel_index].iter_mut().flatten() {
+            if connection.source_index == source_index && connection.source_stream_index == source_stream_index {
+                connection.solo = solo;
+            }
+        }
+    }
+
+    pub fn disconnect_control_source(&mut self, instrument_index: usize) {
+        if instrument_index >= SIZE {
+            panic!("Instrument index out of bounds");
+        }
+
+        self.instruments_control_sources[instrument_index] = None;
+    }
+
+    /// Removes the given value stream connection, if it exists
+    pub fn disconnect_value_stream(&mut self, source_index: usize, source_stream_index: usize, destination_index: usize, destination_stream_index: usize) {
+        if destination_index >= SIZE {
+            panic!("Destination index out of bounds");
+        }
+
+        for connection in self.value_stream_connections[destination_index].iter_mut() {
+            if connection.as_ref().is_some_and(|connection| {
+                connection.source_index == source_index
+                    && connection.source_stream_index == source_stream_index
+                    && connection.destination_stream_index == destination_stream_index
+            }) {
+                *connection = None;
+                self.process_order_dirty = true;
+                return;
+            }
+        }
+    }
+
+    /// Removes the given destination connection, if it exists
+    pub fn disconnect_destination(&mut self, output_channel_index: usize, source_index: usize, source_stream_index: usize) {
+        for connection in self.destination_connections[output_channel_index].iter_mut() {
+            if connection.as_ref().is_some_and(|connection| connection.source_index == source_index && connection.source_stream_index == source_stream_index) {
+                *connection = None;
+                return;
+            }
+        }
+    }
+
+    /// Resolving dependencies, returns the order in which instruments should be processed, in instrument indexes
+    /// 
+    /// Instruments in a dependency cycle, and the instruments depending on them, are left out and never processed,
+    /// the remaining entries are `usize::MAX`. `check_connections` reports such cycles.
+    pub fn get_instrument_process_order(&self) -> [usize; SIZE] {
+        let mut order = [usize::MAX; SIZE];
+        let mut order_index = 0;
+        let mut processed = [false; SIZE];
+
+        loop {
+            let pass_start = order_index;
+            for i in 0..SIZE {
+                if processed[i] {
+                    continue;
+                }
+
+                let mut all_processed = true;
+                for j in 0..self.value_stream_connections[i].len() {
+                    if let Some(value_stream_connection) = &self.value_stream_connections[i][j] {
+                        if !processed[value_stream_connection.source_index] {
+                            all_processed = false;
+                        }
+                    }
+                }
+                if self.bus_dependencies(i).any(|source_index| !processed[source_index]) {
+                    all_processed = false;
+                }
+
+                if all_processed {
+                    processed[i] = true;
+                    order[order_index] = i;
+                    order_index += 1;
+
+                    if order_index == SIZE {
+                        return order;
+                    }
+                }
+            }
+
+            if order_index == pass_start {
+                return order;
+            }
+        }
+    }
+
+    /// Whether the instrument at `index` depends on the one at `ancestor`, directly or through other instruments
+    pub(crate) fn depends_on(&self, index: usize, ancestor: usize) -> bool {
+        let mut visited = [false; SIZE];
+        let mut stack = [0usize; SIZE];
+        let mut stack_len = 1;
+        stack[0] = index;
+        visited[index] = true;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let current = stack[stack_len];
+            let sources = self.value_stream_connections[current].iter().flatten()
+                .map(|connection| connection.source_index)
+                .chain(self.bus_dependencies(current));
+            for source_index in sources {
+                if source_index == ancestor {
+                    return true;
+                }
+                if !visited[source_index] {
+                    visited[source_index] = true;
+                    stack[stack_len] = source_index;
+                    stack_len += 1;
+                }
+            }
+        }
+        false
+    }
+
+    /// The instrument slots in a valid dependency order, using the compiled process order when it is current
+    pub(crate) fn dependency_order(&self) -> impl Iterator<Item = usize> {
+        let mut order = [usize::MAX; SIZE];
+        if self.process_order_dirty {
+            order = self.get_instrument_process_order();
+        } else {
+            order[..self.process_order_len].copy_from_slice(&self.process_order[..self.process_order_len]);
+        }
+
+        order.into_iter().take_while(|&index| index != usize::MAX)
+    }
+
+    /// Marks the compiled process order as stale.
+    /// 
+    /// Connection and instrument changes made through the graph methods do this automatically,
+    /// it is only needed after modifying `instruments` directly.
+    pub fn invalidate_process_order(&mut self) {
+        self.process_order_dirty = true;
+    }
+
+    /// Returns the compiled process order, rebuilding it first if the topology has changed.
+    /// 
+    /// Only indexes of occupied instrument slots are included, grouped by dependency level.
+    pub fn process_order(&mut self) -> &[usize] {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        &self.process_order[..self.process_order_len]
+    }
+
+    /// Returns the number of dependency levels in the compiled process order.
+    /// 
+    /// Instruments in the same level do not depend on each other and can be processed in parallel.
+    pub fn process_level_count(&mut self) -> usize {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        self.process_level_count
+    }
+
+    /// Returns the instrument indexes in the given dependency level of the compiled process order.
+    pub fn process_level(&mut self, level: usize) -> &[usize] {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        let start = if level == 0 { 0 } else { self.process_level_ends[level - 1] };
+        &self.process_order[start..self.process_level_ends[level]]
+    }
+
+    fn rebuild_process_order(&mut self) {
+        let mut order = [0usize; SIZE];
+        let mut order_len = 0;
+        for instrument_index in self.get_instrument_process_order() {
+            if instrument_index == usize::MAX {
+                break;
+            }
+            if self.instruments[instrument_index].is_some() {
+                order[order_len] = instrument_index;
+                order_len += 1;
+            }
+        }
+
+        let mut levels = [0usize; SIZE];
+        let mut level_ends = [0usize; SIZE];
+        let level_count = group_by_level(&mut order[..order_len], &mut levels, &mut level_ends, |index| {
+            self.value_stream_connections[index].iter().flatten().map(|connection| connection.source_index).chain(self.bus_dependencies(index))
+        });
+
+        self.process_order = order;
+        self.process_order_len = order_len;
+        self.process_level_ends = level_ends;
+        self.process_level_count = level_count;
+        self.process_order_dirty = false;
+        self.latencies_dirty = true;
+    }
+
+    /// Rebuilds the process order if needed and fetches the control streams
+    fn begin_block(&mut self) {
+        if self.process_order_dirty {
+            self.rebuild_process_order();
+        }
+        self.update_latencies();
+
+        for control_source in self.control_sources.iter_mut().flatten() {
+            control_source.fetch_next_stream();
+        }
+
+        for bus in self.buses.iter_mut().flatten() {
+            bus.mixed = false;
+        }
+
+        self.dispatch_parameter_changes();
+    }
+
+    /// Feeds the value streams, input channels, buses and control streams connected to an instrument
+    ///
+    /// Each connected stream is still copied once, into the instrument's own input. Connections do not share
+    /// buffers from a pool, even with a single consumer, as instruments read their `InstrumentInput` and write their
+    /// `InstrumentOutput` rather than buffers owned by the graph.
+    fn feed_instrument(&mut self, instrument_index: usize) {
+        // The instrument is taken out of its slot while it is fed, so its sources can be borrowed without copying
+        let Some(instrument) = self.instruments[instrument_index].take() else {
+            return;
+        };
+
+        for (j, value_stream_connection) in self.value_stream_connections[instrument_index].iter().enumerate() {
+            let Some(value_stream_connection) = value_stream_connection else {
+                continue;
+            };
+
+            let source_stream = match (&self.instruments[value_stream_connection.source_index], self.node_modes[value_stream_connection.source_index]) {
+                (_, NodeMode::Muted { .. }) | (None, _) => None,
+                (Some(source_instrument), _) => Some(source_instrument.get_output(value_stream_connection.source_stream_index)),
+            };
+            feed_aligned(self.merge_delays.as_deref_mut(), &mut *instrument, instrument_index, j, value_stream_connection.destination_stream_index, source_stream);
+        }
+        self.feed_inputs(instrument_index, &mut *instrument);
+        self.feed_buses(instrument_index, &mut *instrument);
+
+        if let Some(connection) = &self.instruments_control_sources[instrument_index] {
+            if let Some(control_source) = &self.control_sources[connection.source_index] {
+                for j in 0..instrument.in_control_streams() {
+                    instrument.feed_control_stream(j, control_source.get_control_stream());
+                }
+            }
+        }
+
+        self.instruments[instrument_index] = Some(instrument);
+    }
+
+    /// Enables metering into the given meters, or disables it
+    pub fn set_meters(&mut self, meters: Option<&'a GraphMeters<SIZE, OUTPUT_CHANNELS>>) {
+        self.meters = meters;
+    }
+
+    /// Enables profiling into the given profiler, or disables it
+    pub fn set_profiler(&mut self, profiler: Option<GraphProfiler<SIZE>>) {
+        self.profiler = profiler;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.set_budget(self.sampling_rate, BLOCK_SIZE);
+        }
+    }
+
+    /// The profile of the processing times, if profiling is enabled
+    pub fn profiler(&self) -> Option<&GraphProfiler<SIZE>> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut GraphProfiler<SIZE>> {
+        self.profiler.as_mut()
+    }
+
+    /// Updates the meters, if any, with the outputs of the block
+    fn update_meters(&self) {
+        let Some(meters) = self.meters else {
+            return;
+        };
+
+        for ((instrument, node_meters), mode) in self.instruments.iter().zip(&meters.nodes).zip(&self.node_modes) {
+            if let Some(instrument) = instrument {
+                let muted = matches!(mode, NodeMode::Muted { .. });
+                for (j, meter) in node_meters.iter().enumerate().take(instrument.out_value_streams().min(MAX_METERED_STREAMS)) {
+                    meter.update(if muted { &[0.0; BLOCK_SIZE] } else { instrument.get_output(j) }, meters.hold_blocks, meters.decay);
+                }
+            }
+        }
+
+        for (channel, meter) in self.output_channels.iter().zip(&meters.channels) {
+            meter.update(channel, meters.hold_blocks, meters.decay);
+        }
+    }
+
+    /// Sums the connected instrument outputs and bus returns into the output channels, fading them around edits, and updates the meters
+    fn end_block(&mut self) {
+        self.mix_destinations();
+        self.fade_edited_channels();
+        self.sample_position += BLOCK_SIZE as u64;
+
+        self.update_meters();
+    }
+
+    /// Writes the sum of the connected instrument outputs and bus returns to each output channel,
+    /// the first source is copied rather than added
+    fn mix_destinations(&mut self) {
+        for bus_index in 0..MAX_BUSES {
+            self.mix_bus(bus_index);
+        }
+
+        if self.compensator.is_some() {
+            self.mix_compensated();
+            return;
+        }
+
+        let any_solo = self.destination_connections.iter().flatten().flatten().any(|connection| connection.solo);
+
+        for i in 0..OUTPUT_CHANNELS {
+            let mut written = false;
+            for j in 0..CONNECTION_SIZE {
+                if let Some(destination_connection) = &self.destination_connections[i][j] {
+                    if any_solo && !destination_connection.solo {
+                        continue;
+                    }
+
+                    let source_index = destination_connection.source_index;
+                    if matches!(self.node_modes[source_index], NodeMode::Muted { .. }) {
+                        continue;
+                    }
+
+                    let source_stream_index = destination_connection.source_stream_index;
+
+                    if let Some(source_instrument) = &self.instruments[source_index] {
+                        let source_stream = source_instrument.get_output(source_stream_index);
+                        if written {
+                            for k in 0..BLOCK_SIZE {
+                                self.output_channels[i][k] += source_stream[k];
+                            }
+                        } else {
+                            self.output_channels[i] = *source_stream;
+                            written = true;
+                        }
+                    }
+                }
+            }
+
+            if !self.mix_bus_returns(i, written) {
+                self.output_channels[i] = [0.0; BLOCK_SIZE];
+            }
+        }
+    }
+
+    pub fn process_next(&mut self) {
+        let block_start = self.profiler.as_ref().map(|profiler| profiler.now());
+        self.begin_block();
+
+        for i in 0..self.process_order_len {
+            let instrument_index = self.process_order[i];
+
+            self.feed_instrument(instrument_index);
+            if let Some(instrument) = &mut self.instruments[instrument_index] {
+                if let Some(profiler) = &mut self.profiler {
+                    let start = profiler.now();
+                    process_instrument(&mut **instrument, self.node_modes[instrument_index]);
+                    let duration = profiler.now().saturating_sub(start);
+                    profiler.nodes[instrument_index].record(duration);
+                } else {
+                    process_instrument(&mut **instrument, self.node_modes[instrument_index]);
+                }
+            }
+        }
+
+        self.end_block();
+        self.record_block_time(block_start);
+    }
+
+    /// Records the time since `block_start` as a block time, if profiling
+    fn record_block_time(&mut self, block_start: Option<u64>) {
+        if let (Some(profiler), Some(block_start)) = (&mut self.profiler, block_start) {
+            let duration = profiler.now().saturating_sub(block_start);
+            profiler.block.record(duration);
+        }
+    }
+
+    pub fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE] {
+        &self.output_channels[index]
+    }
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> AudioGraph<Note, BLOCK_SIZE> for InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    fn output_channel_count(&self) -> usize {
+        OUTPUT_CHANNELS
+    }
+
+    fn input_channel_count(&self) -> usize {
+        INPUT_CHANNELS
+    }
+
+    fn set_input(&mut self, index: usize, input: &[MusicalValue; BLOCK_SIZE]) {
+        InstrumentGraph::set_input(self, index, input);
+    }
+
+    fn latency(&self) -> usize {
+        InstrumentGraph::latency(self)
+    }
+
+    fn reset(&mut self) {
+        InstrumentGraph::reset(self);
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        InstrumentGraph::tail_length(self)
+    }
+
+    fn is_silent(&self) -> bool {
+        InstrumentGraph::is_silent(self)
+    }
+
+    fn instrument_mut(&mut self, index: usize) -> Option<&mut dyn InstrumentContainer<Note, BLOCK_SIZE>> {
+        match self.instruments.get_mut(index) {
+            Some(Some(instrument)) => Some(&mut **instrument),
+            _ => None,
+        }
+    }
+
+    fn prepare(&mut self, sampling_rate: usize) {
+        InstrumentGraph::prepare(self, sampling_rate);
+    }
+
+    fn process_next(&mut self) {
+        InstrumentGraph::process_next(self);
+    }
+
+    fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE] {
+        InstrumentGraph::get_output(self, index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::envelope::LinearEnvelope;
+    use crate::instrument::oscillators::SineOscillator;
+    use crate::instrument::{Amplifier, Constant, Delay, Mixer};
+    use crate::{container, container_with_block_size, NoteCommand, NoteCommandType};
+
+    #[test]
+    fn rebuilds_the_process_order_only_after_topology_changes() {
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut constant = container(Constant::<MidiNote>::new(1.0));
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let amplifier = graph.add_instrument(&mut amplifier);
+        assert!(graph.process_order_dirty);
+        graph.process_next();
+        assert!(!graph.process_order_dirty);
+
+        let constant = graph.add_instrument(&mut constant);
+        assert!(graph.process_order_dirty);
+        assert_eq!(graph.process_order(), &[amplifier, constant]);
+        assert!(!graph.process_order_dirty);
+
+        graph.connect_value_stream(constant, 0, amplifier, 0);
+        assert!(graph.process_order_dirty);
+        assert_eq!(graph.process_order(), &[constant, amplifier]);
+
+        // Processing, parameters and node modes keep the compiled order
+        graph.process_next();
+        graph.set_parameter(amplifier, 0, 0.5);
+        graph.set_node_mode(amplifier, NodeMode::Muted { skip_processing: false });
+        graph.process_next();
+        assert!(!graph.process_order_dirty);
+
+        graph.disconnect_value_stream(constant, 0, amplifier, 0);
+        assert!(graph.process_order_dirty);
+        assert_eq!(graph.process_order(), &[amplifier, constant]);
+
+        graph.remove_instrument(constant);
+        assert!(graph.process_order_dirty);
+        assert_eq!(graph.process_order(), &[amplifier]);
+    }
+
+    #[test]
+    fn bypass_passes_the_chosen_stream_through() {
+        let mut signal = container(Constant::<MidiNote>::new(0.25));
+        let mut gain = container(Constant::<MidiNote>::new(3.0));
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let signal = graph.add_instrument(&mut signal);
+        let gain = graph.add_instrument(&mut gain);
+        let amplifier = graph.add_instrument(&mut amplifier);
+        graph.connect_value_stream(signal, 0, amplifier, 0);
+        graph.connect_value_stream(gain, 0, amplifier, 1);
+        graph.connect_destination(0, amplifier, 0);
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 0.75);
+
+        graph.set_node_mode(amplifier, NodeMode::Bypassed { input_stream_index: 1, output_stream_index: 0 });
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[3.0; 128]);
+
+        graph.set_node_mode(amplifier, NodeMode::Bypassed { input_stream_index: 0, output_stream_index: 0 });
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.25; 128]);
+    }
+
+    #[test]
+    fn muted_instruments_are_silent_wherever_they_are_read() {
+        let mut signal = container(Constant::<MidiNote>::new(0.5));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 2> = InstrumentGraph::new();
+        let signal = graph.add_instrument(&mut signal);
+        let mixer = graph.add_instrument(&mut mixer);
+        graph.connect_value_stream(signal, 0, mixer, 0);
+        graph.connect_destination(0, signal, 0);
+        graph.connect_destination(1, mixer, 0);
+
+        // A running muted instrument keeps its output, only the graph silences it
+        graph.set_node_mode(signal, NodeMode::Muted { skip_processing: false });
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+        assert_eq!(graph.get_output(1), &[0.0; 128]);
+        assert_eq!(graph.instruments[signal].as_ref().unwrap().get_output(0)[0], 0.5);
+
+        graph.set_node_mode(signal, NodeMode::Muted { skip_processing: true });
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+        assert_eq!(graph.get_output(1), &[0.0; 128]);
+        assert_eq!(graph.instruments[signal].as_ref().unwrap().get_output(0)[0], 0.0);
+
+        graph.set_node_mode(signal, NodeMode::Active);
+        graph.process_next();
+        assert_eq!(graph.get_output(0)[0], 0.5);
+        assert_eq!(graph.get_output(1)[0], 0.5);
+    }
+
+    #[test]
+    fn soloed_connections_silence_every_other_connection() {
+        let mut first = container(Constant::<MidiNote>::new(1.0));
+        let mut second = container(Constant::<MidiNote>::new(2.0));
+        let mut third = container(Constant::<MidiNote>::new(4.0));
+        let mut graph: InstrumentGraph<4, 1, 2, 2> = InstrumentGraph::new();
+        let first = graph.add_instrument(&mut first);
+        let second = graph.add_instrument(&mut second);
+        let third = graph.add_instrument(&mut third);
+        graph.connect_destination(0, first, 0);
+        graph.connect_destination(0, second, 0);
+        graph.connect_destination(1, third, 0);
+        graph.process_next();
+        assert_eq!((graph.get_output(0)[0], graph.get_output(1)[0]), (3.0, 4.0));
+
+        // Solos apply across every output channel
+        graph.set_destination_solo(0, first, 0, true);
+        graph.process_next();
+        assert_eq!((graph.get_output(0)[0], graph.get_output(1)[0]), (1.0, 0.0));
+
+        graph.set_destination_solo(1, third, 0, true);
+        graph.process_next();
+        assert_eq!((graph.get_output(0)[0], graph.get_output(1)[0]), (1.0, 4.0));
+
+        graph.set_destination_solo(0, first, 0, false);
+        graph.set_destination_solo(1, third, 0, false);
+        graph.process_next();
+        assert_eq!((graph.get_output(0)[0], graph.get_output(1)[0]), (3.0, 4.0));
+    }
+    /// Plays note 60 at full velocity in every block
+    struct NoteOn {
+        stream: [NoteCommand<MidiNote>; 1],
+    }
+
+    impl ControlStreamSource<MidiNote> for NoteOn {
+        fn get_control_stream(&self) -> &[NoteCommand<MidiNote>] {
+            &self.stream
+        }
+
+        fn fetch_next_stream(&mut self) {}
+    }
+
+    #[test]
+    fn prepare_reaches_every_instrument() {
+        let mut note_on = NoteOn { stream: [NoteCommand { command_type: NoteCommandType::NoteOn, velocity: 255, note: 60 }] };
+        let mut frequency = container(Constant::<MidiNote>::new(250.0));
+        let mut sine = container(SineOscillator::<MidiNote>::new(48000));
+        let mut envelope = container(LinearEnvelope::<1, MidiNote>::new_seconds([0.01], [1.0], 0.02));
+        let mut graph: InstrumentGraph<3, 1, 1, 2> = InstrumentGraph::new();
+        let control = graph.add_control_source(&mut note_on);
+        let frequency = graph.add_instrument(&mut frequency);
+        let sine = graph.add_instrument(&mut sine);
+        graph.connect_value_stream(frequency, 0, sine, 0);
+        graph.connect_destination(0, sine, 0);
+        graph.prepare(1000);
+        assert_eq!(graph.sampling_rate(), Some(1000));
+
+        // Instruments added later are prepared as they are added
+        let envelope = graph.add_instrument(&mut envelope);
+        graph.connect_control_source(control, envelope);
+        graph.connect_destination(1, envelope, 0);
+        graph.process_next();
+
+        // A quarter cycle per sample at 1000 Hz
+        assert!((graph.get_output(0)[0] - 1.0).abs() < 1e-6);
+        assert!(graph.get_output(0)[1].abs() < 1e-6);
+
+        // The attack lasts 10 samples at 1000 Hz
+        assert_eq!(graph.get_output(1)[5], 0.5);
+        assert_eq!(graph.get_output(1)[10], 1.0);
+    }
+
+    #[test]
+    fn processes_blocks_of_any_size() {
+        let mut delay = container_with_block_size::<32, _, 1, 0, 1, MidiNote>(Delay::new(40));
+        let mut graph: InstrumentGraph<1, 1, 1, 1, MidiNote, 32, 1> = InstrumentGraph::new();
+        let delay = graph.add_instrument(&mut delay);
+        graph.connect_input(0, delay, 0);
+        graph.connect_destination(0, delay, 0);
+
+        let mut impulse = [0.0; 32];
+        impulse[0] = 1.0;
+        graph.set_input(0, &impulse);
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 32]);
+
+        // The impulse crosses into the second block
+        graph.set_input(0, &[0.0; 32]);
+        graph.process_next();
+        let mut expected = [0.0; 32];
+        expected[8] = 1.0;
+        assert_eq!(graph.get_output(0), &expected);
+    }
+    #[test]
+    fn disconnected_inputs_are_cleared() {
+        let mut constant = container(Constant::<MidiNote>::new(0.5));
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut graph: InstrumentGraph<2, 1, 1, 1> = InstrumentGraph::new();
+        let constant = graph.add_instrument(&mut constant);
+        let mixer = graph.add_instrument(&mut mixer);
+        graph.connect_value_stream(constant, 0, mixer, 0);
+        graph.connect_destination(0, mixer, 0);
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.5; 128]);
+
+        // The input is no longer fed, so the block left over from the last one is zeroed instead of held
+        graph.disconnect_value_stream(constant, 0, mixer, 0);
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+        graph.process_next();
+        assert_eq!(graph.get_output(0), &[0.0; 128]);
+    }
+}
@@ -0,0 +1,440 @@
+//! Parallel graph execution by dependency level.
+//!
+//! The instruments of one dependency level do not read each other's output,
+//! so after their inputs are fed on the calling thread they can be processed on a `WorkerPool` in any order.
+//! The output is identical to the serial `process_next`.
+
+use core::any::Any;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use crate::InstrumentContainer;
+
+use super::heap::HeapInstrumentGraph;
+use super::{process_instrument, InstrumentGraph};
+
+/// How many times an idle worker polls for work before parking
+const SPIN_LIMIT: usize = 4096;
+
+/// The tasks of one `run` call
+struct Job<'f> {
+    task: &'f (dyn Fn(usize) + Sync),
+}
+
+struct PoolShared {
+    /// The current batch: epoch in the upper 32 bits, task count in the next 16 bits and next task index in the lower 16 bits
+    tasks: AtomicU64,
+
+    /// The `Job` of the current batch, valid while the batch has unfinished tasks
+    job: AtomicPtr<()>,
+
+    /// The number of finished tasks in the current batch
+    done: AtomicUsize,
+
+    /// The number of workers that are parked or about to park
+    sleeping: AtomicUsize,
+
+    shutdown: AtomicBool,
+
+    /// The first panic of a task in the current batch, raised again once every task has finished
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl PoolShared {
+    /// Claims the next task index of the given batch
+    fn claim(&self, epoch: u32) -> Option<usize> {
+        let mut current = self.tasks.load(Ordering::Acquire);
+        loop {
+            let count = (current >> 16) & 0xffff;
+            let index = current & 0xffff;
+            if (current >> 32) as u32 != epoch || index >= count {
+                return None;
+            }
+
+            match self.tasks.compare_exchange_weak(current, current + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(index as usize),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Runs a claimed task of the current batch
+    ///
+    /// # Safety
+    ///
+    /// The index must have been claimed from the current batch, which keeps the job alive until it is marked done.
+    unsafe fn run_task(&self, index: usize) {
+        let job = &*(self.job.load(Ordering::Acquire) as *const Job<'static>);
+
+        // A panicking task is still marked done, so `run` does not return or unwind while other tasks use the job
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| (job.task)(index))) {
+            self.panic.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert(payload);
+        }
+        self.done.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// A pool of worker threads processing graph levels in parallel
+///
+/// Running a batch does not allocate or lock unless a task panics: idle workers spin for a while and then park,
+/// and are woken by the thread submitting the next batch.
+pub struct WorkerPool {
+    shared: Arc<PoolShared>,
+    workers: Vec<JoinHandle<()>>,
+    epoch: u32,
+}
+
+impl WorkerPool {
+    /// Creates a pool with the given number of worker threads, in addition to the thread calling `run`
+    pub fn new(threads: usize) -> Self {
+        let shared = Arc::new(PoolShared {
+            tasks: AtomicU64::new(0),
+            job: AtomicPtr::new(core::ptr::null_mut()),
+            done: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            panic: Mutex::new(None),
+        });
+
+        let workers = (0..threads).map(|i| {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("linstr-worker-{}", i))
+                .spawn(move || Self::worker(&shared))
+                .expect("Failed to spawn worker thread")
+        }).collect();
+
+        Self {
+            shared,
+            workers,
+            epoch: 0,
+        }
+    }
+
+    /// The number of worker threads
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    fn worker(shared: &PoolShared) {
+        let mut epoch = 0u32;
+        loop {
+            let mut spins = 0;
+            loop {
+                if shared.shutdown.load(Ordering::Acquire) {
+                    return;
+                }
+
+                let current = (shared.tasks.load(Ordering::Acquire) >> 32) as u32;
+                if current != epoch {
+                    epoch = current;
+                    break;
+                }
+
+                if spins < SPIN_LIMIT {
+                    spins += 1;
+                    spin_loop();
+                } else {
+                    shared.sleeping.fetch_add(1, Ordering::SeqCst);
+                    if (shared.tasks.load(Ordering::SeqCst) >> 32) as u32 == epoch && !shared.shutdown.load(Ordering::SeqCst) {
+                        thread::park();
+                    }
+                    shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+
+            while let Some(index) = shared.claim(epoch) {
+                unsafe {
+                    shared.run_task(index);
+                }
+            }
+        }
+    }
+
+    /// Calls `task` once for every index in `0..count`, spread over the workers and the calling thread,
+    /// and returns when all calls have finished
+    ///
+    /// If a call panics, the panic is raised again on the calling thread once all calls have finished.
+    pub fn run<F: Fn(usize) + Sync>(&mut self, count: usize, task: &F) {
+        if count > 0xffff {
+            panic!("Too many tasks for the worker pool");
+        }
+
+        if count <= 1 || self.workers.is_empty() {
+            for i in 0..count {
+                task(i);
+            }
+            return;
+        }
+
+        let job = Job { task };
+        self.shared.job.store(&job as *const Job as *mut (), Ordering::Release);
+        self.shared.done.store(0, Ordering::Relaxed);
+        self.epoch = self.epoch.wrapping_add(1);
+        self.shared.tasks.store((self.epoch as u64) << 32 | (count as u64) << 16, Ordering::SeqCst);
+
+        if self.shared.sleeping.load(Ordering::SeqCst) > 0 {
+            for worker in &self.workers {
+                worker.thread().unpark();
+            }
+        }
+
+        while let Some(index) = self.shared.claim(self.epoch) {
+            unsafe {
+                self.shared.run_task(index);
+            }
+        }
+
+        // The job must outlive every task that was claimed by a worker
+        while self.shared.done.load(Ordering::Acquire) < count {
+            spin_loop();
+        }
+
+        if let Some(payload) = self.shared.panic.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        for worker in &self.workers {
+            worker.thread().unpark();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// A pointer to the instrument slots of a graph, shared with the tasks of one level
+struct Slots<T>(*mut T);
+
+impl<T> Slots<T> {
+    /// # Safety
+    ///
+    /// Each index may only be borrowed by one task at a time.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get(&self, index: usize) -> &mut T {
+        &mut *self.0.add(index)
+    }
+}
+
+// Every task borrows a distinct slot, and the instruments in the slots are `Send`
+unsafe impl<T: Send> Sync for Slots<T> {}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Processes the next block, running the instruments of each dependency level in parallel on the pool.
+    ///
+    /// The output is identical to `process_next`.
+    pub fn process_next_parallel(&mut self, pool: &mut WorkerPool) {
+        let block_start = self.profiler.as_ref().map(|profiler| profiler.now());
+        self.begin_block();
+
+        let mut start = 0;
+        for level in 0..self.process_level_count {
+            let end = self.process_level_ends[level];
+            for i in start..end {
+                self.feed_instrument(self.process_order[i]);
+            }
+
+            let slots = Slots(self.instruments.as_mut_ptr());
+            let clock = self.profiler.as_ref().map(|profiler| profiler.clock);
+            let stats = self.profiler.as_mut().map(|profiler| Slots(profiler.nodes.as_mut_ptr()));
+            let level_order = &self.process_order[start..end];
+            let node_modes = &self.node_modes;
+            pool.run(level_order.len(), &|i| {
+                // The indexes in a level are distinct, so every instrument is borrowed by one task
+                let slot: &mut Option<&mut dyn InstrumentContainer<Note, BLOCK_SIZE>> = unsafe { slots.get(level_order[i]) };
+                if let Some(instrument) = slot {
+                    if let (Some(clock), Some(stats)) = (clock, &stats) {
+                        let start = clock();
+                        process_instrument(&mut **instrument, node_modes[level_order[i]]);
+                        unsafe { stats.get(level_order[i]) }.record(clock().saturating_sub(start));
+                    } else {
+                        process_instrument(&mut **instrument, node_modes[level_order[i]]);
+                    }
+                }
+            });
+
+            start = end;
+        }
+
+        self.end_block();
+        self.record_block_time(block_start);
+    }
+}
+
+impl<Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize> HeapInstrumentGraph<Note, BLOCK_SIZE> {
+    /// Processes the next block, running the instruments of each dependency level in parallel on the pool.
+    ///
+    /// The output is identical to `process_next`. Heap graphs have no node modes, see `HeapInstrumentGraph`.
+    pub fn process_next_parallel(&mut self, pool: &mut WorkerPool) {
+        self.begin_block();
+
+        let mut start = 0;
+        for level in 0..self.process_level_ends.len() {
+            let end = self.process_level_ends[level];
+            for i in start..end {
+                self.feed_instrument(self.process_order[i]);
+            }
+
+            let slots = Slots(self.instruments.as_mut_ptr());
+            let level_order = &self.process_order[start..end];
+            pool.run(level_order.len(), &|i| {
+                // The indexes in a level are distinct, so every instrument is borrowed by one task
+                let slot: &mut Option<Box<dyn InstrumentContainer<Note, BLOCK_SIZE>>> = unsafe { slots.get(level_order[i]) };
+                if let Some(instrument) = slot {
+                    instrument.process_next();
+                }
+            });
+
+            start = end;
+        }
+
+        self.mix_destinations();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::oscillators::SineOscillator;
+    use crate::instrument::{Amplifier, Constant, Mixer};
+    use crate::{container, MidiNote, MusicalValue};
+    use alloc::vec;
+
+    /// Two oscillators through amplifiers into a mixer, in four dependency levels
+    fn instruments() -> Vec<Box<dyn InstrumentContainer<MidiNote>>> {
+        vec![
+            Box::new(container(Constant::<MidiNote>::new(440.0))),
+            Box::new(container(Constant::<MidiNote>::new(220.0))),
+            Box::new(container(Constant::<MidiNote>::new(0.5))),
+            Box::new(container(SineOscillator::<MidiNote>::new(48000))),
+            Box::new(container(SineOscillator::<MidiNote>::new(48000))),
+            Box::new(container(Amplifier::<MidiNote>::new())),
+            Box::new(container(Amplifier::<MidiNote>::new())),
+            Box::new(container(Mixer::<2, MidiNote>::new())),
+        ]
+    }
+
+    /// The value stream connections, as source index, source stream, destination index and destination stream
+    const CONNECTIONS: [(usize, usize, usize, usize); 8] = [
+        (0, 0, 3, 0),
+        (1, 0, 4, 0),
+        (3, 0, 5, 0),
+        (2, 0, 5, 1),
+        (4, 0, 6, 0),
+        (2, 0, 6, 1),
+        (5, 0, 7, 0),
+        (6, 0, 7, 1),
+    ];
+
+    /// The output channel connections, as channel and source index
+    const DESTINATIONS: [(usize, usize); 2] = [(0, 7), (1, 6)];
+
+    const BLOCKS: usize = 8;
+
+    fn render(mut process: impl FnMut() -> [[MusicalValue; 128]; 2]) -> Vec<[[MusicalValue; 128]; 2]> {
+        (0..BLOCKS).map(|_| process()).collect()
+    }
+
+    #[test]
+    fn parallel_output_is_identical() {
+        let mut pool = WorkerPool::new(3);
+
+        let mut serial_instruments = instruments();
+        let mut parallel_instruments = instruments();
+        let mut serial: InstrumentGraph<8, 1, 4, 2> = InstrumentGraph::new();
+        let mut parallel: InstrumentGraph<8, 1, 4, 2> = InstrumentGraph::new();
+        for (serial_instrument, parallel_instrument) in serial_instruments.iter_mut().zip(&mut parallel_instruments) {
+            serial.add_instrument(&mut **serial_instrument);
+            parallel.add_instrument(&mut **parallel_instrument);
+        }
+        for graph in [&mut serial, &mut parallel] {
+            for (source, source_stream, destination, destination_stream) in CONNECTIONS {
+                graph.connect_value_stream(source, source_stream, destination, destination_stream);
+            }
+            for (channel, source) in DESTINATIONS {
+                graph.connect_destination(channel, source, 0);
+            }
+            graph.prepare(48000);
+        }
+        assert_eq!(serial.process_level_count(), 4);
+
+        let expected = render(|| {
+            serial.process_next();
+            [*serial.get_output(0), *serial.get_output(1)]
+        });
+        let actual = render(|| {
+            parallel.process_next_parallel(&mut pool);
+            [*parallel.get_output(0), *parallel.get_output(1)]
+        });
+        assert_eq!(expected, actual);
+        assert!(expected.iter().flatten().flatten().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn heap_parallel_output_is_identical() {
+        let mut pool = WorkerPool::new(3);
+
+        let mut graphs: Vec<HeapInstrumentGraph> = (0..2).map(|_| {
+            let mut graph = HeapInstrumentGraph::new(2);
+            for instrument in instruments() {
+                graph.add_instrument(instrument);
+            }
+            for (source, source_stream, destination, destination_stream) in CONNECTIONS {
+                graph.connect_value_stream(source, source_stream, destination, destination_stream);
+            }
+            for (channel, source) in DESTINATIONS {
+                graph.connect_destination(channel, source, 0);
+            }
+            graph.prepare(48000);
+            graph
+        }).collect();
+        let (serial, parallel) = graphs.split_at_mut(1);
+        let (serial, parallel) = (&mut serial[0], &mut parallel[0]);
+
+        let expected = render(|| {
+            serial.process_next();
+            [*serial.get_output(0), *serial.get_output(1)]
+        });
+        let actual = render(|| {
+            parallel.process_next_parallel(&mut pool);
+            [*parallel.get_output(0), *parallel.get_output(1)]
+        });
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn panics_after_every_task_finished() {
+        let mut pool = WorkerPool::new(3);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.run(64, &|i| {
+                if i == 0 {
+                    panic!("task failed");
+                }
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }));
+        assert!(result.is_err());
+        assert_eq!(finished.load(Ordering::SeqCst), 63);
+
+        // The pool keeps working after a panic
+        let count = AtomicUsize::new(0);
+        pool.run(16, &|_| {
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 16);
+    }
+}
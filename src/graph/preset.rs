@@ -0,0 +1,203 @@
+//! Saving and restoring graph presets, see `crate::preset` for the format.
+
+use crate::preset::{PresetError, PresetReader, PresetWriter, PRESET_FLAG_STATE, PRESET_MAGIC, PRESET_VERSION};
+use crate::InstrumentContainer;
+
+use super::InstrumentGraph;
+
+/// The stream counts an instrument is recognized by when a preset is loaded
+fn signature<Note: Sized + Default + Copy, const BLOCK_SIZE: usize>(instrument: &dyn InstrumentContainer<Note, BLOCK_SIZE>) -> [u8; 3] {
+    [
+        instrument.in_value_streams().min(u8::MAX as usize) as u8,
+        instrument.in_control_streams().min(u8::MAX as usize) as u8,
+        instrument.out_value_streams().min(u8::MAX as usize) as u8,
+    ]
+}
+
+impl<'a, const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize, const INPUT_CHANNELS: usize> InstrumentGraph<'a, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE, INPUT_CHANNELS> {
+    /// Writes the parameters of every instrument, and their runtime state if `include_state` is set,
+    /// returning the number of bytes written
+    pub fn save_preset(&self, buffer: &mut [u8], include_state: bool) -> Result<usize, PresetError> {
+        let mut writer = PresetWriter::new(buffer);
+        writer.write_bytes(&PRESET_MAGIC)?;
+        writer.write_u16(PRESET_VERSION)?;
+        writer.write_u8(if include_state { PRESET_FLAG_STATE } else { 0 })?;
+        writer.write_u32(self.instruments.iter().flatten().count() as u32)?;
+        if include_state {
+            writer.write_u64(self.sample_position)?;
+        }
+
+        for (index, instrument) in self.instruments.iter().enumerate() {
+            let Some(instrument) = instrument else {
+                continue;
+            };
+
+            writer.write_u32(index as u32)?;
+            writer.write_bytes(&signature(&**instrument))?;
+
+            writer.write_u32(instrument.parameter_count() as u32)?;
+            for id in 0..instrument.parameter_count() {
+                writer.write_u32(id as u32)?;
+                writer.write_f32(instrument.parameter(id).unwrap_or_default())?;
+            }
+
+            let length_position = writer.position();
+            writer.write_u32(0)?;
+            if include_state {
+                instrument.save_state(&mut writer)?;
+                let length = writer.position() - length_position - 4;
+                writer.patch_u32(length_position, length as u32);
+            }
+        }
+
+        Ok(writer.position())
+    }
+
+    /// Restores a preset written by `save_preset`.
+    ///
+    /// The whole preset is checked before anything is changed: its layout, the instruments it was saved from,
+    /// the parameter ids and values, and the runtime state of each instrument through `Instrument::check_state`.
+    /// A failing preset leaves the graph unchanged, as long as `load_state` accepts every state `check_state` does.
+    /// Parameters are set immediately, so smoothed parameters still glide to their new values unless runtime state is restored as well.
+    pub fn load_preset(&mut self, data: &[u8]) -> Result<(), PresetError> {
+        self.read_preset(data, false)?;
+        self.read_preset(data, true)
+    }
+
+    /// Reads a preset, only checking it unless `apply` is set
+    fn read_preset(&mut self, data: &[u8], apply: bool) -> Result<(), PresetError> {
+        let mut reader = PresetReader::new(data);
+        if reader.read_bytes(4)? != PRESET_MAGIC {
+            return Err(PresetError::InvalidFormat);
+        }
+
+        let version = reader.read_u16()?;
+        if version == 0 || version > PRESET_VERSION {
+            return Err(PresetError::UnsupportedVersion(version));
+        }
+
+        let flags = reader.read_u8()?;
+        let count = reader.read_u32()?;
+        if flags & PRESET_FLAG_STATE != 0 {
+            let sample_position = reader.read_u64()?;
+            if apply {
+                self.sample_position = sample_position;
+            }
+        }
+
+        for _ in 0..count {
+            let index = reader.read_u32()? as usize;
+            let saved_signature = reader.read_bytes(3)?;
+            let instrument = match self.instruments.get_mut(index) {
+                Some(Some(instrument)) if signature(&**instrument) == saved_signature => instrument,
+                _ => return Err(PresetError::InstrumentMismatch(index)),
+            };
+
+            for _ in 0..reader.read_u32()? {
+                let id = reader.read_u32()? as usize;
+                let value = reader.read_f32()?;
+                if id >= instrument.parameter_count() || !value.is_finite() {
+                    return Err(PresetError::InvalidFormat);
+                }
+                if apply {
+                    instrument.set_parameter(id, value);
+                }
+            }
+
+            let length = reader.read_u32()? as usize;
+            let state = reader.read_bytes(length)?;
+            if length > 0 {
+                if apply {
+                    instrument.load_state(&mut PresetReader::new(state))?;
+                } else {
+                    instrument.check_state(&mut PresetReader::new(state))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::instrument::oscillators::SineOscillator;
+    use crate::instrument::{Amplifier, Constant};
+    use crate::MidiNote;
+
+    #[test]
+    fn round_trip_resumes_identically() {
+        let mut frequency = container(Constant::<MidiNote>::new(440.0));
+        let mut oscillator = container(SineOscillator::<MidiNote>::new(48000));
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let frequency = graph.add_instrument(&mut frequency);
+        let oscillator = graph.add_instrument(&mut oscillator);
+        graph.connect_value_stream(frequency, 0, oscillator, 0);
+        graph.connect_destination(0, oscillator, 0);
+        graph.prepare(48000);
+        graph.process_next();
+
+        let mut buffer = [0u8; 256];
+        let length = graph.save_preset(&mut buffer, true).unwrap();
+        graph.process_next();
+        let expected = *graph.get_output(0);
+
+        graph.set_parameter(frequency, 0, 100.0);
+        graph.process_next();
+        graph.load_preset(&buffer[..length]).unwrap();
+        assert_eq!(graph.parameter(frequency, 0), Some(440.0));
+        graph.process_next();
+        assert_eq!(*graph.get_output(0), expected);
+    }
+
+    #[test]
+    fn corrupt_preset_changes_nothing() {
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut oscillator = container(SineOscillator::<MidiNote>::new(48000));
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        let amplifier = graph.add_instrument(&mut amplifier);
+        graph.add_instrument(&mut oscillator);
+        graph.set_parameter(amplifier, 0, 0.5);
+        graph.process_next();
+
+        let mut buffer = [0u8; 256];
+        let length = graph.save_preset(&mut buffer, true).unwrap();
+        graph.set_parameter(amplifier, 0, 0.25);
+        graph.process_next();
+
+        // The oscillator phase is the last value of the preset
+        let mut corrupt = buffer;
+        corrupt[length - 4..length].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert_eq!(graph.load_preset(&corrupt[..length]), Err(PresetError::InvalidFormat));
+        corrupt[length - 4..length].copy_from_slice(&1.5f32.to_le_bytes());
+        assert_eq!(graph.load_preset(&corrupt[..length]), Err(PresetError::InvalidFormat));
+        assert_eq!(graph.parameter(amplifier, 0), Some(0.25));
+
+        assert_eq!(graph.load_preset(&buffer[..length - 1]), Err(PresetError::InvalidFormat));
+        let mut wrong_version = buffer;
+        wrong_version[4] = 9;
+        assert_eq!(graph.load_preset(&wrong_version[..length]), Err(PresetError::UnsupportedVersion(9)));
+        wrong_version[4] = 0;
+        assert_eq!(graph.load_preset(&wrong_version[..length]), Err(PresetError::UnsupportedVersion(0)));
+        assert_eq!(graph.parameter(amplifier, 0), Some(0.25));
+
+        graph.load_preset(&buffer[..length]).unwrap();
+        assert_eq!(graph.parameter(amplifier, 0), Some(0.5));
+    }
+
+    #[test]
+    fn rejects_unknown_parameters() {
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut graph: InstrumentGraph<4, 1, 2, 1> = InstrumentGraph::new();
+        graph.add_instrument(&mut amplifier);
+
+        let mut buffer = [0u8; 64];
+        let length = graph.save_preset(&mut buffer, false).unwrap();
+        // Header, then index, signature and parameter count before the first parameter id
+        let id_position = 11 + 4 + 3 + 4;
+        buffer[id_position] = 5;
+        assert_eq!(graph.load_preset(&buffer[..length]), Err(PresetError::InvalidFormat));
+    }
+}
@@ -0,0 +1,179 @@
+//! DSP load profiling.
+//!
+//! A profiler times every instrument and every whole block with a user-supplied clock, so it
+//! works without `std`. Times are compared to the real-time budget of a block, which is known
+//! once the graph is prepared.
+
+/// A monotonic clock returning the current time in nanoseconds
+pub type Clock = fn() -> u64;
+
+/// Aggregated durations of a repeated measurement, in nanoseconds
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ProfileStats {
+    /// The number of measurements
+    pub count: u64,
+
+    pub total: u64,
+
+    /// The shortest duration, 0 if nothing was measured
+    pub min: u64,
+
+    pub max: u64,
+
+    /// The most recent duration
+    pub last: u64,
+}
+
+impl ProfileStats {
+    pub(crate) fn record(&mut self, duration: u64) {
+        self.min = if self.count == 0 { duration } else { self.min.min(duration) };
+        self.max = self.max.max(duration);
+        self.total += duration;
+        self.last = duration;
+        self.count += 1;
+    }
+
+    /// The average duration, 0 if nothing was measured
+    pub fn average(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+/// A duration as a share of the real-time budget of a block, 1.0 being the whole budget
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Load {
+    pub average: f32,
+    pub max: f32,
+}
+
+/// The profile of a graph with `NODES` instrument slots
+#[derive(Debug, Clone)]
+pub struct GraphProfiler<const NODES: usize> {
+    pub(crate) clock: Clock,
+    pub(crate) nodes: [ProfileStats; NODES],
+    pub(crate) block: ProfileStats,
+
+    /// The duration of a block in nanoseconds, 0 if the graph was never prepared
+    pub(crate) budget: u64,
+}
+
+impl<const NODES: usize> GraphProfiler<NODES> {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            nodes: [ProfileStats::default(); NODES],
+            block: ProfileStats::default(),
+            budget: 0,
+        }
+    }
+
+    /// The processing times of the instrument at the given index
+    pub fn node(&self, index: usize) -> &ProfileStats {
+        if index >= NODES {
+            panic!("Instrument index out of bounds");
+        }
+
+        &self.nodes[index]
+    }
+
+    /// The processing times of whole blocks, including routing and mixing
+    pub fn block(&self) -> &ProfileStats {
+        &self.block
+    }
+
+    /// The real-time budget of a block in nanoseconds, if the graph was prepared
+    pub fn budget(&self) -> Option<u64> {
+        if self.budget > 0 { Some(self.budget) } else { None }
+    }
+
+    /// The load of the instrument at the given index, if the graph was prepared
+    pub fn node_load(&self, index: usize) -> Option<Load> {
+        self.load(self.node(index))
+    }
+
+    /// The load of whole blocks, if the graph was prepared
+    pub fn block_load(&self) -> Option<Load> {
+        self.load(&self.block)
+    }
+
+    /// The index of the instrument with the highest maximum processing time, if any was measured
+    pub fn heaviest_node(&self) -> Option<usize> {
+        self.nodes.iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.count > 0)
+            .max_by_key(|(_, stats)| stats.max)
+            .map(|(index, _)| index)
+    }
+
+    /// Clears every measurement
+    pub fn reset(&mut self) {
+        self.nodes = [ProfileStats::default(); NODES];
+        self.block = ProfileStats::default();
+    }
+
+    fn load(&self, stats: &ProfileStats) -> Option<Load> {
+        let budget = self.budget()? as f32;
+        Some(Load {
+            average: stats.average() as f32 / budget,
+            max: stats.max as f32 / budget,
+        })
+    }
+
+    pub(crate) fn set_budget(&mut self, sampling_rate: usize, block_size: usize) {
+        self.budget = if sampling_rate > 0 { block_size as u64 * 1_000_000_000 / sampling_rate as u64 } else { 0 };
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        (self.clock)()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::container;
+    use crate::graph::InstrumentGraph;
+    use crate::instrument::Constant;
+    use crate::MidiNote;
+
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+
+    /// A clock whose n-th reading is 100 n², so that later durations are longer
+    fn fake_clock() -> u64 {
+        let call = CALLS.fetch_add(1, Ordering::Relaxed);
+        100 * call * call
+    }
+
+    #[test]
+    fn accounts_every_node_and_block() {
+        let mut first = container(Constant::<MidiNote>::new(1.0));
+        let mut second = container(Constant::<MidiNote>::new(1.0));
+        let mut graph: InstrumentGraph<3, 1, 1, 1> = InstrumentGraph::new();
+        let first = graph.add_instrument(&mut first);
+        let second = graph.add_instrument(&mut second);
+        graph.set_profiler(Some(GraphProfiler::new(fake_clock)));
+        assert_eq!(graph.profiler().unwrap().block_load(), None);
+
+        // Each block reads the clock at its start, around each instrument and at its end
+        graph.process_next();
+        graph.process_next();
+
+        let profiler = graph.profiler().unwrap();
+        assert_eq!(*profiler.node(first), ProfileStats { count: 2, total: 1800, min: 300, max: 1500, last: 1500 });
+        assert_eq!(*profiler.node(second), ProfileStats { count: 2, total: 2600, min: 700, max: 1900, last: 1900 });
+        assert_eq!(*profiler.block(), ProfileStats { count: 2, total: 11000, min: 2500, max: 8500, last: 8500 });
+        assert_eq!(profiler.node(2).average(), 0);
+        assert_eq!(profiler.block().average(), 5500);
+        assert_eq!(profiler.heaviest_node(), Some(second));
+
+        graph.prepare(128_000);
+        let profiler = graph.profiler().unwrap();
+        assert_eq!(profiler.budget(), Some(1_000_000));
+        assert_eq!(profiler.block_load(), Some(Load { average: 0.0055, max: 0.0085 }));
+
+        graph.profiler_mut().unwrap().reset();
+        assert_eq!(*graph.profiler().unwrap().block(), ProfileStats::default());
+    }
+}
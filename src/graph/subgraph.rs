@@ -0,0 +1,235 @@
+//! Instrument graphs wrapped as instruments.
+
+use crate::{Instrument, InstrumentInput, InstrumentOutput, STANDARD_BLOCK_SIZE};
+
+use super::AudioGraph;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SubgraphInputConnection {
+    pub instrument_index: usize,
+    pub stream_index: usize,
+}
+
+/// A graph wrapped as an instrument, so that a whole voice or effect chain can be used as a single node.
+///
+/// This instrument accepts `IN_VALUE_STREAMS` value streams and `IN_CONTROL_STREAMS` control streams,
+/// each of which can be routed to any number of instrument inputs inside the graph (up to `ROUTES` per stream).
+/// Each value stream also fills the input channel of the graph with the same index, if there is one.
+///
+/// The output streams are the first `OUT_VALUE_STREAMS` output channels of the graph.
+///
+/// The graph always processes `BLOCK_SIZE` samples at a time, so the subgraph must be processed in blocks
+/// of the same size, which is checked at compile time.
+pub struct Subgraph<
+    G,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    const ROUTES: usize = 16usize,
+    const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE,
+> {
+    /// The wrapped graph
+    pub graph: G,
+
+    /// The instrument inputs fed by each value stream of the subgraph
+    pub(crate) value_inputs: [[Option<SubgraphInputConnection>; ROUTES]; IN_VALUE_STREAMS],
+
+    /// The instrument inputs fed by each control stream of the subgraph
+    pub(crate) control_inputs: [[Option<SubgraphInputConnection>; ROUTES]; IN_CONTROL_STREAMS],
+}
+
+impl<
+    G,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    const ROUTES: usize,
+    const BLOCK_SIZE: usize,
+> Subgraph<G, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, ROUTES, BLOCK_SIZE> {
+    pub const fn new(graph: G) -> Self {
+        Self {
+            graph,
+            value_inputs: [[None; ROUTES]; IN_VALUE_STREAMS],
+            control_inputs: [[None; ROUTES]; IN_CONTROL_STREAMS],
+        }
+    }
+
+    /// Routes a value stream of the subgraph to a value stream input of an instrument in the graph
+    pub fn connect_value_input(&mut self, input_stream_index: usize, instrument_index: usize, instrument_stream_index: usize) {
+        if input_stream_index >= IN_VALUE_STREAMS {
+            panic!("Input stream index out of bounds");
+        }
+
+        Self::add_route(&mut self.value_inputs[input_stream_index], instrument_index, instrument_stream_index);
+    }
+
+    /// Routes a control stream of the subgraph to a control stream input of an instrument in the graph
+    ///
+    /// Instruments that are also connected to a control source of the graph receive the control source's stream instead.
+    pub fn connect_control_input(&mut self, input_stream_index: usize, instrument_index: usize, instrument_stream_index: usize) {
+        if input_stream_index >= IN_CONTROL_STREAMS {
+            panic!("Input stream index out of bounds");
+        }
+
+        Self::add_route(&mut self.control_inputs[input_stream_index], instrument_index, instrument_stream_index);
+    }
+
+    fn add_route(routes: &mut [Option<SubgraphInputConnection>; ROUTES], instrument_index: usize, stream_index: usize) {
+        for route in routes.iter_mut() {
+            if route.is_none() {
+                *route = Some(SubgraphInputConnection {
+                    instrument_index,
+                    stream_index,
+                });
+                return;
+            }
+        }
+        panic!("No more space for subgraph input routes");
+    }
+}
+
+impl<
+    G: AudioGraph<Note, BLOCK_SIZE>,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    const ROUTES: usize,
+    const BLOCK_SIZE: usize,
+    Note: Sized + Default + Copy + Send,
+> Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> for Subgraph<G, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, ROUTES, BLOCK_SIZE> {
+    fn process_block<const VALUE_BLOCK: usize, const CONTROL_ELEMENTS: usize>(
+        &mut self,
+        input: &InstrumentInput<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, Note, VALUE_BLOCK, CONTROL_ELEMENTS>,
+        output: &mut InstrumentOutput<OUT_VALUE_STREAMS, VALUE_BLOCK>,
+    ) {
+        const { assert!(VALUE_BLOCK == BLOCK_SIZE, "Subgraph block size must match the block size of its graph") };
+
+        for (i, stream) in input.value_streams.iter().enumerate().take(self.graph.input_channel_count()) {
+            let mut block = [0.0; BLOCK_SIZE];
+            block.copy_from_slice(stream);
+            self.graph.set_input(i, &block);
+        }
+
+        for (stream, routes) in input.value_streams.iter().zip(&self.value_inputs) {
+            for route in routes.iter().flatten() {
+                if let Some(instrument) = self.graph.instrument_mut(route.instrument_index) {
+                    instrument.feed_value_stream(route.stream_index, stream);
+                }
+            }
+        }
+
+        for (stream, routes) in input.control_streams.iter().zip(&self.control_inputs) {
+            for route in routes.iter().flatten() {
+                if let Some(instrument) = self.graph.instrument_mut(route.instrument_index) {
+                    instrument.feed_control_stream(route.stream_index, stream);
+                }
+            }
+        }
+
+        self.graph.process_next();
+
+        for (i, stream) in output.value_streams.iter_mut().enumerate() {
+            if i < self.graph.output_channel_count() {
+                stream.copy_from_slice(self.graph.get_output(i));
+            }
+        }
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, _max_block: usize) {
+        self.graph.prepare(sampling_rate);
+    }
+
+    fn latency(&self) -> usize {
+        self.graph.latency()
+    }
+
+    fn reset(&mut self) {
+        self.graph.reset();
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        self.graph.tail_length()
+    }
+
+    fn is_silent(&self) -> bool {
+        self.graph.is_silent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::InstrumentGraph;
+    use crate::instrument::{Amplifier, Constant, Mixer};
+    use crate::{container, ControlStreamSource, MidiNote, MusicalValue, NoteCommand, NoteCommandType};
+
+    /// Plays note 60 in every block
+    struct NoteOn {
+        stream: [NoteCommand<MidiNote>; 1],
+    }
+
+    impl ControlStreamSource<MidiNote> for NoteOn {
+        fn get_control_stream(&self) -> &[NoteCommand<MidiNote>] {
+            &self.stream
+        }
+
+        fn fetch_next_stream(&mut self) {}
+    }
+
+    /// Outputs the velocity of the first command of the block if it is a note on
+    struct Velocity;
+
+    impl Instrument<0, 1, 1> for Velocity {
+        fn process_block<const VALUE_BLOCK: usize, const CONTROL_ELEMENTS: usize>(
+            &mut self,
+            input: &InstrumentInput<0, 1, MidiNote, VALUE_BLOCK, CONTROL_ELEMENTS>,
+            output: &mut InstrumentOutput<1, VALUE_BLOCK>,
+        ) {
+            let command = input.control_streams[0][0];
+            let velocity = if command.command_type == NoteCommandType::NoteOn { command.velocity as MusicalValue } else { 0.0 };
+            output.value_streams[0] = [velocity; VALUE_BLOCK];
+        }
+    }
+
+    #[test]
+    fn routes_streams_through_the_nested_graph() {
+        let mut amplifier = container(Amplifier::<MidiNote>::new());
+        let mut velocity = container(Velocity);
+        let mut mixer = container(Mixer::<1, MidiNote>::new());
+        let mut inner: InstrumentGraph<3, 1, 2, 3, MidiNote, 128, 1> = InstrumentGraph::new();
+        let amplifier = inner.add_instrument(&mut amplifier);
+        let velocity = inner.add_instrument(&mut velocity);
+        let mixer = inner.add_instrument(&mut mixer);
+        inner.connect_input(0, mixer, 0);
+        inner.connect_destination(0, amplifier, 0);
+        inner.connect_destination(1, velocity, 0);
+        inner.connect_destination(2, mixer, 0);
+
+        // The signal and the gain of the amplifier, the first one also fills input channel 0
+        let mut subgraph: Subgraph<_, 2, 1, 3> = Subgraph::new(inner);
+        subgraph.connect_value_input(0, amplifier, 0);
+        subgraph.connect_value_input(1, amplifier, 1);
+        subgraph.connect_control_input(0, velocity, 0);
+
+        let mut note_on = NoteOn { stream: [NoteCommand { command_type: NoteCommandType::NoteOn, velocity: 100, note: 60 }] };
+        let mut signal = container(Constant::<MidiNote>::new(0.5));
+        let mut gain = container(Constant::<MidiNote>::new(4.0));
+        let mut subgraph = container(subgraph);
+        let mut outer: InstrumentGraph<3, 1, 2, 3> = InstrumentGraph::new();
+        let control = outer.add_control_source(&mut note_on);
+        let signal = outer.add_instrument(&mut signal);
+        let gain = outer.add_instrument(&mut gain);
+        let subgraph = outer.add_instrument(&mut subgraph);
+        outer.connect_value_stream(signal, 0, subgraph, 0);
+        outer.connect_value_stream(gain, 0, subgraph, 1);
+        outer.connect_control_source(control, subgraph);
+        for channel in 0..3 {
+            outer.connect_destination(channel, subgraph, channel);
+        }
+
+        outer.process_next();
+        assert_eq!(outer.get_output(0), &[2.0; 128]);
+        assert_eq!(outer.get_output(1), &[100.0; 128]);
+        assert_eq!(outer.get_output(2), &[0.5; 128]);
+    }
+}
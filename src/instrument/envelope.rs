@@ -1,5 +1,14 @@
 
-use crate::{Instrument, InstrumentInput, InstrumentOutput, NoteCommandType, MidiNote};
+use crate::parameter::{ParameterId, ParameterInfo, ParameterUnit};
+use crate::preset::{PresetError, PresetReader, PresetWriter};
+use crate::stream::{StreamInfo, StreamKind, StreamRole};
+use crate::{Instrument, InstrumentInput, InstrumentOutput, NoteCommandType, MidiNote, MusicalValue};
+
+/// The parameter names of the point times, the times and gains of points beyond these are not automatable
+const TIME_NAMES: [&str; 8] = ["time1", "time2", "time3", "time4", "time5", "time6", "time7", "time8"];
+
+/// The parameter names of the point gains
+const GAIN_NAMES: [&str; 8] = ["gain1", "gain2", "gain3", "gain4", "gain5", "gain6", "gain7", "gain8"];
 
 /// A linear envelope with a fixed number of points.
 /// 
@@ -26,6 +35,11 @@ use crate::{Instrument, InstrumentInput, InstrumentOutput, NoteCommandType, Midi
 /// This instrument accepts no value streams.
 /// 
 /// The output stream is the value of the envelope at the given time.
+/// 
+/// Parameters, all sample accurate:
+/// - `release`: the release time in samples
+/// - `time1` to `time8`: the time of each point in samples
+/// - `gain1` to `gain8`: the gain of each point
 pub struct LinearEnvelope<const POINTS: usize, Note: Sized = MidiNote> {
     /// The time in samples for each point.
     /// 
@@ -38,6 +52,11 @@ pub struct LinearEnvelope<const POINTS: usize, Note: Sized = MidiNote> {
     /// Release time in samples
     pub release_time: usize,
 
+    /// The time in seconds for each point and the release time in seconds, if the envelope was specified in seconds.
+    /// 
+    /// `point_times` and `release_time` are derived from them when the instrument is prepared.
+    pub seconds: Option<([f32; POINTS], f32)>,
+
     /// The current state of the envelope
     /// 
     /// 0 = off, 1-POINTS = playing, POINTS+1 = releasing
@@ -65,6 +84,7 @@ impl<const POINTS: usize, Note: Sized> LinearEnvelope<POINTS, Note> {
             point_times,
             point_gains,
             release_time,
+            seconds: None,
             current_point: 0,
             current_time: 0,
             current_note_gain: 0.0,
@@ -72,6 +92,31 @@ impl<const POINTS: usize, Note: Sized> LinearEnvelope<POINTS, Note> {
             _phantom: core::marker::PhantomData,
         }
     }
+
+    /// Creates an envelope with times in seconds, which take effect once the instrument is prepared
+    pub const fn new_seconds(
+        point_seconds: [f32; POINTS],
+        point_gains: [f32; POINTS],
+        release_seconds: f32,
+    ) -> Self {
+        let mut envelope = Self::new([0; POINTS], point_gains, 0);
+        envelope.seconds = Some((point_seconds, release_seconds));
+        envelope
+    }
+}
+
+/// Reads the saved point, time, note gain and gain of an envelope, rejecting unknown points, a running time
+/// while off and non-finite gains
+fn read_state<const POINTS: usize>(reader: &mut PresetReader) -> Result<(usize, usize, f32, f32), PresetError> {
+    let current_point = reader.read_usize()?;
+    let current_time = reader.read_usize()?;
+    let current_note_gain = reader.read_f32()?;
+    let current_gain = reader.read_f32()?;
+
+    if current_point > POINTS + 1 || (current_point == 0 && current_time > 0) || !current_note_gain.is_finite() || !current_gain.is_finite() {
+        return Err(PresetError::InvalidFormat);
+    }
+    Ok((current_point, current_time, current_note_gain, current_gain))
 }
 
 impl<const POINTS: usize, Note: Sized> Instrument<0, 1, 1, Note> for LinearEnvelope<POINTS, Note> {
@@ -109,53 +154,163 @@ impl<const POINTS: usize, Note: Sized> Instrument<0, 1, 1, Note> for LinearEnvel
             }
         }
 
-        for i in 0..VALUE_BLOCK {
-            if self.current_point == 0 {
-                output.value_streams[0][i] = 0.0;
-            } else if self.current_point <= POINTS {
-                let prev_point_gain = if self.current_point > 1 {
-                    self.point_gains[self.current_point - 2]
-                } else {
-                    0.0
-                };
+        for segment in input.parameter_segments() {
+            for event in segment.events {
+                self.set_parameter(event.id, event.value);
+            }
 
-                let point_time = self.point_times[self.current_point - 1];
+            for i in segment.start..segment.end {
+                if self.current_point == 0 {
+                    output.value_streams[0][i] = 0.0;
+                } else if self.current_point <= POINTS {
+                    let prev_point_gain = if self.current_point > 1 {
+                        self.point_gains[self.current_point - 2]
+                    } else {
+                        0.0
+                    };
 
-                let point_gain = self.point_gains[self.current_point - 1];
-                let gain_diff = point_gain - prev_point_gain;
+                    let point_time = self.point_times[self.current_point - 1];
 
-                let point_time_f32 = point_time as f32;
-                let current_time_f32 = self.current_time.min(point_time) as f32;
+                    let point_gain = self.point_gains[self.current_point - 1];
+                    let gain_diff = point_gain - prev_point_gain;
 
-                let gain = if point_time == 0 { point_gain } else { prev_point_gain + gain_diff * (current_time_f32 / point_time_f32) };
-                output.value_streams[0][i] = gain * self.current_note_gain;
-                self.current_gain = gain;
+                    let point_time_f32 = point_time as f32;
+                    let current_time_f32 = self.current_time.min(point_time) as f32;
 
-                self.current_time += 1;
-                if self.current_time >= point_time {
-                    if self.current_point < POINTS {
-                        self.current_point += 1;
-                        self.current_time = 0;
-                    }
+                    let gain = if point_time == 0 { point_gain } else { prev_point_gain + gain_diff * (current_time_f32 / point_time_f32) };
+                    output.value_streams[0][i] = gain * self.current_note_gain;
+                    self.current_gain = gain;
+
+                    self.current_time += 1;
+                    if self.current_time >= point_time {
+                        if self.current_point < POINTS {
+                            self.current_point += 1;
+                            self.current_time = 0;
+                        }
 
-                    while self.current_point < POINTS && self.point_times[self.current_point] == 0 {
-                        self.current_point += 1;
+                        while self.current_point < POINTS && self.point_times[self.current_point] == 0 {
+                            self.current_point += 1;
+                        }
+                    }
+                } else {
+                    if POINTS == 0 {
+                        self.current_gain = 1.0;
+                    }
+                    let release_time_f32 = self.release_time as f32;
+                    let current_time_f32 = self.current_time as f32;
+                    let gain = if self.release_time == 0 { 0.0 } else { self.current_gain * (1.0 - current_time_f32 / release_time_f32) };
+                    output.value_streams[0][i] = gain * self.current_note_gain;
+                    self.current_time += 1;
+                    if self.current_time >= self.release_time {
+                        self.current_point = 0;
+                        self.current_time = 0;
                     }
                 }
-            } else {
-                if POINTS == 0 {
-                    self.current_gain = 1.0;
-                }
-                let release_time_f32 = self.release_time as f32;
-                let current_time_f32 = self.current_time as f32;
-                let gain = if self.release_time == 0 { 0.0 } else { self.current_gain * (1.0 - current_time_f32 / release_time_f32) };
-                output.value_streams[0][i] = gain * self.current_note_gain;
-                self.current_time += 1;
-                if self.current_time >= self.release_time {
-                    self.current_point = 0;
-                    self.current_time = 0;
-                }
             }
         }
     }
+
+    fn prepare(&mut self, sampling_rate: usize, _max_block: usize) {
+        if let Some((point_seconds, release_seconds)) = self.seconds {
+            for (point_time, seconds) in self.point_times.iter_mut().zip(point_seconds) {
+                *point_time = (seconds * sampling_rate as f32) as usize;
+            }
+            self.release_time = (release_seconds * sampling_rate as f32) as usize;
+        }
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        match (kind, index) {
+            (StreamKind::ControlInput, 0) => Some(StreamInfo::notes("notes")),
+            (StreamKind::ValueOutput, 0) => Some(StreamInfo::new("envelope", StreamRole::Gain, ParameterUnit::Gain, 0.0, 1.0, 0.0)),
+            _ => None,
+        }
+    }
+
+    fn parameter_count(&self) -> usize {
+        1 + 2 * POINTS.min(TIME_NAMES.len())
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        let points = POINTS.min(TIME_NAMES.len());
+        if id == 0 {
+            Some(ParameterInfo::new("release", ParameterUnit::Samples, 0.0, MusicalValue::MAX, 0.0))
+        } else if id <= points {
+            Some(ParameterInfo::new(TIME_NAMES[id - 1], ParameterUnit::Samples, 0.0, MusicalValue::MAX, 0.0))
+        } else if id <= 2 * points {
+            Some(ParameterInfo::new(GAIN_NAMES[id - points - 1], ParameterUnit::Gain, MusicalValue::MIN, MusicalValue::MAX, 0.0))
+        } else {
+            None
+        }
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        let points = POINTS.min(TIME_NAMES.len());
+        if id == 0 {
+            Some(self.release_time as MusicalValue)
+        } else if id <= points {
+            Some(self.point_times[id - 1] as MusicalValue)
+        } else if id <= 2 * points {
+            Some(self.point_gains[id - points - 1])
+        } else {
+            None
+        }
+    }
+
+    /// Setting a time in samples replaces the times specified in seconds
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        let points = POINTS.min(TIME_NAMES.len());
+        if id == 0 {
+            self.release_time = value.max(0.0) as usize;
+            self.seconds = None;
+        } else if id <= points {
+            self.point_times[id - 1] = value.max(0.0) as usize;
+            self.seconds = None;
+        } else if id <= 2 * points {
+            self.point_gains[id - points - 1] = value;
+        }
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        writer.write_usize(self.current_point)?;
+        writer.write_usize(self.current_time)?;
+        writer.write_f32(self.current_note_gain)?;
+        writer.write_f32(self.current_gain)
+    }
+
+    /// The time is limited to the length of the current point or release, as their parameters may have changed
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        let (current_point, current_time, current_note_gain, current_gain) = read_state::<POINTS>(reader)?;
+        let length = match current_point {
+            0 => 0,
+            point if point <= POINTS => self.point_times[point - 1],
+            _ => self.release_time,
+        };
+
+        self.current_point = current_point;
+        self.current_time = current_time.min(length);
+        self.current_note_gain = current_note_gain;
+        self.current_gain = current_gain;
+        Ok(())
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        read_state::<POINTS>(reader).map(drop)
+    }
+
+    fn reset(&mut self) {
+        self.current_point = 0;
+        self.current_time = 0;
+        self.current_note_gain = 0.0;
+        self.current_gain = 0.0;
+    }
+
+    /// The release time, held notes sound until they are released
+    fn tail_length(&self) -> Option<usize> {
+        Some(self.release_time)
+    }
+
+    fn is_silent(&self) -> bool {
+        self.current_point == 0
+    }
 }
@@ -3,8 +3,13 @@
 
 pub mod oscillators;
 pub mod envelope;
+pub mod smoother;
 
-use crate::{Instrument, InstrumentInput, InstrumentOutput, MidiNote};
+use self::smoother::Smoother;
+use crate::parameter::{ParameterId, ParameterInfo, ParameterUnit};
+use crate::preset::{PresetError, PresetReader, PresetWriter};
+use crate::stream::{StreamInfo, StreamKind, StreamRole};
+use crate::{Instrument, InstrumentInput, InstrumentOutput, MidiNote, MusicalValue};
 
 /// The amplifier instrument.
 /// 
@@ -12,30 +17,105 @@ use crate::{Instrument, InstrumentInput, InstrumentOutput, MidiNote};
 /// - The first stream is the input signal
 /// - The second stream is the gain of the amplifier
 /// 
-/// The output stream is the input signal multiplied by the gain.
+/// The output stream is the input signal multiplied by the gain stream and by `gain`.
+/// 
+/// Parameters:
+/// - `gain`: an additional gain factor, smoothed and sample accurate
 pub struct Amplifier<Note: Sized = MidiNote> {
+    /// An additional gain factor, changes are smoothed
+    pub gain: f32,
+
+    /// The smoother of `gain`
+    pub smoother: Smoother,
+
     _phantom: core::marker::PhantomData<Note>,
 }
 
 impl<Note: Sized> Amplifier<Note> {
     pub const fn new() -> Self {
         Self {
+            gain: 1.0,
+            smoother: Smoother::linear(1.0),
             _phantom: core::marker::PhantomData,
         }
     }
 }
 
-impl<Note: Sized> Instrument<2, 0, 1, Note> for Amplifier {
+impl<Note: Sized> Instrument<2, 0, 1, Note> for Amplifier<Note> {
     fn process_block<const VALUE_BLOCK: usize, const CONTROL_ELEMENTS: usize>(
         &mut self,
         input: &InstrumentInput<2, 0, Note, VALUE_BLOCK, CONTROL_ELEMENTS>,
         output: &mut InstrumentOutput<1, VALUE_BLOCK>,
     ) {
 
-        for i in 0..VALUE_BLOCK {
-            output.value_streams[0][i] = input.value_streams[0][i] * input.value_streams[1][i];
+        for segment in input.parameter_segments() {
+            for event in segment.events {
+                self.set_parameter(event.id, event.value);
+            }
+
+            self.smoother.set_target(self.gain);
+            for i in segment.start..segment.end {
+                output.value_streams[0][i] = input.value_streams[0][i] * input.value_streams[1][i] * self.smoother.next_value();
+            }
+        }
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, _max_block: usize) {
+        self.smoother.prepare(sampling_rate);
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        match (kind, index) {
+            (StreamKind::ValueInput, 0) => Some(StreamInfo::audio("input")),
+            (StreamKind::ValueInput, 1) => Some(StreamInfo::new("gain", StreamRole::Gain, ParameterUnit::Gain, 0.0, 1.0, 0.0)),
+            (StreamKind::ValueOutput, 0) => Some(StreamInfo::audio("output")),
+            _ => None,
+        }
+    }
+
+    fn parameter_count(&self) -> usize {
+        1
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        match id {
+            0 => Some(ParameterInfo::new("gain", ParameterUnit::Gain, MusicalValue::MIN, MusicalValue::MAX, 1.0)),
+            _ => None,
         }
     }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        match id {
+            0 => Some(self.gain),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        if id == 0 {
+            self.gain = value;
+        }
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.smoother.save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.smoother.load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        Smoother::check_state(reader)
+    }
+
+    fn reset(&mut self) {
+        self.smoother.reset(self.gain);
+    }
+
+    fn is_silent(&self) -> bool {
+        true
+    }
 }
 
 /// The mixer instrument.
@@ -55,7 +135,7 @@ impl<const INPUT_STREAMS: usize, Note: Sized> Mixer<INPUT_STREAMS, Note> {
     }
 }
 
-impl<const INPUT_STREAMS: usize, Note: Sized> Instrument<INPUT_STREAMS, 0, 1, Note> for Mixer<INPUT_STREAMS> {
+impl<const INPUT_STREAMS: usize, Note: Sized> Instrument<INPUT_STREAMS, 0, 1, Note> for Mixer<INPUT_STREAMS, Note> {
     fn process_block<const VALUE_BLOCK: usize, const CONTROL_ELEMENTS: usize>(
         &mut self,
         input: &InstrumentInput<INPUT_STREAMS, 0, Note, VALUE_BLOCK, CONTROL_ELEMENTS>,
@@ -69,15 +149,53 @@ impl<const INPUT_STREAMS: usize, Note: Sized> Instrument<INPUT_STREAMS, 0, 1, No
             }
         }
     }
+
+    fn stream_info(&self, kind: StreamKind, _index: usize) -> Option<StreamInfo> {
+        match kind {
+            StreamKind::ValueInput => Some(StreamInfo::audio("input")),
+            StreamKind::ValueOutput => Some(StreamInfo::audio("output")),
+            StreamKind::ControlInput => None,
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        true
+    }
 }
 
+/// The delay instrument.
+/// 
+/// This instrument accepts one value stream, the input signal.
+/// 
+/// The output stream is the input signal delayed by `delay` samples.
+/// 
+/// Parameters:
+/// - `delay`: the delay time in samples, smoothed and sample accurate
 pub struct Delay<Note: Sized = MidiNote> {
-    /// The delay time in samples
+    /// The delay time in samples, changes are smoothed
     pub delay: u16,
 
+    /// The smoother of `delay`, fractional delays are interpolated linearly
+    pub smoother: Smoother,
+
+    /// The delay time in seconds, if the delay was specified in seconds.
+    /// 
+    /// `delay` is derived from it when the instrument is prepared.
+    pub delay_seconds: Option<f32>,
+
+    /// Whether `delay` is reported as latency, so that graphs align the paths parallel to this one.
+    /// 
+    /// Off by default, since a delay is usually meant to be heard.
+    pub report_latency: bool,
+
     pub buffer: [f32; 65536],
+
+    /// The position of the next sample written to `buffer`
     pub buffer_index: usize,
 
+    /// The number of silent samples written to `buffer` since the last sound, `usize::MAX` while it is empty
+    silent_samples: usize,
+
     _phantom: core::marker::PhantomData<Note>,
 }
 
@@ -85,11 +203,28 @@ impl<Note: Sized> Delay<Note> {
     pub const fn new(delay: u16) -> Self {
         Self {
             delay,
+            smoother: Smoother::linear(delay as f32),
+            delay_seconds: None,
+            report_latency: false,
             buffer: [0.0; 65536],
             buffer_index: 0,
+            silent_samples: usize::MAX,
             _phantom: core::marker::PhantomData,
         }
     }
+
+    /// Creates a delay specified in seconds, which takes effect once the instrument is prepared
+    pub const fn new_seconds(delay_seconds: f32) -> Self {
+        let mut delay = Self::new(0);
+        delay.delay_seconds = Some(delay_seconds);
+        delay
+    }
+
+    /// The number of past samples the output can still read, including the interpolated one
+    fn longest_delay(&self) -> usize {
+        let longest_delay = (self.delay as f32).max(self.smoother.current()).max(self.smoother.target());
+        libm::ceilf(longest_delay) as usize + 1
+    }
 }
 
 impl<Note: Sized> Instrument<1, 0, 1, Note> for Delay<Note> {
@@ -99,20 +234,146 @@ impl<Note: Sized> Instrument<1, 0, 1, Note> for Delay<Note> {
         output: &mut InstrumentOutput<1, VALUE_BLOCK>,
     ) {
 
-        for i in 0..VALUE_BLOCK {
-            self.buffer[self.buffer_index] = input.value_streams[0][i];
-            self.buffer_index += 1;
-            if self.buffer_index > self.delay as usize {
-                self.buffer_index = 0;
+        for segment in input.parameter_segments() {
+            for event in segment.events {
+                self.set_parameter(event.id, event.value);
+            }
+
+            self.smoother.set_target(self.delay as f32);
+            for i in segment.start..segment.end {
+                self.buffer[self.buffer_index] = input.value_streams[0][i];
+                self.silent_samples = if input.value_streams[0][i] == 0.0 { self.silent_samples.saturating_add(1) } else { 0 };
+
+                let delay = self.smoother.next_value();
+                let whole = delay as usize;
+                let fraction = delay - whole as f32;
+                let mask = self.buffer.len() - 1;
+                let newer = self.buffer[self.buffer_index.wrapping_sub(whole) & mask];
+                let older = self.buffer[self.buffer_index.wrapping_sub(whole + 1) & mask];
+                output.value_streams[0][i] = newer + (older - newer) * fraction;
+
+                self.buffer_index = (self.buffer_index + 1) & mask;
             }
-            output.value_streams[0][i] = self.buffer[self.buffer_index];
         }
     }
+
+    fn prepare(&mut self, sampling_rate: usize, _max_block: usize) {
+        self.smoother.prepare(sampling_rate);
+        if let Some(delay_seconds) = self.delay_seconds {
+            self.delay = (delay_seconds * sampling_rate as f32).clamp(0.0, u16::MAX as f32) as u16;
+            self.smoother.reset(self.delay as f32);
+        }
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        match (kind, index) {
+            (StreamKind::ValueInput, 0) => Some(StreamInfo::audio("input")),
+            (StreamKind::ValueOutput, 0) => Some(StreamInfo::audio("output")),
+            _ => None,
+        }
+    }
+
+    fn parameter_count(&self) -> usize {
+        1
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        match id {
+            0 => Some(ParameterInfo::new("delay", ParameterUnit::Samples, 0.0, u16::MAX as MusicalValue, 0.0)),
+            _ => None,
+        }
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        match id {
+            0 => Some(self.delay as MusicalValue),
+            _ => None,
+        }
+    }
+
+    /// Setting the delay in samples replaces a delay specified in seconds
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        if id == 0 {
+            self.delay = value.clamp(0.0, u16::MAX as MusicalValue) as u16;
+            self.delay_seconds = None;
+        }
+    }
+
+    /// Only the part of the buffer that can still be read is saved
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.smoother.save_state(writer)?;
+
+        let count = self.longest_delay().min(self.buffer.len());
+        let mask = self.buffer.len() - 1;
+        writer.write_u32(count as u32)?;
+        for i in 0..count {
+            writer.write_f32(self.buffer[self.buffer_index.wrapping_sub(count - i) & mask])?;
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.smoother.load_state(reader)?;
+
+        let count = reader.read_u32()? as usize;
+        if count > self.buffer.len() {
+            return Err(PresetError::InvalidFormat);
+        }
+
+        self.buffer = [0.0; 65536];
+        for i in 0..count {
+            self.buffer[i] = reader.read_f32()?;
+        }
+        self.buffer_index = count & (self.buffer.len() - 1);
+        self.silent_samples = 0;
+        Ok(())
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        Smoother::check_state(reader)?;
+
+        let count = reader.read_u32()? as usize;
+        if count > self.buffer.len() {
+            return Err(PresetError::InvalidFormat);
+        }
+        reader.read_bytes(count * 4).map(drop)
+    }
+
+    fn latency(&self) -> usize {
+        if self.report_latency { self.delay as usize } else { 0 }
+    }
+
+    fn reset(&mut self) {
+        self.buffer = [0.0; 65536];
+        self.buffer_index = 0;
+        self.silent_samples = usize::MAX;
+        self.smoother.reset(self.delay as f32);
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        Some(self.longest_delay())
+    }
+
+    fn is_silent(&self) -> bool {
+        self.silent_samples >= self.longest_delay()
+    }
 }
 
+/// The constant instrument.
+/// 
+/// This instrument accepts no streams.
+/// 
+/// The output stream is `value`.
+/// 
+/// Parameters:
+/// - `value`: the output value, smoothed and sample accurate
 pub struct Constant<Note: Sized = MidiNote> {
+    /// The output value, changes are smoothed
     pub value: f32,
 
+    /// The smoother of `value`
+    pub smoother: Smoother,
+
     _phantom: core::marker::PhantomData<Note>,
 }
 
@@ -120,6 +381,7 @@ impl<Note: Sized> Constant<Note> {
     pub const fn new(value: f32) -> Self {
         Self {
             value,
+            smoother: Smoother::linear(value),
             _phantom: core::marker::PhantomData,
         }
     }
@@ -128,12 +390,78 @@ impl<Note: Sized> Constant<Note> {
 impl<Note: Sized> Instrument<0, 0, 1, Note> for Constant<Note> {
     fn process_block<const VALUE_BLOCK: usize, const CONTROL_ELEMENTS: usize>(
         &mut self,
-        _input: &InstrumentInput<0, 0, Note, VALUE_BLOCK, CONTROL_ELEMENTS>,
+        input: &InstrumentInput<0, 0, Note, VALUE_BLOCK, CONTROL_ELEMENTS>,
         output: &mut InstrumentOutput<1, VALUE_BLOCK>,
     ) {
 
-        for i in 0..VALUE_BLOCK {
-            output.value_streams[0][i] = self.value;
+        for segment in input.parameter_segments() {
+            for event in segment.events {
+                self.set_parameter(event.id, event.value);
+            }
+
+            self.smoother.set_target(self.value);
+            for i in segment.start..segment.end {
+                output.value_streams[0][i] = self.smoother.next_value();
+            }
+        }
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, _max_block: usize) {
+        self.smoother.prepare(sampling_rate);
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        match (kind, index) {
+            (StreamKind::ValueOutput, 0) => Some(StreamInfo::new("value", StreamRole::Generic, ParameterUnit::Generic, MusicalValue::MIN, MusicalValue::MAX, 0.0)),
+            _ => None,
+        }
+    }
+
+    fn parameter_count(&self) -> usize {
+        1
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        match id {
+            0 => Some(ParameterInfo::new("value", ParameterUnit::Generic, MusicalValue::MIN, MusicalValue::MAX, 0.0)),
+            _ => None,
+        }
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        match id {
+            0 => Some(self.value),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        if id == 0 {
+            self.value = value;
         }
     }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.smoother.save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.smoother.load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        Smoother::check_state(reader)
+    }
+
+    fn reset(&mut self) {
+        self.smoother.reset(self.value);
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        None
+    }
+
+    fn is_silent(&self) -> bool {
+        self.value == 0.0 && self.smoother.current() == 0.0 && !self.smoother.is_smoothing()
+    }
 }
@@ -1,5 +1,8 @@
 
-use crate::{Instrument, InstrumentInput, InstrumentOutput, MidiNote};
+use crate::parameter::ParameterUnit;
+use crate::preset::{PresetError, PresetReader, PresetWriter};
+use crate::stream::{StreamInfo, StreamKind, StreamRole};
+use crate::{Instrument, InstrumentInput, InstrumentOutput, MidiNote, MusicalValue};
 
 /// Sine oscillator.
 /// 
@@ -10,7 +13,7 @@ use crate::{Instrument, InstrumentInput, InstrumentOutput, MidiNote};
 /// The output stream is the value of the oscillator at the given phase.
 /// The phase is in the range [0, 1), representing a full cycle of the oscillator.
 pub struct SineOscillator<Note: Sized = MidiNote> {
-    /// The sampling rate of the instrument
+    /// The sampling rate of the instrument, updated by `prepare`
     pub sampling_rate: usize,
 
     /// The current phase of the oscillator
@@ -29,6 +32,15 @@ impl<Note: Sized> SineOscillator<Note> {
     }
 }
 
+/// Reads a saved phase, which must be in the range [0, 1)
+fn read_phase(reader: &mut PresetReader) -> Result<f32, PresetError> {
+    let phase = reader.read_f32()?;
+    if !(0.0..1.0).contains(&phase) {
+        return Err(PresetError::InvalidFormat);
+    }
+    Ok(phase)
+}
+
 impl<Note: Sized> Instrument<2, 0, 1, Note> for SineOscillator<Note> {
     fn process_block<const VALUE_BLOCK: usize, const CONTROL_ELEMENTS: usize>(
         &mut self,
@@ -39,15 +51,49 @@ impl<Note: Sized> Instrument<2, 0, 1, Note> for SineOscillator<Note> {
         for i in 0..VALUE_BLOCK {
             let frequency = input.value_streams[0][i];
             let phase_increment = frequency / self.sampling_rate as f32;
-            self.phase += phase_increment + input.value_streams[1][i];
-            while self.phase >= 1.0 {
-                self.phase -= 1.0;
-            }
-            while self.phase < 0.0 {
-                self.phase += 1.0;
+            // Wraps into [0, 1) in one step, like `rem_euclid`, which needs std
+            let phase = self.phase + phase_increment + input.value_streams[1][i];
+            self.phase = phase - libm::floorf(phase);
+            // Tiny negative phases round up to 1, and non-finite inputs give NaN
+            if self.phase >= 1.0 || self.phase.is_nan() {
+                self.phase = 0.0;
             }
 
             output.value_streams[0][i] = libm::sinf(2.0 * core::f32::consts::PI * self.phase);
         }
     }
+
+    fn prepare(&mut self, sampling_rate: usize, _max_block: usize) {
+        self.sampling_rate = sampling_rate;
+    }
+
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        match (kind, index) {
+            (StreamKind::ValueInput, 0) => Some(StreamInfo::new("frequency", StreamRole::Frequency, ParameterUnit::Hertz, 0.0, self.sampling_rate as MusicalValue / 2.0, 0.0)),
+            (StreamKind::ValueInput, 1) => Some(StreamInfo::new("phase", StreamRole::Phase, ParameterUnit::Generic, -1.0, 1.0, 0.0)),
+            (StreamKind::ValueOutput, 0) => Some(StreamInfo::audio("output")),
+            _ => None,
+        }
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        writer.write_f32(self.phase)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.phase = read_phase(reader)?;
+        Ok(())
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        read_phase(reader).map(drop)
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        None
+    }
 }
@@ -0,0 +1,245 @@
+//! Parameter smoothing.
+//!
+//! Setting changes applied in a single step cause audible clicks and zipper noise. A `Smoother`
+//! moves from the current value to a new target over a configurable time instead.
+
+use crate::preset::{PresetError, PresetReader, PresetWriter};
+use crate::MusicalValue;
+
+/// The default smoothing time of the standard instruments, in seconds
+pub const DEFAULT_SMOOTHING_SECONDS: f32 = 0.01;
+
+/// How a `Smoother` approaches its target
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SmoothingMode {
+    /// The value jumps to the target
+    None,
+
+    /// The value moves to the target in a straight line, reaching it after the smoothing time
+    Linear,
+
+    /// The value approaches the target exponentially, covering about 63% of the distance in the smoothing time
+    OnePole,
+}
+
+/// A value smoothed towards a target, one sample at a time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Smoother {
+    pub mode: SmoothingMode,
+
+    /// The smoothing time in seconds
+    pub time: f32,
+
+    /// The sampling rate, 0 until prepared, in which case the value jumps to the target
+    sampling_rate: usize,
+
+    current: MusicalValue,
+    target: MusicalValue,
+
+    /// The change per sample of a linear ramp
+    step: MusicalValue,
+
+    /// The number of samples left in a linear ramp
+    remaining: usize,
+
+    /// The feedback coefficient of the one-pole filter
+    coefficient: MusicalValue,
+}
+
+impl Smoother {
+    pub const fn new(mode: SmoothingMode, time: f32, value: MusicalValue) -> Self {
+        Self {
+            mode,
+            time,
+            sampling_rate: 0,
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+            coefficient: 0.0,
+        }
+    }
+
+    /// A linear smoother with the default smoothing time
+    pub const fn linear(value: MusicalValue) -> Self {
+        Self::new(SmoothingMode::Linear, DEFAULT_SMOOTHING_SECONDS, value)
+    }
+
+    /// Changes how the value is smoothed, taking effect with the next target
+    pub fn set_smoothing(&mut self, mode: SmoothingMode, time: f32) {
+        self.mode = mode;
+        self.time = time;
+        self.update_coefficient();
+    }
+
+    /// Sets the sampling rate the smoothing time is measured at
+    pub fn prepare(&mut self, sampling_rate: usize) {
+        self.sampling_rate = sampling_rate;
+        self.update_coefficient();
+    }
+
+    /// Starts moving towards a new target
+    pub fn set_target(&mut self, target: MusicalValue) {
+        if target == self.target {
+            return;
+        }
+
+        self.target = target;
+        let samples = (self.time * self.sampling_rate as f32) as usize;
+        match self.mode {
+            SmoothingMode::Linear if samples > 0 => {
+                self.step = (target - self.current) / samples as MusicalValue;
+                self.remaining = samples;
+            },
+            SmoothingMode::OnePole if samples > 0 => {},
+            _ => self.reset(target),
+        }
+    }
+
+    /// Jumps to a value without smoothing
+    pub fn reset(&mut self, value: MusicalValue) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+
+    pub fn target(&self) -> MusicalValue {
+        self.target
+    }
+
+    /// The current value, which is the value the last call to `next_value` returned
+    pub fn current(&self) -> MusicalValue {
+        self.current
+    }
+
+    /// Whether the value has not reached the target yet
+    pub fn is_smoothing(&self) -> bool {
+        self.current != self.target
+    }
+
+    /// Advances by one sample, returning the new value
+    pub fn next_value(&mut self) -> MusicalValue {
+        if self.current == self.target {
+            return self.current;
+        }
+
+        match self.mode {
+            SmoothingMode::Linear if self.remaining > 1 => {
+                self.current += self.step;
+                self.remaining -= 1;
+            },
+            SmoothingMode::OnePole if self.coefficient > 0.0 => {
+                // Near the target, rounding can keep the value a few steps away from it forever
+                let next = self.target + (self.current - self.target) * self.coefficient;
+                let close = (next - self.target).abs() <= MusicalValue::EPSILON * self.target.abs().max(1.0);
+                self.current = if close || next == self.current { self.target } else { next };
+            },
+            _ => self.reset(self.target),
+        }
+        self.current
+    }
+
+    /// Writes the progress towards the target, for instrument states
+    pub fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        writer.write_f32(self.current)?;
+        writer.write_f32(self.target)?;
+        writer.write_f32(self.step)?;
+        writer.write_usize(self.remaining)
+    }
+
+    /// Restores the progress written by `save_state`
+    pub fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        (self.current, self.target, self.step, self.remaining) = Self::read_state(reader)?;
+        Ok(())
+    }
+
+    /// Checks progress written by `save_state` without restoring it
+    pub fn check_state(reader: &mut PresetReader) -> Result<(), PresetError> {
+        Self::read_state(reader).map(drop)
+    }
+
+    /// Reads the current value, target, step and remaining samples, which must be finite and describe a ramp
+    /// reaching the target
+    fn read_state(reader: &mut PresetReader) -> Result<(MusicalValue, MusicalValue, MusicalValue, usize), PresetError> {
+        let current = reader.read_f32()?;
+        let target = reader.read_f32()?;
+        let step = reader.read_f32()?;
+        let remaining = reader.read_usize()?;
+
+        if !current.is_finite() || !target.is_finite() || !step.is_finite() {
+            return Err(PresetError::InvalidFormat);
+        }
+        if remaining > 0 {
+            let steps = libm::ceilf((target - current).abs() / step.abs());
+            if step == 0.0 || !steps.is_finite() || remaining as f32 > steps + 1.0 {
+                return Err(PresetError::InvalidFormat);
+            }
+        }
+        Ok((current, target, step, remaining))
+    }
+
+    fn update_coefficient(&mut self) {
+        let samples = self.time * self.sampling_rate as f32;
+        self.coefficient = if samples > 0.0 { libm::expf(-1.0 / samples) } else { 0.0 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_reaches_target_after_smoothing_time() {
+        let mut smoother = Smoother::new(SmoothingMode::Linear, 0.01, 0.0);
+        smoother.prepare(1000);
+        smoother.set_target(1.0);
+        for _ in 0..9 {
+            assert!(smoother.next_value() < 1.0);
+        }
+        assert_eq!(smoother.next_value(), 1.0);
+        assert!(!smoother.is_smoothing());
+    }
+
+    #[test]
+    fn one_pole_converges() {
+        let mut smoother = Smoother::new(SmoothingMode::OnePole, 0.01, 0.0);
+        smoother.prepare(1000);
+        smoother.set_target(1.0);
+        let after_time = (0..10).map(|_| smoother.next_value()).last().unwrap();
+        assert!((after_time - 0.632).abs() < 0.01);
+        for _ in 0..1000 {
+            smoother.next_value();
+        }
+        assert_eq!(smoother.current(), 1.0);
+    }
+
+    #[test]
+    fn unprepared_jumps() {
+        let mut smoother = Smoother::linear(0.0);
+        smoother.set_target(1.0);
+        assert_eq!(smoother.current(), 1.0);
+    }
+
+    #[test]
+    fn state_round_trip_and_corrupt_state() {
+        let mut smoother = Smoother::new(SmoothingMode::Linear, 0.01, 0.0);
+        smoother.prepare(1000);
+        smoother.set_target(1.0);
+        smoother.next_value();
+
+        let mut buffer = [0u8; 20];
+        smoother.save_state(&mut PresetWriter::new(&mut buffer)).unwrap();
+        let mut loaded = Smoother::new(SmoothingMode::Linear, 0.01, 0.0);
+        loaded.prepare(1000);
+        loaded.load_state(&mut PresetReader::new(&buffer)).unwrap();
+        assert_eq!(loaded, smoother);
+
+        let mut corrupt = buffer;
+        corrupt[4..8].copy_from_slice(&f32::INFINITY.to_le_bytes());
+        assert_eq!(Smoother::check_state(&mut PresetReader::new(&corrupt)), Err(PresetError::InvalidFormat));
+
+        let mut corrupt = buffer;
+        corrupt[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Smoother::check_state(&mut PresetReader::new(&corrupt)), Err(PresetError::InvalidFormat));
+    }
+}
@@ -1,7 +1,28 @@
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use parameter::{ParameterEvent, ParameterId, ParameterInfo, ParameterSegments, MAX_PARAMETER_EVENTS};
+use preset::{PresetError, PresetReader, PresetWriter};
+use stream::{StreamInfo, StreamKind};
+
+pub mod dynamic;
 pub mod instrument;
 pub mod graph;
+pub mod parameter;
+pub mod preset;
+pub mod queue;
+pub mod render;
+pub mod stream;
+#[cfg(feature = "alloc")]
+pub mod patch;
+
+#[cfg(feature = "derive")]
+pub use linstr_derive::Instrument;
 
 /// The type of command to be sent to an instrument
 #[repr(u8)]
@@ -65,6 +86,33 @@ pub struct InstrumentInput<
     /// Value streams are used for sending musical values to the instrument
     /// The first dimension is the stream number, the second is the block number
     pub value_streams: [[MusicalValue; VALUE_BLOCK]; VALUE_STREAMS],
+
+    /// Parameter changes during the block, ordered by offset, valid for the first `parameter_event_count` entries
+    pub parameter_events: [ParameterEvent; MAX_PARAMETER_EVENTS],
+
+    pub parameter_event_count: usize,
+}
+
+impl<
+    const VALUE_STREAMS: usize,
+    const CONTROL_STREAMS: usize,
+    Note: Sized,
+    const VALUE_BLOCK: usize,
+    const CONTROL_ELEMENTS: usize,
+> InstrumentInput<VALUE_STREAMS, CONTROL_STREAMS, Note, VALUE_BLOCK, CONTROL_ELEMENTS> {
+    /// The parameter changes during the block, ordered by offset
+    pub fn parameter_events(&self) -> &[ParameterEvent] {
+        &self.parameter_events[..self.parameter_event_count]
+    }
+
+    /// Splits the block at the offsets of the parameter events, for sample accurate automation
+    pub fn parameter_segments(&self) -> ParameterSegments<'_> {
+        ParameterSegments {
+            events: self.parameter_events(),
+            position: 0,
+            block_size: VALUE_BLOCK,
+        }
+    }
 }
 
 /// The output block for an instrument
@@ -90,6 +138,93 @@ pub trait Instrument<
         input: &InstrumentInput<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, Note, BLOCK_SIZE, CONTROL_ELEMENTS>,
         output: &mut InstrumentOutput<OUT_VALUE_STREAMS, BLOCK_SIZE>,
     );
+
+    /// Prepares the instrument for processing at the given sampling rate, in blocks of at most `max_block` samples
+    /// 
+    /// Called before processing starts and again whenever the host configuration changes.
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        let _ = (sampling_rate, max_block);
+    }
+
+    /// Describes the stream of the given kind at the given index, `None` if it is not described
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        let _ = (kind, index);
+        None
+    }
+
+    /// The number of automatable parameters, whose ids are `0..parameter_count()`
+    ///
+    /// Instruments with parameters should process the block in `InstrumentInput::parameter_segments`, applying the
+    /// events of each segment before it. Events are never lost otherwise, see `set_parameter`.
+    fn parameter_count(&self) -> usize {
+        0
+    }
+
+    /// Describes the parameter with the given id
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        let _ = id;
+        None
+    }
+
+    /// Gets the current value of the parameter with the given id
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        let _ = id;
+        None
+    }
+
+    /// Sets the parameter with the given id, usually while applying the parameter events of a block
+    ///
+    /// After processing a block, or skipping it, containers set every event of the block again in order, so that
+    /// instruments that ignore `parameter_segments` still end the block at the automated values. Setting a parameter
+    /// to its current value must therefore change nothing.
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        let _ = (id, value);
+    }
+
+    /// Writes the runtime state of the instrument, such as phases and buffers, in a layout of its choice
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        let _ = writer;
+        Ok(())
+    }
+
+    /// Restores the runtime state written by `save_state`
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        let _ = reader;
+        Ok(())
+    }
+
+    /// Checks runtime state without restoring it, `load_state` must succeed for state passing this check.
+    ///
+    /// Graphs check the state of every instrument before loading any, so that a failing preset changes nothing.
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        let _ = reader;
+        Ok(())
+    }
+
+    /// The number of samples the output lags behind the input, such as the lookahead of a limiter.
+    /// 
+    /// Graphs delay shorter parallel paths by the difference, so that they are summed aligned.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Clears the runtime state, such as buffers, phases and envelope stages, as if the instrument was just prepared.
+    /// 
+    /// Settings and parameter values are kept.
+    fn reset(&mut self) {}
+
+    /// The number of samples the output can keep sounding once the input falls silent and notes are released,
+    /// `None` if it can sound indefinitely, like an oscillator
+    fn tail_length(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    /// Whether the output stays silent for as long as the input does, so that hosts can stop processing.
+    /// 
+    /// The default is false, which is always safe.
+    fn is_silent(&self) -> bool {
+        false
+    }
 }
 
 #[repr(C)]
@@ -100,10 +235,20 @@ pub(crate) struct InstrumentContainerImpl<
     const IN_CONTROL_STREAMS: usize,
     const OUT_VALUE_STREAMS: usize,
     Note: Sized,
+    const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE,
 > {
     pub instrument: I,
-    pub input: InstrumentInput<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, Note, STANDARD_BLOCK_SIZE, STANDARD_ELEMENT_COUNT>,
-    pub output: InstrumentOutput<OUT_VALUE_STREAMS, STANDARD_BLOCK_SIZE>,
+    pub input: InstrumentInput<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, Note, BLOCK_SIZE, STANDARD_ELEMENT_COUNT>,
+    pub output: InstrumentOutput<OUT_VALUE_STREAMS, BLOCK_SIZE>,
+
+    /// Whether each value stream was fed since the last block, later feeds are added to the first one
+    pub value_streams_fed: [bool; IN_VALUE_STREAMS],
+
+    /// Whether each value stream still holds data from an earlier block and must be zeroed before it is read again
+    pub value_streams_stale: [bool; IN_VALUE_STREAMS],
+
+    /// The number of elements fed to each control stream since the last block
+    pub control_streams_fed: [usize; IN_CONTROL_STREAMS],
 }
 
 impl<
@@ -112,7 +257,8 @@ impl<
     const IN_CONTROL_STREAMS: usize,
     const OUT_VALUE_STREAMS: usize,
     Note: Sized + Default + Copy,
-> InstrumentContainerImpl<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> {
+    const BLOCK_SIZE: usize,
+> InstrumentContainerImpl<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note, BLOCK_SIZE> {
     pub fn new(instrument: I) -> Self {
         Self {
             instrument,
@@ -122,53 +268,154 @@ impl<
                     velocity: 0,
                     note: Note::default(),
                 }; STANDARD_ELEMENT_COUNT]; IN_CONTROL_STREAMS],
-                value_streams: [[0.0; STANDARD_BLOCK_SIZE]; IN_VALUE_STREAMS],
+                value_streams: [[0.0; BLOCK_SIZE]; IN_VALUE_STREAMS],
+                parameter_events: [ParameterEvent::default(); MAX_PARAMETER_EVENTS],
+                parameter_event_count: 0,
             },
             output: InstrumentOutput {
-                value_streams: [[0.0; STANDARD_BLOCK_SIZE]; OUT_VALUE_STREAMS],
+                value_streams: [[0.0; BLOCK_SIZE]; OUT_VALUE_STREAMS],
             },
+            value_streams_fed: [false; IN_VALUE_STREAMS],
+            value_streams_stale: [false; IN_VALUE_STREAMS],
+            control_streams_fed: [0; IN_CONTROL_STREAMS],
+        }
+    }
+
+    /// Zeroes the value streams left over from an earlier block that were not fed in this one
+    fn clear_stale_input(&mut self) {
+        for i in 0..IN_VALUE_STREAMS {
+            if self.value_streams_fed[i] {
+                self.value_streams_stale[i] = true;
+            } else if self.value_streams_stale[i] {
+                self.input.value_streams[i] = [0.0; BLOCK_SIZE];
+                self.value_streams_stale[i] = false;
+            }
         }
     }
 
+    /// Resets the fed elements of the control streams and the fed flags of the value streams
     fn clear_input(&mut self) {
         for i in 0..IN_CONTROL_STREAMS {
-            for j in 0..STANDARD_ELEMENT_COUNT {
+            for j in 0..self.control_streams_fed[i] {
                 self.input.control_streams[i][j] = NoteCommand {
                     command_type: NoteCommandType::Noop,
                     velocity: 0,
                     note: Note::default(),
                 };
             }
+            self.control_streams_fed[i] = 0;
         }
 
-        for i in 0..IN_VALUE_STREAMS {
-            for j in 0..STANDARD_BLOCK_SIZE {
-                self.input.value_streams[i][j] = 0.0;
-            }
-        }
+        self.value_streams_fed = [false; IN_VALUE_STREAMS];
+        self.input.parameter_event_count = 0;
     }
 
     fn process_block(&mut self) {
+        self.clear_stale_input();
         self.instrument.process_block(&self.input, &mut self.output);
+        self.apply_parameter_events();
         self.clear_input();
     }
+
+    /// Sets the parameter events of the block, so that they are not lost when the block is skipped or the
+    /// instrument does not apply them itself
+    fn apply_parameter_events(&mut self) {
+        for i in 0..self.input.parameter_event_count {
+            let event = self.input.parameter_events[i];
+            self.instrument.set_parameter(event.id, event.value);
+        }
+    }
+
+    fn clear_output(&mut self) {
+        for i in 0..OUT_VALUE_STREAMS {
+            self.output.value_streams[i] = [0.0; BLOCK_SIZE];
+        }
+    }
 }
 
 /// A container for an instrument, its input, and its output
-pub trait InstrumentContainer<Note: Sized + Default + Copy = MidiNote>: Send {
+/// 
+/// The instrument is processed in blocks of `BLOCK_SIZE` samples.
+pub trait InstrumentContainer<Note: Sized + Default + Copy = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE>: Send {
     fn in_value_streams(&self) -> usize;
 
     fn in_control_streams(&self) -> usize;
 
     fn out_value_streams(&self) -> usize;
 
+    /// Describes the stream of the given kind at the given index, `None` if it is out of bounds or not described
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo>;
+
+    /// Finds the index of a stream of the given kind by its name
+    fn find_stream(&self, kind: StreamKind, name: &str) -> Option<usize> {
+        let count = match kind {
+            StreamKind::ValueInput => self.in_value_streams(),
+            StreamKind::ControlInput => self.in_control_streams(),
+            StreamKind::ValueOutput => self.out_value_streams(),
+        };
+        (0..count).find(|&index| self.stream_info(kind, index).is_some_and(|info| info.name == name))
+    }
+
     /// Processes the next block of data
     fn process_next(&mut self);
 
+    /// Consumes the next block of input without running the instrument,
+    /// passing input stream `input_index` through to output stream `output_index` and silencing the other outputs
+    /// 
+    /// Out of bounds stream indexes may panic.
+    fn process_bypassed(&mut self, input_index: usize, output_index: usize);
+
+    /// Silences every output stream for the next block, running the instrument anyway if `process` is set
+    /// so that its state keeps advancing
+    fn process_muted(&mut self, process: bool);
+
+    /// The number of automatable parameters of the instrument
+    fn parameter_count(&self) -> usize;
+
+    /// Describes the parameter of the instrument with the given id
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo>;
+
+    /// Gets the current value of the parameter of the instrument with the given id
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue>;
+
+    /// Sets a parameter of the instrument immediately, clamping the value to the range of the parameter.
+    /// 
+    /// Unknown parameters are ignored.
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue);
+
+    /// Writes the runtime state of the instrument
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError>;
+
+    /// Restores the runtime state of the instrument
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError>;
+
+    /// Checks runtime state of the instrument without restoring it
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError>;
+
+    /// The latency of the instrument in samples
+    fn latency(&self) -> usize;
+
+    /// Clears the runtime state of the instrument and its pending input and output
+    fn reset(&mut self);
+
+    /// The number of samples the output can keep sounding once the input falls silent, `None` if unbounded
+    fn tail_length(&self) -> Option<usize>;
+
+    /// Whether the output stays silent for as long as the input does
+    fn is_silent(&self) -> bool;
+
+    /// Schedules a parameter change in the next block, clamping the value to the range of the parameter.
+    /// 
+    /// Changes to unknown parameters are dropped. Returns false if no more changes fit in the block.
+    fn feed_parameter_event(&mut self, event: ParameterEvent) -> bool;
+
+    /// Prepares the instrument for processing at the given sampling rate, in blocks of at most `max_block` samples
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize);
+
     /// Gets the output stream at the given index
     /// 
     /// Out of bounds stream indexes may panic.
-    fn get_output(&self, index: usize) -> &[MusicalValue; STANDARD_BLOCK_SIZE];
+    fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE];
 
     /// Feeds a control stream to the instrument. Last call to this function before `process_next` will be used.
     /// 
@@ -187,7 +434,8 @@ impl<
     const IN_CONTROL_STREAMS: usize,
     const OUT_VALUE_STREAMS: usize,
     Note: Sized + Default + Copy + Send,
-> InstrumentContainer<Note> for InstrumentContainerImpl<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> {
+    const BLOCK_SIZE: usize,
+> InstrumentContainer<Note, BLOCK_SIZE> for InstrumentContainerImpl<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note, BLOCK_SIZE> {
     fn in_value_streams(&self) -> usize {
         IN_VALUE_STREAMS
     }
@@ -200,29 +448,148 @@ impl<
         OUT_VALUE_STREAMS
     }
 
+    fn stream_info(&self, kind: StreamKind, index: usize) -> Option<StreamInfo> {
+        let count = match kind {
+            StreamKind::ValueInput => IN_VALUE_STREAMS,
+            StreamKind::ControlInput => IN_CONTROL_STREAMS,
+            StreamKind::ValueOutput => OUT_VALUE_STREAMS,
+        };
+        if index < count { self.instrument.stream_info(kind, index) } else { None }
+    }
+
     fn process_next(&mut self) {
         self.process_block();
     }
 
-    fn get_output(&self, index: usize) -> &[MusicalValue; STANDARD_BLOCK_SIZE] {
+    fn process_bypassed(&mut self, input_index: usize, output_index: usize) {
+        self.apply_parameter_events();
+        self.clear_stale_input();
+        self.clear_output();
+        self.output.value_streams[output_index] = self.input.value_streams[input_index];
+        self.clear_input();
+    }
+
+    fn process_muted(&mut self, process: bool) {
+        if process {
+            self.process_block();
+        } else {
+            self.apply_parameter_events();
+            self.clear_stale_input();
+            self.clear_input();
+        }
+        self.clear_output();
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.instrument.parameter_count()
+    }
+
+    fn parameter_info(&self, id: ParameterId) -> Option<ParameterInfo> {
+        self.instrument.parameter_info(id)
+    }
+
+    fn parameter(&self, id: ParameterId) -> Option<MusicalValue> {
+        self.instrument.parameter(id)
+    }
+
+    fn set_parameter(&mut self, id: ParameterId, value: MusicalValue) {
+        if let Some(info) = self.instrument.parameter_info(id) {
+            self.instrument.set_parameter(id, info.clamp(value));
+        }
+    }
+
+    fn save_state(&self, writer: &mut PresetWriter) -> Result<(), PresetError> {
+        self.instrument.save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.instrument.load_state(reader)
+    }
+
+    fn check_state(&self, reader: &mut PresetReader) -> Result<(), PresetError> {
+        self.instrument.check_state(reader)
+    }
+
+    fn latency(&self) -> usize {
+        self.instrument.latency()
+    }
+
+    fn reset(&mut self) {
+        self.instrument.reset();
+        self.clear_input();
+        self.input.value_streams = [[0.0; BLOCK_SIZE]; IN_VALUE_STREAMS];
+        self.value_streams_stale = [false; IN_VALUE_STREAMS];
+        self.clear_output();
+    }
+
+    fn tail_length(&self) -> Option<usize> {
+        self.instrument.tail_length()
+    }
+
+    fn is_silent(&self) -> bool {
+        self.instrument.is_silent()
+    }
+
+    fn feed_parameter_event(&mut self, event: ParameterEvent) -> bool {
+        let Some(info) = self.instrument.parameter_info(event.id) else {
+            return true;
+        };
+
+        let count = self.input.parameter_event_count;
+        if count == MAX_PARAMETER_EVENTS {
+            return false;
+        }
+
+        let event = ParameterEvent {
+            id: event.id,
+            value: info.clamp(event.value),
+            offset: event.offset.min(BLOCK_SIZE.saturating_sub(1)),
+        };
+
+        // Keep the events ordered by offset, events at the same offset stay in the order they were fed
+        let events = &mut self.input.parameter_events;
+        let mut i = count;
+        while i > 0 && events[i - 1].offset > event.offset {
+            events[i] = events[i - 1];
+            i -= 1;
+        }
+        events[i] = event;
+        self.input.parameter_event_count = count + 1;
+        true
+    }
+
+    fn prepare(&mut self, sampling_rate: usize, max_block: usize) {
+        self.instrument.prepare(sampling_rate, max_block);
+    }
+
+    fn get_output(&self, index: usize) -> &[MusicalValue; BLOCK_SIZE] {
         &self.output.value_streams[index]
     }
     
     fn feed_control_stream(&mut self, stream_index: usize, stream: &[NoteCommand<Note>]) {
         let len = stream.len().min(STANDARD_ELEMENT_COUNT);
-        for i in 0..len {
-            self.input.control_streams[stream_index][i] = stream[i];
-        }
+        self.input.control_streams[stream_index][..len].copy_from_slice(&stream[..len]);
+        self.control_streams_fed[stream_index] = self.control_streams_fed[stream_index].max(len);
     }
 
     fn feed_value_stream(&mut self, stream_index: usize, stream: &[MusicalValue]) {
-        let len = stream.len().min(STANDARD_BLOCK_SIZE);
-        for i in 0..len {
-            self.input.value_streams[stream_index][i] += stream[i];
+        let len = stream.len().min(BLOCK_SIZE);
+        let input = &mut self.input.value_streams[stream_index];
+
+        // The first feed of a block overwrites the stream, so it never has to be cleared beforehand
+        if self.value_streams_fed[stream_index] {
+            for (sample, value) in input.iter_mut().zip(&stream[..len]) {
+                *sample += value;
+            }
+        } else {
+            input[..len].copy_from_slice(&stream[..len]);
+            input[len..].fill(0.0);
+            self.value_streams_fed[stream_index] = true;
         }
     }
 }
 
+/// Wraps an instrument into a container processing blocks of `STANDARD_BLOCK_SIZE` samples
 pub fn container<
     I: Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> + Send,
     const IN_VALUE_STREAMS: usize,
@@ -232,7 +599,23 @@ pub fn container<
 >(
     instrument: I,
 ) -> impl InstrumentContainer<Note> {
-    InstrumentContainerImpl::new(instrument)
+    InstrumentContainerImpl::<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note, STANDARD_BLOCK_SIZE>::new(instrument)
+}
+
+/// Wraps an instrument into a container processing blocks of `BLOCK_SIZE` samples
+/// 
+/// The remaining parameters can be inferred: `container_with_block_size::<32, _, _, _, _, _>(instrument)`.
+pub fn container_with_block_size<
+    const BLOCK_SIZE: usize,
+    I: Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> + Send,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized + Default + Copy + Send,
+>(
+    instrument: I,
+) -> impl InstrumentContainer<Note, BLOCK_SIZE> {
+    InstrumentContainerImpl::<I, IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note, BLOCK_SIZE>::new(instrument)
 }
 
 pub trait ControlStreamSource<Note: Sized + Send>: Send {
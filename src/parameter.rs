@@ -0,0 +1,172 @@
+//! Named, automatable instrument parameters.
+//!
+//! Instruments describe their parameters by id, from 0 to `Instrument::parameter_count`, and apply
+//! the parameter events of a block as they process it. Events carry the offset of the sample they
+//! take effect at, so instruments can follow automation with sample accuracy by processing the
+//! block in the segments returned by `InstrumentInput::parameter_segments`. The events of a block are
+//! set again once it is processed, so instruments that ignore the segments follow automation per block.
+
+use crate::MusicalValue;
+
+/// The id of a parameter, its index in the parameters of the instrument
+pub type ParameterId = usize;
+
+/// The maximum number of parameter events an instrument receives in a block
+pub const MAX_PARAMETER_EVENTS: usize = 16;
+
+/// The unit of a parameter value, for display
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ParameterUnit {
+    Generic,
+    Samples,
+    Seconds,
+    Hertz,
+    /// A linear gain factor
+    Gain,
+}
+
+/// The description of a parameter
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ParameterInfo {
+    pub name: &'static str,
+    pub unit: ParameterUnit,
+
+    /// The lowest value, lower values are clamped
+    pub min: MusicalValue,
+
+    /// The highest value, higher values are clamped
+    pub max: MusicalValue,
+
+    pub default: MusicalValue,
+}
+
+impl ParameterInfo {
+    pub const fn new(name: &'static str, unit: ParameterUnit, min: MusicalValue, max: MusicalValue, default: MusicalValue) -> Self {
+        Self {
+            name,
+            unit,
+            min,
+            max,
+            default,
+        }
+    }
+
+    /// Clamps a value to the range of the parameter
+    ///
+    /// Unlike `f32::clamp`, this never panics: a NaN value becomes `min`, a NaN bound is ignored and `max` wins
+    /// over `min` when they are inverted.
+    pub fn clamp(&self, value: MusicalValue) -> MusicalValue {
+        value.max(self.min).min(self.max)
+    }
+}
+
+/// A parameter change taking effect at the given sample of a block
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ParameterEvent {
+    pub id: ParameterId,
+    pub value: MusicalValue,
+
+    /// The sample of the block at which the value takes effect
+    pub offset: usize,
+}
+
+impl Default for ParameterEvent {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            value: 0.0,
+            offset: 0,
+        }
+    }
+}
+
+/// A run of samples of a block, preceded by the parameter events taking effect at its start
+#[derive(Debug, Clone)]
+pub struct ParameterSegment<'a> {
+    /// The events to apply before processing the segment
+    pub events: &'a [ParameterEvent],
+
+    pub start: usize,
+
+    /// The end of the segment, exclusive
+    pub end: usize,
+}
+
+/// Splits a block at the offsets of its parameter events, see `InstrumentInput::parameter_segments`
+#[derive(Debug, Clone)]
+pub struct ParameterSegments<'a> {
+    pub(crate) events: &'a [ParameterEvent],
+    pub(crate) position: usize,
+    pub(crate) block_size: usize,
+}
+
+impl<'a> Iterator for ParameterSegments<'a> {
+    type Item = ParameterSegment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.block_size {
+            return None;
+        }
+
+        let start = self.position;
+        let count = self.events.iter().take_while(|event| event.offset <= start).count();
+        let (events, rest) = self.events.split_at(count);
+        let end = rest.first().map_or(self.block_size, |event| event.offset.min(self.block_size));
+
+        self.events = rest;
+        self.position = end;
+        Some(ParameterSegment { events, start, end })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_never_panics() {
+        let info = ParameterInfo::new("gain", ParameterUnit::Gain, 0.0, 2.0, 1.0);
+        assert_eq!(info.clamp(3.0), 2.0);
+        assert_eq!(info.clamp(-1.0), 0.0);
+        assert_eq!(info.clamp(MusicalValue::NAN), 0.0);
+
+        let inverted = ParameterInfo::new("inverted", ParameterUnit::Generic, 2.0, 0.0, 1.0);
+        assert_eq!(inverted.clamp(1.0), 0.0);
+
+        let unbounded = ParameterInfo::new("unbounded", ParameterUnit::Generic, MusicalValue::NAN, MusicalValue::NAN, 0.0);
+        assert_eq!(unbounded.clamp(5.0), 5.0);
+    }
+
+    fn event(offset: usize) -> ParameterEvent {
+        ParameterEvent { id: 0, value: offset as MusicalValue, offset }
+    }
+
+    /// The `(start, end, event count)` of every segment
+    fn segments(events: &[ParameterEvent], block_size: usize) -> ([(usize, usize, usize); 4], usize) {
+        let mut result = [(0, 0, 0); 4];
+        let mut count = 0;
+        for segment in (ParameterSegments { events, position: 0, block_size }) {
+            result[count] = (segment.start, segment.end, segment.events.len());
+            count += 1;
+        }
+        (result, count)
+    }
+
+    #[test]
+    fn segments_split_at_event_offsets() {
+        let (result, count) = segments(&[], 8);
+        assert_eq!(&result[..count], &[(0, 8, 0)]);
+
+        let (result, count) = segments(&[event(0), event(3)], 8);
+        assert_eq!(&result[..count], &[(0, 3, 1), (3, 8, 1)]);
+
+        let (result, count) = segments(&[event(2), event(2), event(5)], 8);
+        assert_eq!(&result[..count], &[(0, 2, 0), (2, 5, 2), (5, 8, 1)]);
+    }
+
+    #[test]
+    fn segments_end_at_the_block_size() {
+        let (result, count) = segments(&[event(4), event(8), event(12)], 8);
+        assert_eq!(&result[..count], &[(0, 4, 0), (4, 8, 1)]);
+    }
+}
@@ -0,0 +1,400 @@
+//! Textual patch descriptions.
+//!
+//! A patch is a line based description of a graph:
+//!
+//! ```text
+//! # Comments start with '#'
+//! node <name> <type> [key=value ...]
+//! control <name>
+//! connect <node>[:<stream>] -> <node>[:<stream>]
+//! route <control> -> <node>
+//! output <channel> <- <node>[:<stream>]
+//! ```
+//!
+//! Instrument types are looked up in an `InstrumentRegistry`. Streams are given by index or by the name in their
+//! `StreamInfo`, such as `osc:frequency`, and default to 0.
+//! Declared controls are bound to control sources after parsing, in the same way as `add_control_source`.
+//!
+//! Parsing builds a `HeapInstrumentGraph` owning the instruments. `Patch::instrument_graph` builds an
+//! `InstrumentGraph` borrowing them, for fixed size processing.
+
+pub mod registry;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::graph::heap::HeapInstrumentGraph;
+use crate::graph::InstrumentGraph;
+use crate::stream::StreamKind;
+use crate::{ControlStreamSource, InstrumentContainer, MidiNote, STANDARD_BLOCK_SIZE};
+
+use self::registry::{InstrumentRegistry, Parameters};
+
+/// The highest number of output channels a patch can use
+pub const MAX_PATCH_OUTPUT_CHANNELS: usize = 64;
+
+/// The reason a patch could not be built
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchErrorKind {
+    /// The line could not be parsed
+    Syntax,
+    UnknownInstrument(String),
+    UnknownNode(String),
+    UnknownControl(String),
+    DuplicateName(String),
+    /// A parameter has an invalid value
+    InvalidParameter(String),
+    /// A stream index is out of range for the node
+    StreamOutOfRange(String, usize),
+    /// A node has no stream described by this name
+    UnknownStream(String, String),
+    /// An output channel is not below `MAX_PATCH_OUTPUT_CHANNELS`
+    OutputChannelOutOfRange(usize),
+    /// A connection would make a node depend on its own output
+    Cycle(String),
+    /// The patch has more nodes, controls, connections or output channels than the graph has room for
+    GraphTooSmall,
+}
+
+impl core::fmt::Display for PatchErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PatchErrorKind::Syntax => write!(f, "syntax error"),
+            PatchErrorKind::UnknownInstrument(name) => write!(f, "unknown instrument type `{}`", name),
+            PatchErrorKind::UnknownNode(name) => write!(f, "unknown node `{}`", name),
+            PatchErrorKind::UnknownControl(name) => write!(f, "unknown control `{}`", name),
+            PatchErrorKind::DuplicateName(name) => write!(f, "`{}` is already declared", name),
+            PatchErrorKind::InvalidParameter(name) => write!(f, "invalid value for parameter `{}`", name),
+            PatchErrorKind::StreamOutOfRange(name, index) => write!(f, "node `{}` has no stream {}", name, index),
+            PatchErrorKind::UnknownStream(name, stream) => write!(f, "node `{}` has no stream named `{}`", name, stream),
+            PatchErrorKind::OutputChannelOutOfRange(channel) => write!(f, "output channel {} is not below {}", channel, MAX_PATCH_OUTPUT_CHANNELS),
+            PatchErrorKind::Cycle(name) => write!(f, "connection to node `{}` would create a cycle", name),
+            PatchErrorKind::GraphTooSmall => write!(f, "the patch does not fit the graph"),
+        }
+    }
+}
+
+/// An error in a patch, with the line it occurred on
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchError {
+    /// The line number, starting at 1
+    pub line: usize,
+    pub kind: PatchErrorKind,
+}
+
+impl core::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+struct Endpoint<'s> {
+    node: &'s str,
+
+    /// The stream index or name, if given
+    stream: Option<&'s str>,
+}
+
+enum Statement<'s> {
+    Node { name: &'s str, instrument: &'s str, params: Parameters<'s> },
+    Control { name: &'s str },
+    Connect { source: Endpoint<'s>, destination: Endpoint<'s> },
+    Route { control: &'s str, node: &'s str },
+    Output { channel: usize, source: Endpoint<'s> },
+}
+
+fn parse_endpoint(text: &str) -> Result<Endpoint<'_>, PatchErrorKind> {
+    match text.split_once(':') {
+        Some((_, "")) => Err(PatchErrorKind::Syntax),
+        Some((node, stream)) => Ok(Endpoint { node, stream: Some(stream) }),
+        None => Ok(Endpoint { node: text, stream: None }),
+    }
+}
+
+fn parse_statement(line: &str) -> Result<Option<Statement<'_>>, PatchErrorKind> {
+    let line = match line.split_once('#') {
+        Some((content, _)) => content,
+        None => line,
+    };
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    match words.as_slice() {
+        [] => Ok(None),
+        ["node", name, instrument, params @ ..] => {
+            let mut entries = Vec::with_capacity(params.len());
+            for param in params {
+                entries.push(param.split_once('=').ok_or(PatchErrorKind::Syntax)?);
+            }
+            Ok(Some(Statement::Node { name, instrument, params: Parameters { entries } }))
+        },
+        ["control", name] => Ok(Some(Statement::Control { name })),
+        ["connect", source, "->", destination] => Ok(Some(Statement::Connect {
+            source: parse_endpoint(source)?,
+            destination: parse_endpoint(destination)?,
+        })),
+        ["route", control, "->", node] => Ok(Some(Statement::Route { control, node })),
+        ["output", channel, "<-", source] => {
+            let channel = channel.parse().map_err(|_| PatchErrorKind::Syntax)?;
+            if channel >= MAX_PATCH_OUTPUT_CHANNELS {
+                return Err(PatchErrorKind::OutputChannelOutOfRange(channel));
+            }
+            Ok(Some(Statement::Output { channel, source: parse_endpoint(source)? }))
+        },
+        _ => Err(PatchErrorKind::Syntax),
+    }
+}
+
+/// A graph built from a textual patch, with the names of its nodes and controls
+pub struct Patch<Note: Sized + Default + Copy + Send + 'static = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> {
+    pub graph: HeapInstrumentGraph<Note, BLOCK_SIZE>,
+
+    /// Instrument indexes in the graph, by node name
+    pub nodes: BTreeMap<String, usize>,
+
+    /// Control source indexes in the graph, by control name
+    pub controls: BTreeMap<String, usize>,
+}
+
+impl<Note: Sized + Default + Copy + Send + 'static, const BLOCK_SIZE: usize> Patch<Note, BLOCK_SIZE> {
+    /// Parses a patch and builds its graph with instruments from the registry
+    ///
+    /// The graph has as many output channels as the highest channel used by an `output` line.
+    pub fn parse(source: &str, registry: &InstrumentRegistry<Note, BLOCK_SIZE>) -> Result<Self, PatchError> {
+        let mut statements = Vec::new();
+        let mut output_channels = 0;
+        for (index, line) in source.lines().enumerate() {
+            let statement = parse_statement(line).map_err(|kind| PatchError { line: index + 1, kind })?;
+            if let Some(Statement::Output { channel, .. }) = &statement {
+                output_channels = output_channels.max(channel + 1);
+            }
+            if let Some(statement) = statement {
+                statements.push((index + 1, statement));
+            }
+        }
+
+        let mut patch = Self {
+            graph: HeapInstrumentGraph::new(output_channels),
+            nodes: BTreeMap::new(),
+            controls: BTreeMap::new(),
+        };
+
+        for (line, statement) in statements {
+            patch.apply(statement, registry).map_err(|kind| PatchError { line, kind })?;
+        }
+
+        Ok(patch)
+    }
+
+    fn apply(&mut self, statement: Statement, registry: &InstrumentRegistry<Note, BLOCK_SIZE>) -> Result<(), PatchErrorKind> {
+        match statement {
+            Statement::Node { name, instrument, params } => {
+                if self.nodes.contains_key(name) {
+                    return Err(PatchErrorKind::DuplicateName(name.to_string()));
+                }
+                let instrument = registry.create(instrument, &params)?;
+                let index = self.graph.add_instrument(instrument);
+                self.nodes.insert(name.to_string(), index);
+            },
+            Statement::Control { name } => {
+                if self.controls.contains_key(name) {
+                    return Err(PatchErrorKind::DuplicateName(name.to_string()));
+                }
+                self.graph.control_sources.push(None);
+                self.controls.insert(name.to_string(), self.graph.control_sources.len() - 1);
+            },
+            Statement::Connect { source, destination } => {
+                let (source_index, source_stream) = self.node_output(&source)?;
+                let destination_index = self.node(destination.node)?;
+                let destination_stream = self.stream(&destination, destination_index, StreamKind::ValueInput)?;
+                if destination_stream >= self.stream_counts(destination_index).0 {
+                    return Err(PatchErrorKind::StreamOutOfRange(destination.node.to_string(), destination_stream));
+                }
+                if source_index == destination_index || self.graph.depends_on(source_index, destination_index) {
+                    return Err(PatchErrorKind::Cycle(destination.node.to_string()));
+                }
+                self.graph.connect_value_stream(source_index, source_stream, destination_index, destination_stream);
+            },
+            Statement::Route { control, node } => {
+                let control_index = *self.controls.get(control).ok_or_else(|| PatchErrorKind::UnknownControl(control.to_string()))?;
+                let node_index = self.node(node)?;
+                if self.stream_counts(node_index).1 == 0 {
+                    return Err(PatchErrorKind::StreamOutOfRange(node.to_string(), 0));
+                }
+                self.graph.connect_control_source(control_index, node_index);
+            },
+            Statement::Output { channel, source } => {
+                let (source_index, source_stream) = self.node_output(&source)?;
+                self.graph.connect_destination(channel, source_index, source_stream);
+            },
+        }
+        Ok(())
+    }
+
+    fn node(&self, name: &str) -> Result<usize, PatchErrorKind> {
+        self.nodes.get(name).copied().ok_or_else(|| PatchErrorKind::UnknownNode(name.to_string()))
+    }
+
+    /// Instrument and stream index of an output endpoint
+    fn node_output(&self, endpoint: &Endpoint) -> Result<(usize, usize), PatchErrorKind> {
+        let index = self.node(endpoint.node)?;
+        let stream = self.stream(endpoint, index, StreamKind::ValueOutput)?;
+        if stream >= self.stream_counts(index).2 {
+            return Err(PatchErrorKind::StreamOutOfRange(endpoint.node.to_string(), stream));
+        }
+        Ok((index, stream))
+    }
+
+    /// Resolves the stream of an endpoint, given by index or by name, without checking the index
+    fn stream(&self, endpoint: &Endpoint, index: usize, kind: StreamKind) -> Result<usize, PatchErrorKind> {
+        let Some(stream) = endpoint.stream else {
+            return Ok(0);
+        };
+        if let Ok(stream) = stream.parse() {
+            return Ok(stream);
+        }
+        self.graph.instruments[index].as_ref()
+            .and_then(|instrument| instrument.find_stream(kind, stream))
+            .ok_or_else(|| PatchErrorKind::UnknownStream(endpoint.node.to_string(), stream.to_string()))
+    }
+
+    /// Input value, input control and output value stream counts of a node
+    fn stream_counts(&self, index: usize) -> (usize, usize, usize) {
+        match &self.graph.instruments[index] {
+            Some(instrument) => (instrument.in_value_streams(), instrument.in_control_streams(), instrument.out_value_streams()),
+            None => (0, 0, 0),
+        }
+    }
+
+    /// Gets the instrument index of a node by name
+    pub fn node_index(&self, name: &str) -> Option<usize> {
+        self.nodes.get(name).copied()
+    }
+
+    /// Binds a control source to a control declared in the patch
+    pub fn bind_control_source(&mut self, name: &str, control_source: Box<dyn ControlStreamSource<Note>>) -> Result<(), PatchErrorKind> {
+        let index = *self.controls.get(name).ok_or_else(|| PatchErrorKind::UnknownControl(name.to_string()))?;
+        self.graph.control_sources[index] = Some(control_source);
+        Ok(())
+    }
+
+    /// Builds an `InstrumentGraph` with the topology of the patch, borrowing its instruments and bound control sources
+    ///
+    /// Node and control indexes are the same as in `graph`, and the graph is prepared at the sampling rate `graph`
+    /// was prepared for, if any.
+    pub fn instrument_graph<const SIZE: usize, const CONTROL_SIZE: usize, const CONNECTION_SIZE: usize, const OUTPUT_CHANNELS: usize>(
+        &mut self,
+    ) -> Result<InstrumentGraph<'_, SIZE, CONTROL_SIZE, CONNECTION_SIZE, OUTPUT_CHANNELS, Note, BLOCK_SIZE>, PatchErrorKind> {
+        let source = &mut self.graph;
+        let fits = source.instruments.len() <= SIZE
+            && source.control_sources.len() <= CONTROL_SIZE
+            && source.output_channels.len() <= OUTPUT_CHANNELS
+            && source.value_stream_connections.iter().all(|connections| connections.len() <= CONNECTION_SIZE)
+            && source.destination_connections.iter().all(|connections| connections.len() <= CONNECTION_SIZE);
+        if !fits {
+            return Err(PatchErrorKind::GraphTooSmall);
+        }
+
+        let mut graph = InstrumentGraph::new();
+        for (slot, instrument) in graph.instruments.iter_mut().zip(&mut source.instruments) {
+            *slot = instrument.as_deref_mut().map(|instrument| instrument as &mut dyn InstrumentContainer<Note, BLOCK_SIZE>);
+        }
+        for (slot, control_source) in graph.control_sources.iter_mut().zip(&mut source.control_sources) {
+            *slot = control_source.as_deref_mut().map(|control_source| control_source as &mut dyn ControlStreamSource<Note>);
+        }
+
+        for (destination_index, connections) in source.value_stream_connections.iter().enumerate() {
+            for connection in connections {
+                graph.connect_value_stream(connection.source_index, connection.source_stream_index, destination_index, connection.destination_stream_index);
+            }
+        }
+        for (instrument_index, connection) in source.instruments_control_sources.iter().enumerate() {
+            if let Some(connection) = connection {
+                graph.connect_control_source(connection.source_index, instrument_index);
+            }
+        }
+        for (channel, connections) in source.destination_connections.iter().enumerate() {
+            for connection in connections {
+                graph.connect_destination(channel, connection.source_index, connection.source_stream_index);
+            }
+        }
+
+        if source.sampling_rate > 0 {
+            graph.prepare(source.sampling_rate);
+        }
+        Ok(graph)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = "
+        # A constant signal through an amplifier
+        node signal const value=0.5
+        node level const value=2
+        node amp amp
+        connect signal -> amp:input
+        connect level -> amp:1
+        output 1 <- amp:output
+    ";
+
+    fn parse(source: &str) -> Result<Patch, PatchError> {
+        Patch::parse(source, &InstrumentRegistry::with_standard_instruments(48000))
+    }
+
+    fn error(source: &str) -> PatchError {
+        parse(source).err().expect("the patch should be rejected")
+    }
+
+    #[test]
+    fn builds_graphs() {
+        let mut patch = parse(PATCH).unwrap();
+        assert_eq!(patch.graph.output_channel_count(), 2);
+        patch.graph.process_next();
+        assert_eq!(patch.graph.get_output(0)[0], 0.0);
+        assert_eq!(patch.graph.get_output(1)[0], 1.0);
+
+        let amp = patch.node_index("amp").unwrap();
+        let mut graph = patch.instrument_graph::<4, 1, 2, 2>().unwrap();
+        assert!(graph.instruments[amp].is_some());
+        graph.process_next();
+        assert_eq!(graph.get_output(1)[0], 1.0);
+    }
+
+    #[test]
+    fn rejects_graphs_too_small() {
+        let mut patch = parse(PATCH).unwrap();
+        assert!(matches!(patch.instrument_graph::<2, 1, 2, 2>(), Err(PatchErrorKind::GraphTooSmall)));
+        assert!(matches!(patch.instrument_graph::<4, 1, 1, 2>(), Err(PatchErrorKind::GraphTooSmall)));
+        assert!(matches!(patch.instrument_graph::<4, 1, 2, 1>(), Err(PatchErrorKind::GraphTooSmall)));
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(error("node a const\n\nconnect a ->").line, 3);
+        assert_eq!(error("connect a -> b").kind, PatchErrorKind::UnknownNode("a".to_string()));
+        assert_eq!(error("node a nothing").kind, PatchErrorKind::UnknownInstrument("nothing".to_string()));
+        assert_eq!(error("node a const\nnode a amp").kind, PatchErrorKind::DuplicateName("a".to_string()));
+        assert_eq!(error("node a const value=loud").kind, PatchErrorKind::InvalidParameter("value".to_string()));
+        assert_eq!(error("route keys -> a").kind, PatchErrorKind::UnknownControl("keys".to_string()));
+        assert_eq!(error("node a const\noutput 0 <- a:1").kind, PatchErrorKind::StreamOutOfRange("a".to_string(), 1));
+        assert_eq!(error("node a const\noutput 0 <- a:gain").kind, PatchErrorKind::UnknownStream("a".to_string(), "gain".to_string()));
+        assert_eq!(error("node a const\noutput 0 <- a:").kind, PatchErrorKind::Syntax);
+    }
+
+    #[test]
+    fn rejects_out_of_range_output_channels() {
+        let kind = error("node a const\noutput 100000000 <- a").kind;
+        assert_eq!(kind, PatchErrorKind::OutputChannelOutOfRange(100000000));
+        assert!(parse("node a const\noutput 63 <- a").is_ok());
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        assert_eq!(error("node a amp\nconnect a -> a").kind, PatchErrorKind::Cycle("a".to_string()));
+        assert_eq!(error("node a amp\nnode b amp\nconnect a -> b\nconnect b -> a:1").kind, PatchErrorKind::Cycle("a".to_string()));
+    }
+}
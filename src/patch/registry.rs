@@ -0,0 +1,187 @@
+//! Instrument registry used to build patches.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::instrument::envelope::LinearEnvelope;
+use crate::instrument::oscillators::SineOscillator;
+use crate::instrument::{Amplifier, Constant, Delay, Mixer};
+use crate::{container_with_block_size, Instrument, InstrumentContainer, MidiNote, STANDARD_BLOCK_SIZE};
+
+use super::PatchErrorKind;
+
+/// The parameters given to an instrument in a patch, as `key=value` pairs
+#[derive(Debug, Clone, Default)]
+pub struct Parameters<'s> {
+    pub(crate) entries: Vec<(&'s str, &'s str)>,
+}
+
+impl<'s> Parameters<'s> {
+    /// Gets the raw text of a parameter
+    pub fn get(&self, name: &str) -> Option<&'s str> {
+        self.entries.iter().find(|(key, _)| *key == name).map(|(_, value)| *value)
+    }
+
+    pub fn f32_or(&self, name: &str, default: f32) -> Result<f32, PatchErrorKind> {
+        match self.get(name) {
+            Some(value) => parse_value(name, value),
+            None => Ok(default),
+        }
+    }
+
+    pub fn usize_or(&self, name: &str, default: usize) -> Result<usize, PatchErrorKind> {
+        match self.get(name) {
+            Some(value) => parse_value(name, value),
+            None => Ok(default),
+        }
+    }
+
+    /// Gets a comma separated list of numbers, an absent parameter is an empty list
+    pub fn f32_list(&self, name: &str) -> Result<Vec<f32>, PatchErrorKind> {
+        self.list(name)
+    }
+
+    /// Gets a comma separated list of integers, an absent parameter is an empty list
+    pub fn usize_list(&self, name: &str) -> Result<Vec<usize>, PatchErrorKind> {
+        self.list(name)
+    }
+
+    fn list<T: core::str::FromStr>(&self, name: &str) -> Result<Vec<T>, PatchErrorKind> {
+        match self.get(name) {
+            Some(value) => value.split(',').map(|item| parse_value(name, item.trim())).collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+fn parse_value<T: core::str::FromStr>(name: &str, value: &str) -> Result<T, PatchErrorKind> {
+    value.parse().map_err(|_| PatchErrorKind::InvalidParameter(name.to_string()))
+}
+
+/// A function building an instrument from its patch parameters
+pub type Constructor<Note, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> = Box<dyn Fn(&Parameters) -> Result<Box<dyn InstrumentContainer<Note, BLOCK_SIZE>>, PatchErrorKind> + Send + Sync>;
+
+/// Maps instrument type names to constructors
+pub struct InstrumentRegistry<Note: Sized + Default + Copy + Send + 'static = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> {
+    constructors: BTreeMap<String, Constructor<Note, BLOCK_SIZE>>,
+}
+
+impl<Note: Sized + Default + Copy + Send + 'static, const BLOCK_SIZE: usize> Default for InstrumentRegistry<Note, BLOCK_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Note: Sized + Default + Copy + Send + 'static, const BLOCK_SIZE: usize> InstrumentRegistry<Note, BLOCK_SIZE> {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self {
+            constructors: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a registry with the standard instruments:
+    /// - `sine`: `SineOscillator`, sampling rate from `rate` (defaults to `sampling_rate`)
+    /// - `amp`: `Amplifier`
+    /// - `mixer`: `Mixer` with `inputs` input streams (1 to 8, defaults to 2)
+    /// - `envelope`: `LinearEnvelope` with point `times` and `gains` (1 to 4 points) and `release` time, all times in samples
+    /// - `delay`: `Delay` of `samples` samples
+    /// - `const`: `Constant` with the given `value`
+    pub fn with_standard_instruments(sampling_rate: usize) -> Self {
+        let mut registry = Self::new();
+
+        registry.register("sine", move |params| {
+            Ok(boxed(SineOscillator::<Note>::new(params.usize_or("rate", sampling_rate)?)))
+        });
+
+        registry.register("amp", |_| Ok(boxed(Amplifier::<Note>::new())));
+
+        registry.register("mixer", |params| {
+            Ok(match params.usize_or("inputs", 2)? {
+                1 => boxed(Mixer::<1, Note>::new()),
+                2 => boxed(Mixer::<2, Note>::new()),
+                3 => boxed(Mixer::<3, Note>::new()),
+                4 => boxed(Mixer::<4, Note>::new()),
+                5 => boxed(Mixer::<5, Note>::new()),
+                6 => boxed(Mixer::<6, Note>::new()),
+                7 => boxed(Mixer::<7, Note>::new()),
+                8 => boxed(Mixer::<8, Note>::new()),
+                _ => return Err(PatchErrorKind::InvalidParameter("inputs".to_string())),
+            })
+        });
+
+        registry.register("envelope", |params| {
+            let times = params.usize_list("times")?;
+            let gains = params.f32_list("gains")?;
+            let release = params.usize_or("release", 0)?;
+            if times.len() != gains.len() {
+                return Err(PatchErrorKind::InvalidParameter("gains".to_string()));
+            }
+
+            Ok(match times.len() {
+                1 => boxed(envelope::<1, Note>(&times, &gains, release)),
+                2 => boxed(envelope::<2, Note>(&times, &gains, release)),
+                3 => boxed(envelope::<3, Note>(&times, &gains, release)),
+                4 => boxed(envelope::<4, Note>(&times, &gains, release)),
+                _ => return Err(PatchErrorKind::InvalidParameter("times".to_string())),
+            })
+        });
+
+        registry.register("delay", |params| {
+            let samples = params.usize_or("samples", 0)?;
+            let samples = u16::try_from(samples).map_err(|_| PatchErrorKind::InvalidParameter("samples".to_string()))?;
+            Ok(boxed(Delay::<Note>::new(samples)))
+        });
+
+        registry.register("const", |params| {
+            Ok(boxed(Constant::<Note>::new(params.f32_or("value", 0.0)?)))
+        });
+
+        registry
+    }
+
+    /// Registers a constructor under the given name, replacing any previous one
+    pub fn register<F>(&mut self, name: &str, constructor: F)
+    where
+        F: Fn(&Parameters) -> Result<Box<dyn InstrumentContainer<Note, BLOCK_SIZE>>, PatchErrorKind> + Send + Sync + 'static,
+    {
+        self.constructors.insert(name.to_string(), Box::new(constructor));
+    }
+
+    /// Checks whether a constructor is registered under the given name
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(name)
+    }
+
+    /// Builds an instrument of the given type
+    pub fn create(&self, name: &str, params: &Parameters) -> Result<Box<dyn InstrumentContainer<Note, BLOCK_SIZE>>, PatchErrorKind> {
+        match self.constructors.get(name) {
+            Some(constructor) => constructor(params),
+            None => Err(PatchErrorKind::UnknownInstrument(name.to_string())),
+        }
+    }
+}
+
+/// Wraps an instrument into a boxed container, for use in constructors
+pub fn boxed<
+    I: Instrument<IN_VALUE_STREAMS, IN_CONTROL_STREAMS, OUT_VALUE_STREAMS, Note> + Send + 'static,
+    const IN_VALUE_STREAMS: usize,
+    const IN_CONTROL_STREAMS: usize,
+    const OUT_VALUE_STREAMS: usize,
+    Note: Sized + Default + Copy + Send + 'static,
+    const BLOCK_SIZE: usize,
+>(
+    instrument: I,
+) -> Box<dyn InstrumentContainer<Note, BLOCK_SIZE>> {
+    Box::new(container_with_block_size::<BLOCK_SIZE, _, _, _, _, _>(instrument))
+}
+
+fn envelope<const POINTS: usize, Note: Sized>(times: &[usize], gains: &[f32], release: usize) -> LinearEnvelope<POINTS, Note> {
+    let mut point_times = [0; POINTS];
+    let mut point_gains = [0.0; POINTS];
+    point_times.copy_from_slice(times);
+    point_gains.copy_from_slice(gains);
+    LinearEnvelope::new(point_times, point_gains, release)
+}
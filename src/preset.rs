@@ -0,0 +1,177 @@
+//! Binary presets of parameters and runtime state.
+//!
+//! Presets are written into and read from byte slices, so they work without allocation. All values
+//! are little endian. A graph preset starts with a header:
+//!
+//! ```text
+//! magic "LNSP", version: u16, flags: u8, node count: u32, sample position: u64 (with runtime state only)
+//! ```
+//!
+//! followed by one entry per occupied instrument slot:
+//!
+//! ```text
+//! index: u32, in value streams: u8, in control streams: u8, out value streams: u8,
+//! parameter count: u32, (id: u32, value: f32) for each parameter,
+//! state length: u32, state bytes
+//! ```
+//!
+//! The state is only written when requested, and is empty otherwise. Its layout is up to the instrument.
+
+use crate::MusicalValue;
+
+/// The magic bytes at the start of a graph preset
+pub const PRESET_MAGIC: [u8; 4] = *b"LNSP";
+
+/// The current preset format version, presets of later versions, or of version 0, are rejected
+pub const PRESET_VERSION: u16 = 1;
+
+/// Set in the header flags when the preset includes runtime state
+pub const PRESET_FLAG_STATE: u8 = 1;
+
+/// The reason a preset could not be written or read
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PresetError {
+    /// The buffer is too small for the preset
+    BufferTooSmall,
+
+    /// The data ended early or is not a preset
+    InvalidFormat,
+
+    UnsupportedVersion(u16),
+
+    /// The instrument at the given index does not match the one the preset was saved from
+    InstrumentMismatch(usize),
+}
+
+impl core::fmt::Display for PresetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PresetError::BufferTooSmall => write!(f, "buffer too small for the preset"),
+            PresetError::InvalidFormat => write!(f, "invalid preset data"),
+            PresetError::UnsupportedVersion(version) => write!(f, "unsupported preset version {}", version),
+            PresetError::InstrumentMismatch(index) => write!(f, "instrument {} does not match the preset", index),
+        }
+    }
+}
+
+/// Writes little endian values into a byte slice
+pub struct PresetWriter<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+}
+
+impl<'b> PresetWriter<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self {
+            buffer,
+            position: 0,
+        }
+    }
+
+    /// The number of bytes written so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), PresetError> {
+        let end = self.position + bytes.len();
+        if end > self.buffer.len() {
+            return Err(PresetError::BufferTooSmall);
+        }
+
+        self.buffer[self.position..end].copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> Result<(), PresetError> {
+        self.write_bytes(&[value])
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> Result<(), PresetError> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> Result<(), PresetError> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> Result<(), PresetError> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_f32(&mut self, value: MusicalValue) -> Result<(), PresetError> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    /// Writes a `usize` as a `u64`
+    pub fn write_usize(&mut self, value: usize) -> Result<(), PresetError> {
+        self.write_u64(value as u64)
+    }
+
+    /// Overwrites a `u32` written earlier at the given position, for lengths known only afterwards
+    pub(crate) fn patch_u32(&mut self, position: usize, value: u32) {
+        self.buffer[position..position + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Reads little endian values from a byte slice
+pub struct PresetReader<'b> {
+    data: &'b [u8],
+    position: usize,
+}
+
+impl<'b> PresetReader<'b> {
+    pub fn new(data: &'b [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+        }
+    }
+
+    /// The number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'b [u8], PresetError> {
+        if count > self.remaining() {
+            return Err(PresetError::InvalidFormat);
+        }
+
+        let bytes = &self.data[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], PresetError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, PresetError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, PresetError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, PresetError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, PresetError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<MusicalValue, PresetError> {
+        Ok(MusicalValue::from_le_bytes(self.read_array()?))
+    }
+
+    /// Reads a `usize` written by `PresetWriter::write_usize`
+    pub fn read_usize(&mut self) -> Result<usize, PresetError> {
+        usize::try_from(self.read_u64()?).map_err(|_| PresetError::InvalidFormat)
+    }
+}
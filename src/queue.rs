@@ -0,0 +1,168 @@
+//! Lock-free single-producer single-consumer queue.
+//!
+//! Used to pass messages to and from the audio thread without locking or allocating.
+//! A queue is split into one `Producer` and one `Consumer`, either borrowing the queue (for statics or
+//! long-lived owners) or, with the `alloc` feature, sharing it through an `Arc`.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fixed-capacity queue holding up to `CAPACITY - 1` messages
+pub struct Queue<T, const CAPACITY: usize> {
+    buffer: [UnsafeCell<MaybeUninit<T>>; CAPACITY],
+
+    /// The next position to read, only written by the consumer
+    head: AtomicUsize,
+
+    /// The next position to write, only written by the producer
+    tail: AtomicUsize,
+}
+
+// Only the producer writes a slot before publishing it, and only the consumer reads it afterwards
+unsafe impl<T: Send, const CAPACITY: usize> Sync for Queue<T, CAPACITY> {}
+
+impl<T, const CAPACITY: usize> Default for Queue<T, CAPACITY> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const CAPACITY: usize> Queue<T, CAPACITY> {
+    pub const fn new() -> Self {
+        const { assert!(CAPACITY > 0, "Queue capacity must be above 0") };
+        Self {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; CAPACITY],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Splits the queue into its producer and consumer ends
+    pub fn split(&mut self) -> (Producer<&Self>, Consumer<&Self>) {
+        let queue: &Self = self;
+        (Producer { queue }, Consumer { queue })
+    }
+
+    /// Creates a queue shared by its producer and consumer ends
+    #[cfg(feature = "alloc")]
+    pub fn shared() -> (Producer<alloc::sync::Arc<Self>>, Consumer<alloc::sync::Arc<Self>>) {
+        let queue = alloc::sync::Arc::new(Self::new());
+        (Producer { queue: queue.clone() }, Consumer { queue })
+    }
+
+    /// Safety: must only be called by the single producer
+    unsafe fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % CAPACITY;
+        if next == self.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        (*self.buffer[tail].get()).write(value);
+        self.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    /// Safety: must only be called by the single consumer, and the messages must not be popped while borrowed
+    unsafe fn peek(&self) -> impl Iterator<Item = &T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let len = (self.tail.load(Ordering::Acquire) + CAPACITY - head) % CAPACITY;
+        (0..len).map(move |i| unsafe { (*self.buffer[(head + i) % CAPACITY].get()).assume_init_ref() })
+    }
+
+    /// Safety: must only be called by the single consumer
+    unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = (*self.buffer[head].get()).assume_init_read();
+        self.head.store((head + 1) % CAPACITY, Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const CAPACITY: usize> Drop for Queue<T, CAPACITY> {
+    fn drop(&mut self) {
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
+
+/// The sending end of a queue
+pub struct Producer<Q> {
+    queue: Q,
+}
+
+impl<T, const CAPACITY: usize, Q: Deref<Target = Queue<T, CAPACITY>>> Producer<Q> {
+    /// Sends a message, giving it back if the queue is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        unsafe { self.queue.push(value) }
+    }
+}
+
+/// The receiving end of a queue
+pub struct Consumer<Q> {
+    queue: Q,
+}
+
+impl<T, const CAPACITY: usize, Q: Deref<Target = Queue<T, CAPACITY>>> Consumer<Q> {
+    /// Receives the oldest message, if any
+    pub fn pop(&mut self) -> Option<T> {
+        unsafe { self.queue.pop() }
+    }
+
+    /// The messages sent so far, oldest first, without receiving them
+    pub fn peek<'q>(&'q self) -> impl Iterator<Item = &'q T>
+    where
+        T: 'q,
+    {
+        unsafe { self.queue.peek() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_queue_pops_nothing() {
+        let mut queue = Queue::<u32, 4>::new();
+        let (_, mut consumer) = queue.split();
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn full_queue_gives_message_back() {
+        let mut queue = Queue::<u32, 4>::new();
+        let (mut producer, mut consumer) = queue.split();
+        for i in 0..3 {
+            assert_eq!(producer.push(i), Ok(()));
+        }
+        assert_eq!(producer.push(3), Err(3));
+
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(producer.push(3), Ok(()));
+    }
+
+    #[test]
+    fn wraps_around_in_order() {
+        let mut queue = Queue::<u32, 3>::new();
+        let (mut producer, mut consumer) = queue.split();
+        for i in 0..10 {
+            assert_eq!(producer.push(i), Ok(()));
+            assert_eq!(producer.push(i + 100), Ok(()));
+            assert_eq!(consumer.pop(), Some(i));
+            assert_eq!(consumer.pop(), Some(i + 100));
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    fn single_slot_queue_holds_nothing() {
+        let mut queue = Queue::<u32, 1>::new();
+        let (mut producer, mut consumer) = queue.split();
+        assert_eq!(producer.push(1), Err(1));
+        assert_eq!(consumer.pop(), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! Rendering graphs into host audio buffers.
+
+#[cfg(feature = "std")]
+pub mod wav;
+
+use crate::graph::AudioGraph;
+use crate::{MidiNote, MusicalValue, STANDARD_BLOCK_SIZE};
+
+/// The maximum number of device channels of a `Renderer`
+pub const MAX_CHANNELS: usize = 32;
+
+/// A sample format of a host buffer
+pub trait Sample: Copy {
+    /// Converts a value in the range [-1, 1] to the sample format, clipping values outside the range
+    fn from_value(value: MusicalValue) -> Self;
+}
+
+impl Sample for f32 {
+    fn from_value(value: MusicalValue) -> Self {
+        value
+    }
+}
+
+impl Sample for i16 {
+    fn from_value(value: MusicalValue) -> Self {
+        (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+    }
+}
+
+impl Sample for i32 {
+    fn from_value(value: MusicalValue) -> Self {
+        (value.clamp(-1.0, 1.0) as f64 * i32::MAX as f64) as i32
+    }
+}
+
+/// Renders a graph into buffers of any length, carrying partial blocks over between calls.
+///
+/// Each device channel is mapped to a graph output channel, or to silence.
+/// By default device channel `i` plays graph channel `i` modulo the graph's channel count,
+/// so a mono graph is played on every device channel.
+pub struct Renderer<G, Note: Sized + Default + Copy + Send = MidiNote, const BLOCK_SIZE: usize = STANDARD_BLOCK_SIZE> {
+    /// The rendered graph
+    pub graph: G,
+
+    /// The graph output channel of each device channel
+    pub(crate) channel_map: [Option<usize>; MAX_CHANNELS],
+
+    /// The number of device channels
+    pub(crate) channels: usize,
+
+    /// The position of the next sample to be rendered in the current graph block
+    pub(crate) position: usize,
+
+    _phantom: core::marker::PhantomData<Note>,
+}
+
+impl<G: AudioGraph<Note, BLOCK_SIZE>, Note: Sized + Default + Copy + Send, const BLOCK_SIZE: usize> Renderer<G, Note, BLOCK_SIZE> {
+    pub fn new(graph: G, channels: usize) -> Self {
+        if channels > MAX_CHANNELS {
+            panic!("Too many device channels");
+        }
+
+        let graph_channels = graph.output_channel_count();
+        let mut channel_map = [None; MAX_CHANNELS];
+        if graph_channels > 0 {
+            for (i, channel) in channel_map.iter_mut().take(channels).enumerate() {
+                *channel = Some(i % graph_channels);
+            }
+        }
+
+        Self {
+            graph,
+            channel_map,
+            channels,
+            position: BLOCK_SIZE,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// The number of device channels
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Maps a device channel to a graph output channel, or to silence
+    pub fn map_channel(&mut self, device_channel: usize, graph_channel: Option<usize>) {
+        if device_channel >= self.channels {
+            panic!("Device channel index out of bounds");
+        }
+
+        if graph_channel.is_some_and(|channel| channel >= self.graph.output_channel_count()) {
+            panic!("Graph channel index out of bounds");
+        }
+
+        self.channel_map[device_channel] = graph_channel;
+    }
+
+    /// Renders `frames` frames, calling `write(self, frame, count, block_start)` for each run of `count` frames
+    /// starting at `frame` that can be read from the current graph block starting at `block_start`
+    fn render<F: FnMut(&Self, usize, usize, usize)>(&mut self, frames: usize, mut write: F) {
+        let mut frame = 0;
+        while frame < frames {
+            if self.position == BLOCK_SIZE {
+                self.graph.process_next();
+                self.position = 0;
+            }
+
+            let count = (frames - frame).min(BLOCK_SIZE - self.position);
+            write(self, frame, count, self.position);
+            frame += count;
+            self.position += count;
+        }
+    }
+
+    /// Fills an interleaved buffer, whose length should be a multiple of the channel count.
+    ///
+    /// A trailing partial frame is not rendered, it is filled with silence.
+    pub fn render_interleaved<S: Sample>(&mut self, data: &mut [S]) {
+        let channels = self.channels;
+        if channels == 0 {
+            return;
+        }
+
+        let frames = data.len() / channels;
+        self.render(frames, |renderer, frame, count, block_start| {
+            for channel in 0..channels {
+                let source = renderer.channel_map[channel].map(|graph_channel| renderer.graph.get_output(graph_channel));
+                for i in 0..count {
+                    let value = source.map_or(0.0, |source| source[block_start + i]);
+                    data[(frame + i) * channels + channel] = S::from_value(value);
+                }
+            }
+        });
+
+        for sample in &mut data[frames * channels..] {
+            *sample = S::from_value(0.0);
+        }
+    }
+
+    /// Fills one buffer per device channel, rendering as many frames as the shortest buffer holds
+    pub fn render_planar<S: Sample>(&mut self, data: &mut [&mut [S]]) {
+        let channels = self.channels.min(data.len());
+        let frames = data.iter().take(channels).map(|buffer| buffer.len()).min().unwrap_or(0);
+
+        self.render(frames, |renderer, frame, count, block_start| {
+            for (channel, buffer) in data.iter_mut().take(channels).enumerate() {
+                let source = renderer.channel_map[channel].map(|graph_channel| renderer.graph.get_output(graph_channel));
+                for i in 0..count {
+                    let value = source.map_or(0.0, |source| source[block_start + i]);
+                    buffer[frame + i] = S::from_value(value);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::InstrumentGraph;
+    use crate::{container_with_block_size, Instrument, InstrumentContainer, InstrumentInput, InstrumentOutput};
+
+    /// Outputs the index of every sample it renders, negated on its second stream
+    struct Counter {
+        next: usize,
+    }
+
+    impl Instrument<0, 0, 2> for Counter {
+        fn process_block<const BLOCK_SIZE: usize, const CONTROL_ELEMENTS: usize>(
+            &mut self,
+            _input: &InstrumentInput<0, 0, MidiNote, BLOCK_SIZE, CONTROL_ELEMENTS>,
+            output: &mut InstrumentOutput<2, BLOCK_SIZE>,
+        ) {
+            for i in 0..BLOCK_SIZE {
+                output.value_streams[0][i] = self.next as MusicalValue;
+                output.value_streams[1][i] = -(self.next as MusicalValue);
+                self.next += 1;
+            }
+        }
+    }
+
+    /// Renders a counter in blocks of 4 samples, on graph channels 0 and 1
+    fn renderer(counter: &mut dyn InstrumentContainer<MidiNote, 4>, channels: usize) -> Renderer<InstrumentGraph<'_, 1, 1, 1, 2, MidiNote, 4>, MidiNote, 4> {
+        let mut graph = InstrumentGraph::new();
+        let index = graph.add_instrument(counter);
+        graph.connect_destination(0, index, 0);
+        graph.connect_destination(1, index, 1);
+        Renderer::new(graph, channels)
+    }
+
+    #[test]
+    fn interleaved_buffers_span_blocks() {
+        let mut counter = container_with_block_size::<4, _, 0, 0, 2, MidiNote>(Counter { next: 0 });
+        let mut renderer = renderer(&mut counter, 2);
+
+        // 3 frames, then 7 frames crossing two block boundaries
+        let mut first = [0.0; 6];
+        renderer.render_interleaved(&mut first);
+        assert_eq!(first, [0.0, -0.0, 1.0, -1.0, 2.0, -2.0]);
+
+        let mut second = [0.0; 14];
+        renderer.render_interleaved(&mut second);
+        for frame in 0..7 {
+            assert_eq!(second[frame * 2], (frame + 3) as MusicalValue);
+            assert_eq!(second[frame * 2 + 1], -((frame + 3) as MusicalValue));
+        }
+    }
+
+    #[test]
+    fn trailing_partial_frame_is_silent() {
+        let mut counter = container_with_block_size::<4, _, 0, 0, 2, MidiNote>(Counter { next: 1 });
+        let mut renderer = renderer(&mut counter, 2);
+
+        let mut data = [9.0; 5];
+        renderer.render_interleaved(&mut data);
+        assert_eq!(data, [1.0, -1.0, 2.0, -2.0, 0.0]);
+    }
+
+    #[test]
+    fn planar_buffers_render_the_shortest_length() {
+        let mut counter = container_with_block_size::<4, _, 0, 0, 2, MidiNote>(Counter { next: 0 });
+        let mut renderer = renderer(&mut counter, 2);
+
+        let mut left = [9.0; 5];
+        let mut right = [9.0; 6];
+        renderer.render_planar(&mut [&mut left[..], &mut right[..]]);
+        assert_eq!(left, [0.0, 1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(right, [-0.0, -1.0, -2.0, -3.0, -4.0, 9.0]);
+
+        renderer.render_planar(&mut [&mut left[..], &mut right[..5]]);
+        assert_eq!(left, [5.0, 6.0, 7.0, 8.0, 9.0]);
+    }
+
+    #[test]
+    fn channels_follow_the_channel_map() {
+        let mut counter = container_with_block_size::<4, _, 0, 0, 2, MidiNote>(Counter { next: 1 });
+        let mut renderer = renderer(&mut counter, 3);
+
+        // Device channels wrap around the graph channels by default
+        let mut data = [0.0; 3];
+        renderer.render_interleaved(&mut data);
+        assert_eq!(data, [1.0, -1.0, 1.0]);
+
+        renderer.map_channel(0, Some(1));
+        renderer.map_channel(1, None);
+        renderer.render_interleaved(&mut data);
+        assert_eq!(data, [-2.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn integer_samples_are_scaled_and_clipped() {
+        assert_eq!(i16::from_value(0.5), 16383);
+        assert_eq!(i16::from_value(-1.0), -i16::MAX);
+        assert_eq!(i16::from_value(2.0), i16::MAX);
+        assert_eq!(i16::from_value(-2.0), -i16::MAX);
+
+        assert_eq!(i32::from_value(1.0), i32::MAX);
+        assert_eq!(i32::from_value(-0.5), -(i32::MAX / 2));
+        assert_eq!(i32::from_value(1.5), i32::MAX);
+        assert_eq!(i32::from_value(-1.5), -i32::MAX);
+
+        assert_eq!(f32::from_value(1.5), 1.5);
+    }
+}
@@ -0,0 +1,320 @@
+//! Offline rendering to RIFF WAV.
+//!
+//! Graphs are rendered as fast as possible, one block at a time, and written as interleaved frames
+//! with one WAV channel per graph output channel.
+
+use std::io::{self, Write};
+use std::vec::Vec;
+
+use crate::graph::AudioGraph;
+use crate::MusicalValue;
+
+/// The sample format of a WAV file
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WavFormat {
+    /// 16-bit integer PCM
+    Pcm16,
+
+    /// 24-bit integer PCM
+    Pcm24,
+
+    /// 32-bit IEEE float
+    Float32,
+}
+
+impl WavFormat {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            WavFormat::Pcm16 => 2,
+            WavFormat::Pcm24 => 3,
+            WavFormat::Float32 => 4,
+        }
+    }
+}
+
+/// How long to render
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RenderLength {
+    /// Exactly the given number of frames
+    Frames(usize),
+
+    /// Whole blocks until every channel stayed at or below `threshold` for `hold` frames,
+    /// or until `max_frames` frames were rendered
+    UntilSilence {
+        threshold: MusicalValue,
+        hold: usize,
+        max_frames: usize,
+    },
+}
+
+/// The format tag of integer PCM
+const FORMAT_PCM: u16 = 1;
+
+/// The format tag of IEEE float samples
+const FORMAT_FLOAT: u16 = 3;
+
+/// The format tag of `WAVE_FORMAT_EXTENSIBLE`, whose sub format GUID holds the actual format tag
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// The last 14 bytes of the sub format GUIDs, which start with the format tag as a `u16`
+const SUB_FORMAT_SUFFIX: [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "WAV data too large")
+}
+
+/// The size of the data chunk for the given number of frames, without the pad byte
+fn data_size(format: WavFormat, channels: u16, frames: usize) -> io::Result<u32> {
+    let block_align = channels as u64 * format.bytes_per_sample() as u64;
+    u64::try_from(frames).ok()
+        .and_then(|frames| frames.checked_mul(block_align))
+        .and_then(|size| u32::try_from(size).ok())
+        .ok_or_else(too_large)
+}
+
+/// Writes a WAV header for the given number of frames.
+///
+/// Float samples and more than two channels use `WAVE_FORMAT_EXTENSIBLE`, with the standard speaker positions
+/// for mono and stereo and no positions otherwise. The data chunk must be followed by `write_padding`.
+pub fn write_header<W: Write>(writer: &mut W, format: WavFormat, channels: u16, sample_rate: u32, frames: usize) -> io::Result<()> {
+    let bytes_per_sample = format.bytes_per_sample() as u16;
+    let block_align = channels.checked_mul(bytes_per_sample).ok_or_else(too_large)?;
+    let byte_rate = sample_rate.checked_mul(block_align as u32).ok_or_else(too_large)?;
+    let data_size = data_size(format, channels, frames)?;
+
+    let format_tag = match format {
+        WavFormat::Float32 => FORMAT_FLOAT,
+        _ => FORMAT_PCM,
+    };
+    let extensible = format_tag != FORMAT_PCM || channels > 2;
+    let fmt_size = if extensible { 40u32 } else { 16 };
+
+    // Non-PCM formats carry the frame count in a fact chunk
+    let fact_frames = if format_tag != FORMAT_PCM { Some(u32::try_from(frames).map_err(|_| too_large())?) } else { None };
+    let fact_size = if fact_frames.is_some() { 12 } else { 0 };
+
+    let riff_size = (4 + 8 + fmt_size + fact_size + 8)
+        .checked_add(data_size)
+        .and_then(|size| size.checked_add(data_size % 2))
+        .ok_or_else(too_large)?;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_size.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&fmt_size.to_le_bytes())?;
+    writer.write_all(&(if extensible { FORMAT_EXTENSIBLE } else { format_tag }).to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+
+    if extensible {
+        let channel_mask: u32 = match channels {
+            1 => 0x4,
+            2 => 0x3,
+            _ => 0,
+        };
+        writer.write_all(&22u16.to_le_bytes())?;
+        writer.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+        writer.write_all(&channel_mask.to_le_bytes())?;
+        writer.write_all(&format_tag.to_le_bytes())?;
+        writer.write_all(&SUB_FORMAT_SUFFIX)?;
+    }
+
+    if let Some(frames) = fact_frames {
+        writer.write_all(b"fact")?;
+        writer.write_all(&4u32.to_le_bytes())?;
+        writer.write_all(&frames.to_le_bytes())?;
+    }
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())
+}
+
+/// Writes the pad byte RIFF requires after a data chunk of odd size, which only 24-bit samples can give
+pub fn write_padding<W: Write>(writer: &mut W, format: WavFormat, channels: u16, frames: usize) -> io::Result<()> {
+    if data_size(format, channels, frames)? % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// Writes one sample, clipping integer formats to [-1, 1]
+pub fn write_sample<W: Write>(writer: &mut W, format: WavFormat, value: MusicalValue) -> io::Result<()> {
+    match format {
+        WavFormat::Pcm16 => writer.write_all(&((value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes()),
+        WavFormat::Pcm24 => writer.write_all(&((value.clamp(-1.0, 1.0) * 8_388_607.0) as i32).to_le_bytes()[..3]),
+        WavFormat::Float32 => writer.write_all(&value.to_le_bytes()),
+    }
+}
+
+fn write_block<G: AudioGraph<Note, BLOCK_SIZE>, Note: Sized + Default + Copy + Send, W: Write, const BLOCK_SIZE: usize>(writer: &mut W, format: WavFormat, graph: &G, frames: usize) -> io::Result<()> {
+    for frame in 0..frames {
+        for channel in 0..graph.output_channel_count() {
+            write_sample(writer, format, graph.get_output(channel)[frame])?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders a graph offline and writes it as a WAV file, returning the number of frames written.
+///
+/// A `RenderLength::Frames` render is streamed to the writer, an `UntilSilence` render is buffered
+/// until its length is known.
+pub fn render_wav<G: AudioGraph<Note, BLOCK_SIZE>, Note: Sized + Default + Copy + Send, W: Write, const BLOCK_SIZE: usize>(
+    graph: &mut G,
+    format: WavFormat,
+    sample_rate: u32,
+    length: RenderLength,
+    mut writer: W,
+) -> io::Result<usize> {
+    let channels = u16::try_from(graph.output_channel_count()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Too many channels"))?;
+
+    match length {
+        RenderLength::Frames(frames) => {
+            write_header(&mut writer, format, channels, sample_rate, frames)?;
+            let mut remaining = frames;
+            while remaining > 0 {
+                graph.process_next();
+                let count = remaining.min(BLOCK_SIZE);
+                write_block(&mut writer, format, graph, count)?;
+                remaining -= count;
+            }
+            write_padding(&mut writer, format, channels, frames)?;
+            writer.flush()?;
+            Ok(frames)
+        },
+        RenderLength::UntilSilence { threshold, hold, max_frames } => {
+            let mut data = Vec::new();
+            let mut frames = 0;
+            let mut silent_frames = 0;
+            while frames < max_frames && (frames == 0 || silent_frames < hold) {
+                graph.process_next();
+                let count = (max_frames - frames).min(BLOCK_SIZE);
+                for frame in 0..count {
+                    let silent = (0..graph.output_channel_count()).all(|channel| graph.get_output(channel)[frame].abs() <= threshold);
+                    silent_frames = if silent { silent_frames + 1 } else { 0 };
+                }
+                write_block(&mut data, format, graph, count)?;
+                frames += count;
+            }
+
+            write_header(&mut writer, format, channels, sample_rate, frames)?;
+            writer.write_all(&data)?;
+            write_padding(&mut writer, format, channels, frames)?;
+            writer.flush()?;
+            Ok(frames)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::graph::InstrumentGraph;
+    use crate::instrument::Constant;
+    use crate::{container, MidiNote};
+
+    fn u16_at(bytes: &[u8], position: usize) -> u16 {
+        u16::from_le_bytes([bytes[position], bytes[position + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], position: usize) -> u32 {
+        u32::from_le_bytes(bytes[position..position + 4].try_into().unwrap())
+    }
+
+    /// The position and size of each chunk, checking that the chunks fill the RIFF size exactly
+    fn chunks(file: &[u8]) -> Vec<([u8; 4], usize, usize)> {
+        assert_eq!(&file[..4], b"RIFF");
+        assert_eq!(&file[8..12], b"WAVE");
+        assert_eq!(u32_at(file, 4) as usize, file.len() - 8);
+
+        let mut chunks = Vec::new();
+        let mut position = 12;
+        while position < file.len() {
+            let size = u32_at(file, position + 4) as usize;
+            chunks.push((file[position..position + 4].try_into().unwrap(), position + 8, size));
+            position += 8 + size + size % 2;
+        }
+        assert_eq!(position, file.len());
+        chunks
+    }
+
+    #[test]
+    fn pcm16_stereo_header() {
+        let mut header = Vec::new();
+        write_header(&mut header, WavFormat::Pcm16, 2, 48000, 10).unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"RIFF");
+        expected.extend_from_slice(&(36u32 + 40).to_le_bytes());
+        expected.extend_from_slice(b"WAVEfmt ");
+        expected.extend_from_slice(&16u32.to_le_bytes());
+        expected.extend_from_slice(&1u16.to_le_bytes());
+        expected.extend_from_slice(&2u16.to_le_bytes());
+        expected.extend_from_slice(&48000u32.to_le_bytes());
+        expected.extend_from_slice(&192000u32.to_le_bytes());
+        expected.extend_from_slice(&4u16.to_le_bytes());
+        expected.extend_from_slice(&16u16.to_le_bytes());
+        expected.extend_from_slice(b"data");
+        expected.extend_from_slice(&40u32.to_le_bytes());
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn float_header_is_extensible_with_fact() {
+        let mut file = Vec::new();
+        write_header(&mut file, WavFormat::Float32, 1, 44100, 3).unwrap();
+        file.extend_from_slice(&[0; 12]);
+
+        let chunks = chunks(&file);
+        let names: Vec<_> = chunks.iter().map(|chunk| chunk.0).collect();
+        assert_eq!(names, [*b"fmt ", *b"fact", *b"data"]);
+
+        let (_, fmt, fmt_size) = chunks[0];
+        assert_eq!(fmt_size, 40);
+        assert_eq!(u16_at(&file, fmt), 0xFFFE);
+        assert_eq!(u16_at(&file, fmt + 14), 32);
+        assert_eq!(u16_at(&file, fmt + 16), 22);
+        assert_eq!(u16_at(&file, fmt + 18), 32);
+        assert_eq!(u32_at(&file, fmt + 20), 0x4);
+        assert_eq!(file[fmt + 24..fmt + 40], [3, 0, 0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71]);
+        assert_eq!(u32_at(&file, chunks[1].1), 3);
+    }
+
+    #[test]
+    fn multichannel_pcm_is_extensible() {
+        let mut header = Vec::new();
+        write_header(&mut header, WavFormat::Pcm16, 6, 48000, 0).unwrap();
+        assert_eq!(u16_at(&header, 20), 0xFFFE);
+        assert_eq!(u32_at(&header, 40), 0);
+        assert_eq!(header[44..46], [1, 0]);
+    }
+
+    #[test]
+    fn odd_data_is_padded() {
+        let mut constant = container(Constant::<MidiNote>::new(0.5));
+        let mut graph: InstrumentGraph<1, 1, 1, 1> = InstrumentGraph::new();
+        let index = graph.add_instrument(&mut constant);
+        graph.connect_destination(0, index, 0);
+
+        let mut file = Vec::new();
+        assert_eq!(render_wav(&mut graph, WavFormat::Pcm24, 48000, RenderLength::Frames(3), &mut file).unwrap(), 3);
+        let chunks = chunks(&file);
+        assert_eq!(chunks[1], (*b"data", 44, 9));
+        assert_eq!(file.len(), 44 + 9 + 1);
+    }
+
+    #[test]
+    fn rejects_sizes_beyond_u32() {
+        let invalid_input = |result: io::Result<()>| result.unwrap_err().kind() == io::ErrorKind::InvalidInput;
+        assert!(invalid_input(write_header(&mut Vec::new(), WavFormat::Pcm16, 2, 48000, 1 << 30)));
+        assert!(invalid_input(write_header(&mut Vec::new(), WavFormat::Float32, 8, u32::MAX, 1)));
+        assert!(invalid_input(write_header(&mut Vec::new(), WavFormat::Float32, u16::MAX, 48000, 1)));
+        assert!(invalid_input(write_header(&mut Vec::new(), WavFormat::Pcm16, 1, 48000, (u32::MAX / 2) as usize)));
+    }
+}
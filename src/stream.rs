@@ -0,0 +1,140 @@
+//! Descriptions of instrument streams.
+//!
+//! Instruments describe what each of their streams carries with `Instrument::stream_info`, so that user
+//! interfaces can label streams, patches can refer to them by name and graphs can check that connected
+//! streams fit together.
+
+use crate::parameter::ParameterUnit;
+use crate::MusicalValue;
+
+/// The kind of a stream of an instrument
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StreamKind {
+    ValueInput,
+    ControlInput,
+    ValueOutput,
+}
+
+/// What a stream carries
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StreamRole {
+    /// Any value, which fits every other role
+    Generic,
+
+    /// An audio signal
+    Audio,
+
+    Frequency,
+
+    /// A gain factor, such as the output of an envelope
+    Gain,
+
+    /// A phase or phase offset, in cycles
+    Phase,
+
+    /// Note commands, carried by control streams
+    Notes,
+}
+
+impl StreamRole {
+    /// Whether a stream of this role is expected to feed a stream of the `destination` role
+    pub fn fits(self, destination: StreamRole) -> bool {
+        self == destination || self == StreamRole::Generic || destination == StreamRole::Generic
+    }
+}
+
+/// The description of a stream
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub name: &'static str,
+    pub role: StreamRole,
+    pub unit: ParameterUnit,
+
+    /// The lowest expected value, lower values are not clamped
+    pub min: MusicalValue,
+
+    /// The highest expected value, higher values are not clamped
+    pub max: MusicalValue,
+
+    /// The value of an unconnected input
+    pub default: MusicalValue,
+}
+
+impl StreamInfo {
+    pub const fn new(name: &'static str, role: StreamRole, unit: ParameterUnit, min: MusicalValue, max: MusicalValue, default: MusicalValue) -> Self {
+        Self {
+            name,
+            role,
+            unit,
+            min,
+            max,
+            default,
+        }
+    }
+
+    /// An audio stream, in the nominal range of -1 to 1
+    pub const fn audio(name: &'static str) -> Self {
+        Self::new(name, StreamRole::Audio, ParameterUnit::Generic, -1.0, 1.0, 0.0)
+    }
+
+    /// A control stream of note commands, its range is unused
+    pub const fn notes(name: &'static str) -> Self {
+        Self::new(name, StreamRole::Notes, ParameterUnit::Generic, 0.0, 0.0, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::oscillators::SineOscillator;
+    use crate::instrument::Mixer;
+    use crate::{container, Instrument, InstrumentContainer, InstrumentInput, InstrumentOutput, MidiNote};
+
+    /// Passes its input through without describing its streams
+    struct Undescribed;
+
+    impl Instrument<1, 0, 1> for Undescribed {
+        fn process_block<const BLOCK_SIZE: usize, const CONTROL_ELEMENTS: usize>(
+            &mut self,
+            input: &InstrumentInput<1, 0, MidiNote, BLOCK_SIZE, CONTROL_ELEMENTS>,
+            output: &mut InstrumentOutput<1, BLOCK_SIZE>,
+        ) {
+            output.value_streams[0] = input.value_streams[0];
+        }
+    }
+
+    #[test]
+    fn streams_are_undescribed_by_default() {
+        let undescribed = container(Undescribed);
+        assert_eq!(undescribed.stream_info(StreamKind::ValueInput, 0), None);
+        assert_eq!(undescribed.stream_info(StreamKind::ValueOutput, 0), None);
+        assert_eq!(undescribed.find_stream(StreamKind::ValueInput, "input"), None);
+    }
+
+    #[test]
+    fn streams_out_of_bounds_are_undescribed() {
+        // The mixer describes every index, the container only the ones it has
+        let mixer = container(Mixer::<2, MidiNote>::new());
+        assert_eq!(mixer.stream_info(StreamKind::ValueInput, 1), Some(StreamInfo::audio("input")));
+        assert_eq!(mixer.stream_info(StreamKind::ValueInput, 2), None);
+        assert_eq!(mixer.stream_info(StreamKind::ValueOutput, 1), None);
+    }
+
+    #[test]
+    fn finds_streams_by_name() {
+        let sine = container(SineOscillator::<MidiNote>::new(48000));
+        assert_eq!(sine.find_stream(StreamKind::ValueInput, "frequency"), Some(0));
+        assert_eq!(sine.find_stream(StreamKind::ValueInput, "phase"), Some(1));
+        assert_eq!(sine.find_stream(StreamKind::ValueOutput, "output"), Some(0));
+        assert_eq!(sine.find_stream(StreamKind::ValueOutput, "phase"), None);
+        assert_eq!(sine.find_stream(StreamKind::ControlInput, "notes"), None);
+    }
+
+    #[test]
+    fn roles_fit_themselves_and_generic_streams() {
+        assert!(StreamRole::Audio.fits(StreamRole::Audio));
+        assert!(StreamRole::Generic.fits(StreamRole::Frequency));
+        assert!(StreamRole::Gain.fits(StreamRole::Generic));
+        assert!(!StreamRole::Audio.fits(StreamRole::Frequency));
+    }
+}
@@ -0,0 +1,66 @@
+#![cfg(feature = "derive")]
+
+use linstr::dynamic::{DynInput, DynOutput};
+use linstr::stream::StreamKind;
+use linstr::{container, Instrument, InstrumentContainer, MidiNote};
+
+/// The example from the documentation of the derive macro
+#[derive(Instrument)]
+#[instrument(process = Self::process, new)]
+#[value_input("input", Audio)]
+#[value_input("gain", Gain, unit = Gain, min = 0.0, max = 1.0)]
+#[value_output("output", Audio)]
+pub struct Gain {
+    #[parameter("volume", unit = Gain, min = 0.0, max = 2.0, default = 1.0)]
+    pub volume: f32,
+}
+
+impl Gain {
+    fn process<Note>(&mut self, input: &DynInput<Note>, output: &mut DynOutput) {
+        for segment in input.parameter_segments() {
+            for event in segment.events {
+                self.apply_parameter(event.id, event.value);
+            }
+            for i in segment.start..segment.end {
+                output.value_stream_mut(0)[i] = input.value_stream(0)[i] * input.value_stream(1)[i] * self.volume;
+            }
+        }
+    }
+}
+
+#[derive(Instrument)]
+#[instrument(process = Self::process, new = const)]
+#[value_output("output", Audio)]
+pub struct Level {
+    #[parameter("level", default = 0.5)]
+    pub level: f32,
+}
+
+impl Level {
+    fn process<Note>(&mut self, _input: &DynInput<Note>, output: &mut DynOutput) {
+        output.value_stream_mut(0).fill(self.level);
+    }
+}
+
+const LEVEL: Level = Level::new();
+
+#[test]
+fn derives_the_documented_example() {
+    let mut gain = container::<_, 2, 0, 1, MidiNote>(Gain::new());
+    assert_eq!(gain.find_stream(StreamKind::ValueInput, "gain"), Some(1));
+    assert_eq!(gain.parameter_info(0).map(|info| info.name), Some("volume"));
+    assert_eq!(gain.parameter(0), Some(1.0));
+
+    gain.set_parameter(0, 3.0);
+    gain.feed_value_stream(0, &[0.5; 128]);
+    gain.feed_value_stream(1, &[0.5; 128]);
+    gain.process_next();
+    assert_eq!(gain.get_output(0)[0], 0.5);
+}
+
+#[test]
+fn derives_const_new() {
+    let mut level = container::<_, 0, 0, 1, MidiNote>(LEVEL);
+    level.process_next();
+    assert_eq!(level.get_output(0)[127], 0.5);
+}